@@ -3,6 +3,14 @@ import { LoginRequest, AuthResponse } from '../types'
 import { API_BASE_URL } from '@/config/api'
 import { cookies } from 'next/headers'
 
+// The address this server was connected from. Route handlers can't see the socket, but Next.js
+// records its address in x-forwarded-for when the request has none, and a proxy in front of us
+// appends it; either way it is the last entry, the one the client can't choose.
+function clientAddress(request: Request): string | undefined {
+  const last = request.headers.get('x-forwarded-for')?.split(',').pop()?.trim()
+  return last || undefined
+}
+
 export async function POST(request: Request) {
   try {
     const body: LoginRequest = await request.json()
@@ -15,6 +23,12 @@ export async function POST(request: Request) {
     if (accessToken) {
       headers['Authorization'] = `Bearer ${accessToken}`
     }
+    // Lets the backend throttle failed logins per client rather than per frontend server. Only the
+    // address we can vouch for is passed on, so the backend's last entry is never client-chosen.
+    const clientIp = clientAddress(request)
+    if (clientIp) {
+      headers['X-Forwarded-For'] = clientIp
+    }
 
     const response = await fetch(`${API_BASE_URL}/auth/login`, {
       method: 'POST',
@@ -1,5 +1,5 @@
 import { useEffect, useState } from 'react';
-import { API_BASE_URL } from '../config/api';
+import { API_BASE_URL, authHeaders } from '../config/api';
 
 interface Conversation {
   id: string;
@@ -26,7 +26,7 @@ export const useConversationFetcher = () => {
   useEffect(() => {
     const fetchConversations = async () => {
       try {
-        const response = await fetch(`${API_BASE_URL}/conversations`);
+        const response = await fetch(`${API_BASE_URL}/conversations`, { headers: authHeaders() });
         const data: Conversation[] = await response.json();
         
         const now = new Date();
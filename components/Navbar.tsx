@@ -8,7 +8,7 @@ import {
   ChevronLeft
 } from 'lucide-react';
 import { useConversationFetcher } from './ConversationFetcher';
-import { API_BASE_URL } from '../config/api';
+import { API_BASE_URL, authHeaders } from '../config/api';
 
 interface NavbarProps {
   darkMode: boolean;
@@ -32,7 +32,7 @@ export default function Navbar({
   const handleConversationClick = async (id: string) => {
     setSelectedConversationId(id);
     try {
-      const response = await fetch(`${API_BASE_URL}/conversations/${id}`);
+      const response = await fetch(`${API_BASE_URL}/conversations/${id}`, { headers: authHeaders() });
       const messages = await response.json();
       setMessages(messages);
     } catch (error) {
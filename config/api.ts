@@ -1 +1,9 @@
 export const API_BASE_URL = 'http://localhost:8000';
+
+export const authHeaders = (): Record<string, string> => {
+  const token = document.cookie
+    .split('; ')
+    .find(row => row.startsWith('access_token='))
+    ?.split('=')[1];
+  return token ? { 'Authorization': `Bearer ${token}` } : {};
+};
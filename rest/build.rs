@@ -30,21 +30,9 @@ fn main() {
         println!("cargo:info=.env not found at: {}", env_src.display());
     }
 
-    // Copy database file
-    let db_src = Path::new(&manifest_dir).join("sqlite/deepseek_chat.db");
-    println!("cargo:info=Looking for DB at: {}", db_src.display());
-    let db_dest = bin_dir.join("sqlite/deepseek_chat.db");
-
-    // if sqlite not found, create the directory
-    if !db_dest.parent().unwrap().exists() {
-        fs::create_dir_all(db_dest.parent().unwrap()).expect("Failed to create DB directory");
-        println!("cargo:info=Created DB directory: {}", db_dest.parent().unwrap().display());
-    }
-    fs::copy(&db_src, &db_dest).expect("Failed to copy database file");
-    println!("cargo:info=Copied DB to: {}", db_dest.display());
-
     // Re-run if these files change
     println!("cargo:rerun-if-changed={}", env_src.display());
-    println!("cargo:rerun-if-changed={}", db_src.display());
     println!("cargo:rerun-if-changed=build.rs");
+    // Migrations are embedded with `sqlx::migrate!`
+    println!("cargo:rerun-if-changed=migrations");
 }
@@ -0,0 +1,129 @@
+-- The schema the SQLite migrations up to 20250429000000 arrive at. Later migrations are added to
+-- both directories under the same version.
+--
+-- Flags are BOOLEAN and timestamps TIMESTAMP(0), which reads back as text in the same
+-- `YYYY-MM-DD HH:MM:SS` form SQLite stores; unix timestamps stay BIGINT.
+CREATE TABLE users (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    email TEXT NOT NULL,
+    username TEXT,
+    role TEXT NOT NULL DEFAULT 'user',
+    disabled BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP(0) DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP(0) DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX idx_users_email ON users(email);
+
+CREATE TABLE auth (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    userid BIGINT REFERENCES users(id),
+    email TEXT NOT NULL,
+    password_hash TEXT NOT NULL,
+    created_at TIMESTAMP(0) DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP(0) DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE conversation (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    title TEXT NOT NULL,
+    updatetime TEXT NOT NULL,
+    filepath TEXT NOT NULL,
+    userid BIGINT REFERENCES users(id)
+);
+
+CREATE INDEX idx_conversation_userid ON conversation(userid);
+
+CREATE TABLE messages (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    conversation_id BIGINT NOT NULL REFERENCES conversation(id) ON DELETE CASCADE,
+    seq BIGINT NOT NULL,
+    role TEXT NOT NULL,
+    content TEXT NOT NULL,
+    truncated BOOLEAN NOT NULL DEFAULT FALSE,
+    model TEXT,
+    prompt_tokens BIGINT,
+    completion_tokens BIGINT,
+    created_at TIMESTAMP(0) DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (conversation_id, seq)
+);
+
+CREATE TABLE refresh_tokens (
+    jti TEXT PRIMARY KEY,
+    userid BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    family TEXT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    revoked BOOLEAN NOT NULL DEFAULT FALSE,
+    replaced_by TEXT,
+    created_at TIMESTAMP(0) DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_refresh_tokens_family ON refresh_tokens(family);
+CREATE INDEX idx_refresh_tokens_userid ON refresh_tokens(userid);
+
+CREATE TABLE invites (
+    code TEXT PRIMARY KEY,
+    created_by BIGINT REFERENCES users(id) ON DELETE SET NULL,
+    used_by BIGINT REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMP(0) DEFAULT CURRENT_TIMESTAMP,
+    used_at TIMESTAMP(0)
+);
+
+CREATE TABLE password_resets (
+    token_hash TEXT PRIMARY KEY,
+    userid BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    expires_at BIGINT NOT NULL,
+    used_at TIMESTAMP(0),
+    created_at TIMESTAMP(0) DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_password_resets_userid ON password_resets(userid);
+
+CREATE TABLE login_attempts (
+    scope TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    failures BIGINT NOT NULL DEFAULT 0,
+    last_failure BIGINT NOT NULL,
+    locked_until BIGINT,
+    PRIMARY KEY (scope, subject)
+);
+
+CREATE TABLE audit_log (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    actor_id BIGINT,
+    event TEXT NOT NULL,
+    target_id BIGINT,
+    detail TEXT,
+    ip TEXT,
+    user_agent TEXT,
+    created_at TIMESTAMP(0) DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_audit_log_target_id ON audit_log(target_id);
+CREATE INDEX idx_audit_log_actor_id ON audit_log(actor_id);
+CREATE INDEX idx_audit_log_event ON audit_log(event);
+CREATE INDEX idx_audit_log_created_at ON audit_log(created_at);
+
+CREATE TABLE oidc_logins (
+    state TEXT PRIMARY KEY,
+    nonce TEXT NOT NULL,
+    code_verifier TEXT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    created_at TIMESTAMP(0) DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Space separated scopes, e.g. 'conversations:read conversations:write'
+CREATE TABLE api_keys (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    userid BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    prefix TEXT NOT NULL,
+    key_hash TEXT NOT NULL UNIQUE,
+    scopes TEXT NOT NULL,
+    expires_at BIGINT,
+    last_used_at BIGINT,
+    revoked BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP(0) DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_api_keys_userid ON api_keys(userid);
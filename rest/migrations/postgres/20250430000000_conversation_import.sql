@@ -0,0 +1,3 @@
+-- Conversations whose legacy JSON file has not made it into `messages` yet; see the SQLite
+-- migration of the same name.
+ALTER TABLE conversation ADD COLUMN import_pending BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,9 @@
+-- Conversations belong to the user who created them.
+ALTER TABLE conversation ADD COLUMN userid INTEGER REFERENCES users(id);
+
+-- Existing conversations predate ownership; hand them to the first admin.
+UPDATE conversation
+SET userid = (SELECT id FROM users WHERE role = 'admin' ORDER BY id LIMIT 1)
+WHERE userid IS NULL;
+
+CREATE INDEX idx_conversation_userid ON conversation(userid);
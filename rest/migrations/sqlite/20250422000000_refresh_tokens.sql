@@ -0,0 +1,15 @@
+-- Every refresh token handed out, keyed by its `jti` claim.
+-- Tokens issued from the same login share a `family`; refreshing revokes the old token and
+-- records its successor, so presenting a revoked token again revokes the whole family.
+CREATE TABLE refresh_tokens (
+    jti TEXT PRIMARY KEY,
+    userid INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    family TEXT NOT NULL,
+    expires_at INTEGER NOT NULL,
+    revoked INTEGER NOT NULL DEFAULT 0,
+    replaced_by TEXT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_refresh_tokens_family ON refresh_tokens(family);
+CREATE INDEX idx_refresh_tokens_userid ON refresh_tokens(userid);
@@ -0,0 +1,2 @@
+-- Registration relies on the database to reject a second account for the same email.
+CREATE UNIQUE INDEX idx_users_email ON users(email);
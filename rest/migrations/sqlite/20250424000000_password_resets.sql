@@ -0,0 +1,10 @@
+-- Outstanding password reset tokens. Only a SHA-256 hash of each token is kept.
+CREATE TABLE password_resets (
+    token_hash TEXT PRIMARY KEY,
+    userid INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    expires_at INTEGER NOT NULL,
+    used_at TIMESTAMP,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_password_resets_userid ON password_resets(userid);
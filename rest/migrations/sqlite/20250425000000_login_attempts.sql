@@ -0,0 +1,10 @@
+-- Failed login counters, one row per email and per client IP (`scope` is `email` or `ip`).
+-- `locked_until` is a unix timestamp; logins for the subject are refused until then.
+CREATE TABLE login_attempts (
+    scope TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_failure INTEGER NOT NULL,
+    locked_until INTEGER,
+    PRIMARY KEY (scope, subject)
+);
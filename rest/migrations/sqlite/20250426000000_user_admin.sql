@@ -0,0 +1,15 @@
+-- Disabled users can't log in and their tokens stop working.
+ALTER TABLE users ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0;
+
+-- Record of administrative actions. Ids are kept without foreign keys so entries outlive the
+-- users they mention.
+CREATE TABLE audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    actor_id INTEGER,
+    event TEXT NOT NULL,
+    target_id INTEGER,
+    detail TEXT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_audit_log_target_id ON audit_log(target_id);
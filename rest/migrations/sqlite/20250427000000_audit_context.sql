@@ -0,0 +1,7 @@
+-- Where audited requests came from.
+ALTER TABLE audit_log ADD COLUMN ip TEXT;
+ALTER TABLE audit_log ADD COLUMN user_agent TEXT;
+
+CREATE INDEX idx_audit_log_actor_id ON audit_log(actor_id);
+CREATE INDEX idx_audit_log_event ON audit_log(event);
+CREATE INDEX idx_audit_log_created_at ON audit_log(created_at);
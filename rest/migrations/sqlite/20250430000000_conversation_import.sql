@@ -0,0 +1,7 @@
+-- Conversations whose legacy JSON file has not made it into `messages` yet. A file that fails to
+-- import leaves its conversation pending, so it is retried on the next start instead of being
+-- taken for an empty conversation.
+ALTER TABLE conversation ADD COLUMN import_pending INTEGER NOT NULL DEFAULT 0;
+
+UPDATE conversation SET import_pending = 1
+WHERE filepath != '' AND NOT EXISTS (SELECT 1 FROM messages m WHERE m.conversation_id = conversation.id);
@@ -0,0 +1,4 @@
+-- Emails are compared without regard to case, so one account can't be registered twice under
+-- different capitalisations.
+DROP INDEX idx_users_email;
+CREATE UNIQUE INDEX idx_users_email ON users(lower(email));
@@ -0,0 +1,331 @@
+pub mod types;
+
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use sqlx::AnyConnection;
+use types::{AdminError, AdminUser, ForceResetPasswordRequest, ListUsersQuery, SetRoleRequest, UserPage};
+
+use crate::audit::{
+    self, TIMESTAMP_FORMAT,
+    types::{AuditContext, AuditEntry, AuditEvent, AuditPage, AuditQuery},
+};
+use crate::auth::{self, lockout, types::{AppState, AuthUser}};
+use crate::conversation;
+use crate::db::Flag;
+
+const MAX_PER_PAGE: i64 = 100;
+
+pub async fn list_users(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ListUsersQuery>,
+) -> Result<Json<UserPage>, AdminError> {
+    let page = query.page.max(1);
+    let per_page = query.per_page.clamp(1, MAX_PER_PAGE);
+
+    let users: Vec<AdminUser> = sqlx::query_as(
+        "SELECT id, email, username, role, disabled, CAST(created_at AS TEXT) AS created_at FROM users
+         ORDER BY id LIMIT $1 OFFSET $2",
+    )
+    .bind(per_page)
+    .bind((page - 1) * per_page)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Database error when listing users: {}", e);
+        AdminError::DatabaseError
+    })?;
+
+    let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|_| AdminError::DatabaseError)?;
+
+    Ok(Json(UserPage {
+        users,
+        page,
+        per_page,
+        total,
+    }))
+}
+
+/// Audit log entries, newest first, filtered by user, event and time range.
+pub async fn list_audit(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<AuditPage>, AdminError> {
+    let page = query.page.max(1);
+    let per_page = query.per_page.clamp(1, MAX_PER_PAGE);
+    let from = query.from.map(|t| t.format(TIMESTAMP_FORMAT).to_string());
+    let to = query.to.map(|t| t.format(TIMESTAMP_FORMAT).to_string());
+
+    const FILTER: &str = "($1 IS NULL OR actor_id = $1 OR target_id = $1)
+         AND ($2 IS NULL OR event = $2)
+         AND ($3 IS NULL OR CAST(created_at AS TEXT) >= $3)
+         AND ($4 IS NULL OR CAST(created_at AS TEXT) < $4)";
+
+    let entries: Vec<AuditEntry> = sqlx::query_as(&format!(
+        "SELECT id, actor_id, event, target_id, detail, ip, user_agent, CAST(created_at AS TEXT) AS created_at
+         FROM audit_log
+         WHERE {} ORDER BY id DESC LIMIT $5 OFFSET $6",
+        FILTER
+    ))
+    .bind(query.user_id)
+    .bind(&query.event)
+    .bind(&from)
+    .bind(&to)
+    .bind(per_page)
+    .bind((page - 1) * per_page)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Database error when listing audit log: {}", e);
+        AdminError::DatabaseError
+    })?;
+
+    let total: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM audit_log WHERE {}", FILTER))
+        .bind(query.user_id)
+        .bind(&query.event)
+        .bind(&from)
+        .bind(&to)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|_| AdminError::DatabaseError)?;
+
+    Ok(Json(AuditPage {
+        entries,
+        page,
+        per_page,
+        total,
+    }))
+}
+
+pub async fn set_role(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    admin: AuthUser,
+    context: AuditContext,
+    Json(request): Json<SetRoleRequest>,
+) -> Result<StatusCode, AdminError> {
+    if !auth::ROLES.contains(&request.role.as_str()) {
+        return Err(AdminError::InvalidRole);
+    }
+    if id == admin.id && request.role != "admin" {
+        return Err(AdminError::SelfAction);
+    }
+
+    let mut tx = state.pool.begin().await.map_err(|_| AdminError::DatabaseError)?;
+
+    // Access tokens keep the old role until they expire; refreshing picks up the new one
+    sqlx::query("UPDATE users SET role = $1, updated_at = CURRENT_TIMESTAMP WHERE id = $2 RETURNING id")
+        .bind(&request.role)
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| AdminError::DatabaseError)?
+        .ok_or(AdminError::NotFound)?;
+
+    let detail = format!("role={}", request.role);
+    audit(&mut tx, &admin, &context, AuditEvent::RoleChanged, id, Some(detail)).await?;
+    tx.commit().await.map_err(|_| AdminError::DatabaseError)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn disable_user(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    admin: AuthUser,
+    context: AuditContext,
+) -> Result<StatusCode, AdminError> {
+    set_disabled(&state, &admin, &context, id, true).await
+}
+
+pub async fn enable_user(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    admin: AuthUser,
+    context: AuditContext,
+) -> Result<StatusCode, AdminError> {
+    set_disabled(&state, &admin, &context, id, false).await
+}
+
+/// Sets a new password for a user and signs out all of their sessions.
+pub async fn force_reset_password(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    admin: AuthUser,
+    context: AuditContext,
+    Json(request): Json<ForceResetPasswordRequest>,
+) -> Result<StatusCode, AdminError> {
+    let hash = auth::hash_password(&state.auth_config, &request.new_password)?;
+    let mut tx = state.pool.begin().await.map_err(|_| AdminError::DatabaseError)?;
+
+    let updated = sqlx::query("UPDATE auth SET password_hash = $1, updated_at = CURRENT_TIMESTAMP WHERE userid = $2")
+        .bind(&hash)
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AdminError::DatabaseError)?;
+    if updated.rows_affected() == 0 {
+        return Err(AdminError::NotFound);
+    }
+
+    revoke_sessions(&mut tx, id).await?;
+    audit(&mut tx, &admin, &context, AuditEvent::PasswordForceReset, id, None).await?;
+    tx.commit().await.map_err(|_| AdminError::DatabaseError)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Lifts a login lockout on a user's email.
+pub async fn unlock_user(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    admin: AuthUser,
+    context: AuditContext,
+) -> Result<StatusCode, AdminError> {
+    let email: String = sqlx::query_scalar("SELECT email FROM users WHERE id = $1")
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|_| AdminError::DatabaseError)?
+        .ok_or(AdminError::NotFound)?;
+
+    lockout::unlock_email(&state.pool, &email).await?;
+
+    let mut conn = state.pool.acquire().await.map_err(|_| AdminError::DatabaseError)?;
+    audit(&mut conn, &admin, &context, AuditEvent::UserUnlocked, id, None).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Deletes a user along with their credentials, sessions and conversations.
+pub async fn delete_user(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    admin: AuthUser,
+    context: AuditContext,
+) -> Result<StatusCode, AdminError> {
+    if id == admin.id {
+        return Err(AdminError::SelfAction);
+    }
+
+    let mut tx = state.pool.begin().await.map_err(|_| AdminError::DatabaseError)?;
+
+    let email: String = sqlx::query_scalar("SELECT email FROM users WHERE id = $1")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| AdminError::DatabaseError)?
+        .ok_or(AdminError::NotFound)?;
+
+    sqlx::query("DELETE FROM messages WHERE conversation_id IN (SELECT id FROM conversation WHERE userid = $1)")
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AdminError::DatabaseError)?;
+
+    let conversations: Vec<(String, Flag)> =
+        sqlx::query_as("DELETE FROM conversation WHERE userid = $1 RETURNING filepath, import_pending")
+            .bind(id)
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(|_| AdminError::DatabaseError)?;
+
+    // Refresh tokens, reset tokens and invite references go with the users row
+    sqlx::query("DELETE FROM auth WHERE userid = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AdminError::DatabaseError)?;
+
+    sqlx::query("DELETE FROM users WHERE id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            tracing::error!("Database error when deleting user {}: {}", id, e);
+            AdminError::DatabaseError
+        })?;
+
+    sqlx::query("DELETE FROM login_attempts WHERE scope = 'email' AND subject = $1")
+        .bind(email.to_lowercase())
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AdminError::DatabaseError)?;
+
+    let detail = format!("email={} conversations={}", email, conversations.len());
+    audit(&mut tx, &admin, &context, AuditEvent::UserDeleted, id, Some(detail)).await?;
+
+    tx.commit().await.map_err(|_| AdminError::DatabaseError)?;
+    tracing::info!("User {} deleted by admin {}", id, admin.id);
+
+    for (filepath, Flag(import_pending)) in &conversations {
+        conversation::remove_legacy_file(&state.conversation_dir, filepath, *import_pending);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn set_disabled(
+    state: &AppState,
+    admin: &AuthUser,
+    context: &AuditContext,
+    id: i64,
+    disabled: bool,
+) -> Result<StatusCode, AdminError> {
+    if id == admin.id {
+        return Err(AdminError::SelfAction);
+    }
+
+    let mut tx = state.pool.begin().await.map_err(|_| AdminError::DatabaseError)?;
+
+    sqlx::query("UPDATE users SET disabled = $1, updated_at = CURRENT_TIMESTAMP WHERE id = $2 RETURNING id")
+        .bind(disabled)
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| AdminError::DatabaseError)?
+        .ok_or(AdminError::NotFound)?;
+
+    if disabled {
+        revoke_sessions(&mut tx, id).await?;
+    }
+
+    let event = if disabled { AuditEvent::UserDisabled } else { AuditEvent::UserEnabled };
+    audit(&mut tx, admin, context, event, id, None).await?;
+    tx.commit().await.map_err(|_| AdminError::DatabaseError)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn revoke_sessions(conn: &mut AnyConnection, user_id: i64) -> Result<(), AdminError> {
+    sqlx::query("UPDATE refresh_tokens SET revoked = TRUE WHERE userid = $1 AND revoked = FALSE")
+        .bind(user_id)
+        .execute(conn)
+        .await
+        .map_err(|_| AdminError::DatabaseError)?;
+
+    Ok(())
+}
+
+async fn audit(
+    conn: &mut AnyConnection,
+    admin: &AuthUser,
+    context: &AuditContext,
+    event: AuditEvent,
+    target_id: i64,
+    detail: Option<String>,
+) -> Result<(), AdminError> {
+    audit::record(conn, context, event, Some(admin.id), Some(target_id), detail)
+        .await
+        .map_err(|e| {
+            tracing::error!("Database error when writing audit log: {}", e);
+            AdminError::DatabaseError
+        })
+}
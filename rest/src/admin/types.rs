@@ -0,0 +1,97 @@
+use axum::{
+    Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::FromRow;
+
+use crate::auth::types::AuthError;
+use crate::db::Flag;
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct AdminUser {
+    pub id: i64,
+    pub email: String,
+    pub username: Option<String>,
+    pub role: String,
+    #[sqlx(try_from = "Flag")]
+    pub disabled: bool,
+    pub created_at: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserPage {
+    pub users: Vec<AdminUser>,
+    pub page: i64,
+    pub per_page: i64,
+    pub total: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListUsersQuery {
+    #[serde(default = "default_page")]
+    pub page: i64,
+    #[serde(default = "default_per_page")]
+    pub per_page: i64,
+}
+
+fn default_page() -> i64 {
+    1
+}
+
+fn default_per_page() -> i64 {
+    20
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetRoleRequest {
+    pub role: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForceResetPasswordRequest {
+    pub new_password: String,
+}
+
+#[derive(Debug)]
+pub enum AdminError {
+    NotFound,
+    InvalidRole,
+    InvalidPassword(&'static str),
+    /// Admins can't disable, demote or delete their own account.
+    SelfAction,
+    DatabaseError,
+    Internal,
+}
+
+impl From<AuthError> for AdminError {
+    fn from(error: AuthError) -> Self {
+        match error {
+            AuthError::Validation(message) => AdminError::InvalidPassword(message),
+            AuthError::DatabaseError => AdminError::DatabaseError,
+            _ => AdminError::Internal,
+        }
+    }
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        let (status, code, error_message) = match self {
+            AdminError::NotFound => (StatusCode::NOT_FOUND, "not_found", "User not found"),
+            AdminError::InvalidRole => (StatusCode::BAD_REQUEST, "invalid_role", "Unknown role"),
+            AdminError::InvalidPassword(message) => (StatusCode::BAD_REQUEST, "invalid_password", message),
+            AdminError::SelfAction => (StatusCode::BAD_REQUEST, "self_action", "Admins cannot do this to their own account"),
+            AdminError::DatabaseError => (StatusCode::INTERNAL_SERVER_ERROR, "database_error", "Database error"),
+            AdminError::Internal => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "Internal error"),
+        };
+
+        let body = Json(json!({
+            "error": error_message,
+            "code": code,
+        }));
+
+        (status, body).into_response()
+    }
+}
@@ -0,0 +1,201 @@
+pub mod types;
+
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::{Method, StatusCode, request::Parts},
+};
+use chrono::Utc;
+use rand::{RngCore, rngs::OsRng};
+use types::{ApiKey, ApiKeyError, ApiKeyResource, CreateApiKeyRequest, CreatedApiKey, DbApiKey, RenameApiKeyRequest};
+
+use crate::audit::{self, types::{AuditContext, AuditEvent}};
+use crate::auth::{self, types::{AppState, AuthError, AuthUser}};
+
+/// Every API key starts with this, which is how the auth extractor tells them from JWTs.
+pub const KEY_PREFIX: &str = "sk-";
+
+/// Scopes a key can be given.
+pub const SCOPES: [&str; 2] = ["conversations:read", "conversations:write"];
+
+const MAX_NAME_LENGTH: usize = 100;
+/// `last_used_at` is only written when it is at least this many seconds old.
+const LAST_USED_RESOLUTION: i64 = 60;
+
+pub async fn create_api_key(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    context: AuditContext,
+    Json(request): Json<CreateApiKeyRequest>,
+) -> Result<(StatusCode, Json<CreatedApiKey>), ApiKeyError> {
+    let name = validate_name(&request.name)?;
+    let scopes = match request.scopes {
+        Some(scopes) if scopes.is_empty() => return Err(ApiKeyError::InvalidScope),
+        Some(scopes) => {
+            if !scopes.iter().all(|scope| SCOPES.contains(&scope.as_str())) {
+                return Err(ApiKeyError::InvalidScope);
+            }
+            scopes
+        }
+        None => SCOPES.iter().map(|scope| scope.to_string()).collect(),
+    };
+    let expires_at = request.expires_at.map(|t| t.timestamp());
+    if expires_at.is_some_and(|t| t <= Utc::now().timestamp()) {
+        return Err(ApiKeyError::InvalidExpiry);
+    }
+
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let key = format!("{}{}", KEY_PREFIX, hex::encode(bytes));
+    let prefix = key[..KEY_PREFIX.len() + 8].to_string();
+
+    let mut tx = state.pool.begin().await.map_err(|_| ApiKeyError::DatabaseError)?;
+
+    let row: DbApiKey = sqlx::query_as(
+        "INSERT INTO api_keys (userid, name, prefix, key_hash, scopes, expires_at) VALUES ($1, $2, $3, $4, $5, $6)
+         RETURNING id, name, prefix, scopes, expires_at, last_used_at, CAST(created_at AS TEXT) AS created_at",
+    )
+    .bind(user.id)
+    .bind(name)
+    .bind(&prefix)
+    .bind(auth::hash_token(&key))
+    .bind(scopes.join(" "))
+    .bind(expires_at)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| {
+        tracing::error!("Database error when creating API key: {}", e);
+        ApiKeyError::DatabaseError
+    })?;
+
+    let detail = format!("key={} scopes={}", row.id, row.scopes);
+    audit::record(&mut *tx, &context, AuditEvent::ApiKeyCreated, Some(user.id), Some(user.id), Some(detail))
+        .await
+        .map_err(|_| ApiKeyError::DatabaseError)?;
+    tx.commit().await.map_err(|_| ApiKeyError::DatabaseError)?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedApiKey {
+            key,
+            api_key: row.into(),
+        }),
+    ))
+}
+
+/// The caller's keys that haven't been revoked, newest first.
+pub async fn list_api_keys(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+) -> Result<Json<Vec<ApiKey>>, ApiKeyError> {
+    let keys: Vec<DbApiKey> = sqlx::query_as(
+        "SELECT id, name, prefix, scopes, expires_at, last_used_at, CAST(created_at AS TEXT) AS created_at FROM api_keys
+         WHERE userid = $1 AND revoked = FALSE ORDER BY id DESC",
+    )
+    .bind(user.id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Database error when listing API keys: {}", e);
+        ApiKeyError::DatabaseError
+    })?;
+
+    Ok(Json(keys.into_iter().map(ApiKey::from).collect()))
+}
+
+pub async fn rename_api_key(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Json(request): Json<RenameApiKeyRequest>,
+) -> Result<Json<ApiKey>, ApiKeyError> {
+    let name = validate_name(&request.name)?;
+
+    let key: DbApiKey = sqlx::query_as(
+        "UPDATE api_keys SET name = $1 WHERE id = $2 AND userid = $3 AND revoked = FALSE
+         RETURNING id, name, prefix, scopes, expires_at, last_used_at, CAST(created_at AS TEXT) AS created_at",
+    )
+    .bind(name)
+    .bind(id)
+    .bind(user.id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|_| ApiKeyError::DatabaseError)?
+    .ok_or(ApiKeyError::NotFound)?;
+
+    Ok(Json(key.into()))
+}
+
+pub async fn revoke_api_key(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    context: AuditContext,
+) -> Result<StatusCode, ApiKeyError> {
+    let mut tx = state.pool.begin().await.map_err(|_| ApiKeyError::DatabaseError)?;
+
+    sqlx::query("UPDATE api_keys SET revoked = TRUE WHERE id = $1 AND userid = $2 AND revoked = FALSE RETURNING id")
+        .bind(id)
+        .bind(user.id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| ApiKeyError::DatabaseError)?
+        .ok_or(ApiKeyError::NotFound)?;
+
+    let detail = format!("key={}", id);
+    audit::record(&mut *tx, &context, AuditEvent::ApiKeyRevoked, Some(user.id), Some(user.id), Some(detail))
+        .await
+        .map_err(|_| ApiKeyError::DatabaseError)?;
+    tx.commit().await.map_err(|_| ApiKeyError::DatabaseError)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Resolves an `sk-` key to its owner, if it is live and its scopes cover the request.
+pub async fn authenticate(parts: &Parts, state: &AppState, key: &str) -> Result<AuthUser, AuthError> {
+    let Some(ApiKeyResource(resource)) = parts.extensions.get::<ApiKeyResource>().copied() else {
+        tracing::warn!("API key used on {}, which only accepts access tokens", parts.uri.path());
+        return Err(AuthError::Forbidden);
+    };
+
+    let now = Utc::now().timestamp();
+    let (id, user_id, scopes, role): (i64, i64, String, String) = sqlx::query_as(
+        "SELECT k.id, k.userid, k.scopes, u.role FROM api_keys k JOIN users u ON u.id = k.userid
+         WHERE k.key_hash = $1 AND k.revoked = FALSE AND (k.expires_at IS NULL OR k.expires_at > $2)",
+    )
+    .bind(auth::hash_token(key))
+    .bind(now)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|_| AuthError::DatabaseError)?
+    .ok_or(AuthError::InvalidToken)?;
+
+    auth::check_enabled(state, user_id).await?;
+
+    let access = if parts.method == Method::GET || parts.method == Method::HEAD { "read" } else { "write" };
+    let required = format!("{}:{}", resource, access);
+    if !scopes.split_whitespace().any(|scope| scope == required) {
+        tracing::warn!("API key {} lacks scope {}", id, required);
+        return Err(AuthError::Forbidden);
+    }
+
+    sqlx::query("UPDATE api_keys SET last_used_at = $1 WHERE id = $2 AND (last_used_at IS NULL OR last_used_at <= $3)")
+        .bind(now)
+        .bind(id)
+        .bind(now - LAST_USED_RESOLUTION)
+        .execute(&state.pool)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    Ok(AuthUser { id: user_id, role })
+}
+
+fn validate_name(name: &str) -> Result<&str, ApiKeyError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(ApiKeyError::InvalidName);
+    }
+    Ok(name)
+}
@@ -0,0 +1,103 @@
+use axum::{
+    Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::FromRow;
+
+/// Marks the routes API keys may be used on, as `router.route_layer(Extension(ApiKeyResource(..)))`.
+///
+/// A key needs `<resource>:read` for GET and HEAD requests and `<resource>:write` for anything
+/// else. Routes without the extension only accept access tokens.
+#[derive(Debug, Clone, Copy)]
+pub struct ApiKeyResource(pub &'static str);
+
+#[derive(Debug, Serialize)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    /// The start of the key, e.g. `sk-1a2b3c4d`.
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: Option<String>,
+}
+
+#[derive(FromRow)]
+pub struct DbApiKey {
+    pub id: i64,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: String,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+    pub created_at: Option<String>,
+}
+
+impl From<DbApiKey> for ApiKey {
+    fn from(key: DbApiKey) -> Self {
+        ApiKey {
+            id: key.id,
+            name: key.name,
+            prefix: key.prefix,
+            scopes: key.scopes.split_whitespace().map(str::to_string).collect(),
+            expires_at: key.expires_at.and_then(|t| DateTime::from_timestamp(t, 0)),
+            last_used_at: key.last_used_at.and_then(|t| DateTime::from_timestamp(t, 0)),
+            created_at: key.created_at,
+        }
+    }
+}
+
+/// A newly created key; the only time the full key is returned.
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    /// Defaults to every scope.
+    pub scopes: Option<Vec<String>>,
+    /// Never expires if unset.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenameApiKeyRequest {
+    pub name: String,
+}
+
+#[derive(Debug)]
+pub enum ApiKeyError {
+    NotFound,
+    InvalidName,
+    InvalidScope,
+    InvalidExpiry,
+    DatabaseError,
+}
+
+impl IntoResponse for ApiKeyError {
+    fn into_response(self) -> Response {
+        let (status, code, error_message) = match self {
+            ApiKeyError::NotFound => (StatusCode::NOT_FOUND, "not_found", "API key not found"),
+            ApiKeyError::InvalidName => (StatusCode::BAD_REQUEST, "invalid_name", "Name must be 1 to 100 characters"),
+            ApiKeyError::InvalidScope => (StatusCode::BAD_REQUEST, "invalid_scope", "Unknown scope"),
+            ApiKeyError::InvalidExpiry => (StatusCode::BAD_REQUEST, "invalid_expiry", "Expiry must be in the future"),
+            ApiKeyError::DatabaseError => (StatusCode::INTERNAL_SERVER_ERROR, "database_error", "Database error"),
+        };
+
+        let body = Json(json!({
+            "error": error_message,
+            "code": code,
+        }));
+
+        (status, body).into_response()
+    }
+}
@@ -0,0 +1,72 @@
+pub mod types;
+
+use std::sync::Arc;
+
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts},
+};
+use sqlx::AnyExecutor;
+use types::{AuditContext, AuditEvent};
+
+use crate::auth::{lockout::ClientIp, types::{AppState, AuthError}};
+
+/// Format of `audit_log.created_at`, as written by SQLite's `CURRENT_TIMESTAMP` (UTC).
+pub const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+impl FromRequestParts<Arc<AppState>> for AuditContext {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let ClientIp(ip) = ClientIp::from_request_parts(parts, state).await?;
+        let user_agent = parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|h| h.to_str().ok())
+            .map(str::to_string);
+
+        Ok(AuditContext {
+            ip: ip.map(|ip| ip.to_string()),
+            user_agent,
+        })
+    }
+}
+
+/// Appends an entry to `audit_log`. Pass a transaction to have it commit with the change it
+/// describes.
+pub async fn record<'e, E>(
+    executor: E,
+    context: &AuditContext,
+    event: AuditEvent,
+    actor_id: Option<i64>,
+    target_id: Option<i64>,
+    detail: Option<String>,
+) -> Result<(), sqlx::Error>
+where
+    E: AnyExecutor<'e>,
+{
+    tracing::info!(
+        "Audit {}: actor {:?}, target {:?}, ip {:?}",
+        event.as_str(),
+        actor_id,
+        target_id,
+        context.ip
+    );
+
+    sqlx::query(
+        "INSERT INTO audit_log (actor_id, event, target_id, detail, ip, user_agent) VALUES ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(actor_id)
+    .bind(event.as_str())
+    .bind(target_id)
+    .bind(detail)
+    .bind(&context.ip)
+    .bind(&context.user_agent)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
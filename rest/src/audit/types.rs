@@ -0,0 +1,105 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+/// Security-relevant events, stored in `audit_log.event` as their `as_str` name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent {
+    LoginSucceeded,
+    LoginFailed,
+    TokenRefreshed,
+    /// A rotated refresh token was presented again and its family revoked.
+    TokenReuseDetected,
+    Logout,
+    LogoutAll,
+    UserRegistered,
+    PasswordChanged,
+    PasswordReset,
+    RoleChanged,
+    UserDisabled,
+    UserEnabled,
+    /// An admin set a user's password.
+    PasswordForceReset,
+    UserUnlocked,
+    UserDeleted,
+    ConversationDeleted,
+    ApiKeyCreated,
+    ApiKeyRevoked,
+}
+
+impl AuditEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditEvent::LoginSucceeded => "login.succeeded",
+            AuditEvent::LoginFailed => "login.failed",
+            AuditEvent::TokenRefreshed => "token.refreshed",
+            AuditEvent::TokenReuseDetected => "token.reuse_detected",
+            AuditEvent::Logout => "logout",
+            AuditEvent::LogoutAll => "logout.all",
+            AuditEvent::UserRegistered => "user.registered",
+            AuditEvent::PasswordChanged => "password.changed",
+            AuditEvent::PasswordReset => "password.reset",
+            AuditEvent::RoleChanged => "user.role_changed",
+            AuditEvent::UserDisabled => "user.disabled",
+            AuditEvent::UserEnabled => "user.enabled",
+            AuditEvent::PasswordForceReset => "user.password_reset",
+            AuditEvent::UserUnlocked => "user.unlocked",
+            AuditEvent::UserDeleted => "user.deleted",
+            AuditEvent::ConversationDeleted => "conversation.deleted",
+            AuditEvent::ApiKeyCreated => "api_key.created",
+            AuditEvent::ApiKeyRevoked => "api_key.revoked",
+        }
+    }
+}
+
+/// Where a request came from, recorded with every audit entry.
+#[derive(Debug, Clone, Default)]
+pub struct AuditContext {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct AuditEntry {
+    pub id: i64,
+    /// The user who caused the event, if known.
+    pub actor_id: Option<i64>,
+    pub event: String,
+    /// The user the event happened to, if any.
+    pub target_id: Option<i64>,
+    pub detail: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    /// Entries where this user is the actor or the target.
+    pub user_id: Option<i64>,
+    pub event: Option<String>,
+    /// Inclusive lower bound.
+    pub from: Option<DateTime<Utc>>,
+    /// Exclusive upper bound.
+    pub to: Option<DateTime<Utc>>,
+    #[serde(default = "default_page")]
+    pub page: i64,
+    #[serde(default = "default_per_page")]
+    pub per_page: i64,
+}
+
+fn default_page() -> i64 {
+    1
+}
+
+fn default_per_page() -> i64 {
+    50
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditPage {
+    pub entries: Vec<AuditEntry>,
+    pub page: i64,
+    pub per_page: i64,
+    pub total: i64,
+}
@@ -0,0 +1,89 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use axum::{
+    extract::{FromRequestParts, Request},
+    response::{IntoResponse, Response},
+};
+use tower::{Layer, Service};
+
+use super::types::{AppState, AuthError, AuthUser};
+
+/// Rejects requests whose caller does not have `role`, e.g.
+/// `router.route_layer(require_role(&state, "admin"))`.
+///
+/// Admins pass every guard. Callers without a valid token get the usual 401 from `AuthUser`,
+/// callers with the wrong role get a 403. The caller is kept in the request extensions, so
+/// handlers extracting `AuthUser` behind the guard don't authenticate them a second time.
+pub fn require_role(state: &Arc<AppState>, role: &'static str) -> RequireRoleLayer {
+    RequireRoleLayer {
+        state: Arc::clone(state),
+        role,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequireRoleLayer {
+    state: Arc<AppState>,
+    role: &'static str,
+}
+
+impl<S> Layer<S> for RequireRoleLayer {
+    type Service = RequireRole<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireRole {
+            inner,
+            state: Arc::clone(&self.state),
+            role: self.role,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequireRole<S> {
+    inner: S,
+    state: Arc<AppState>,
+    role: &'static str,
+}
+
+impl<S> Service<Request> for RequireRole<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // Keep the service that was polled ready and leave a fresh clone in its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let state = Arc::clone(&self.state);
+        let role = self.role;
+
+        Box::pin(async move {
+            let (mut parts, body) = request.into_parts();
+            let user = match AuthUser::from_request_parts(&mut parts, &state).await {
+                Ok(user) => user,
+                Err(e) => return Ok(e.into_response()),
+            };
+
+            if user.role != role && !user.is_admin() {
+                tracing::warn!("User {} with role {} denied, requires {}", user.id, user.role, role);
+                return Ok(AuthError::Forbidden.into_response());
+            }
+
+            parts.extensions.insert(user);
+            inner.call(Request::from_parts(parts, body)).await
+        })
+    }
+}
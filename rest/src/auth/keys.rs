@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation, decode, decode_header, encode,
+    errors::ErrorKind,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+        EllipticCurveKeyType, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters, OctetKeyPairType,
+        PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+};
+use serde::{Serialize, de::DeserializeOwned};
+use simple_asn1::{ASN1Block, oid};
+
+/// Keys tokens are signed and verified with.
+///
+/// Tokens carry the `kid` of the key that signed them. Verification keys other than the signing
+/// key let tokens signed before a rotation stay valid until they expire.
+#[derive(Clone)]
+pub struct JwtKeys {
+    kid: String,
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    verification: HashMap<String, VerificationKey>,
+}
+
+#[derive(Clone)]
+struct VerificationKey {
+    algorithm: Algorithm,
+    decoding: DecodingKey,
+    /// Public form of the key for `/.well-known/jwks.json`; `None` for HMAC secrets.
+    jwk: Option<Jwk>,
+}
+
+impl std::fmt::Debug for JwtKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut kids: Vec<_> = self.verification.keys().collect();
+        kids.sort();
+        f.debug_struct("JwtKeys")
+            .field("kid", &self.kid)
+            .field("algorithm", &self.algorithm)
+            .field("verification", &kids)
+            .finish_non_exhaustive()
+    }
+}
+
+impl JwtKeys {
+    /// Signs and verifies with a shared HS256 secret. Nothing is published in the JWKS.
+    pub fn hmac(kid: &str, secret: &[u8]) -> Self {
+        let verification = VerificationKey {
+            algorithm: Algorithm::HS256,
+            decoding: DecodingKey::from_secret(secret),
+            jwk: None,
+        };
+
+        Self {
+            kid: kid.to_string(),
+            algorithm: Algorithm::HS256,
+            encoding: EncodingKey::from_secret(secret),
+            verification: HashMap::from([(kid.to_string(), verification)]),
+        }
+    }
+
+    /// Signs with a PEM private key for RS256, ES256 or EdDSA. `public_pem` must hold the
+    /// matching public key, which is also used for verification and published in the JWKS.
+    pub fn from_pem(
+        kid: &str,
+        algorithm: Algorithm,
+        private_pem: &[u8],
+        public_pem: &[u8],
+    ) -> Result<Self, KeyError> {
+        let encoding = match algorithm {
+            Algorithm::RS256 => EncodingKey::from_rsa_pem(private_pem),
+            Algorithm::ES256 => EncodingKey::from_ec_pem(private_pem),
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(private_pem),
+            other => return Err(KeyError::UnsupportedAlgorithm(other)),
+        }
+        .map_err(|e| KeyError::InvalidKey(kid.to_string(), e.to_string()))?;
+
+        let verification = VerificationKey::from_pem(kid, public_pem)?;
+        if verification.algorithm != algorithm {
+            return Err(KeyError::AlgorithmMismatch(kid.to_string(), algorithm));
+        }
+
+        let keys = Self {
+            kid: kid.to_string(),
+            algorithm,
+            encoding,
+            verification: HashMap::from([(kid.to_string(), verification)]),
+        };
+
+        // A public key from another pair would only show up once every token got rejected
+        let probe = keys
+            .encode(&serde_json::json!({ "probe": true }))
+            .map_err(|e| KeyError::InvalidKey(kid.to_string(), e.to_string()))?;
+        let mut validation = Validation::new(algorithm);
+        validation.required_spec_claims.clear();
+        validation.validate_exp = false;
+        validation.validate_aud = false;
+        decode::<serde_json::Value>(&probe, &keys.verification[kid].decoding, &validation)
+            .map_err(|_| KeyError::KeyPairMismatch(kid.to_string()))?;
+
+        Ok(keys)
+    }
+
+    /// Also accepts tokens signed by the PEM public key `public_pem` under `kid`, e.g. the key
+    /// being rotated out. Its algorithm follows from the key type.
+    pub fn add_verification_key(&mut self, kid: &str, public_pem: &[u8]) -> Result<(), KeyError> {
+        if self.verification.contains_key(kid) {
+            return Err(KeyError::DuplicateKid(kid.to_string()));
+        }
+
+        let key = VerificationKey::from_pem(kid, public_pem)?;
+        self.verification.insert(kid.to_string(), key);
+        Ok(())
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Signs `claims` with the signing key, naming it in the header's `kid`.
+    pub fn encode<T: Serialize>(&self, claims: &T) -> jsonwebtoken::errors::Result<String> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = Some(self.kid.clone());
+        encode(&header, claims, &self.encoding)
+    }
+
+    /// Verifies `token` with the key named by its `kid`, or with the signing key if it has none,
+    /// as tokens issued before key IDs were introduced don't.
+    ///
+    /// `validation` is used for everything but the algorithm, which is pinned to the key's.
+    pub fn decode<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        mut validation: Validation,
+    ) -> jsonwebtoken::errors::Result<TokenData<T>> {
+        let header = decode_header(token)?;
+        let kid = header.kid.as_deref().unwrap_or(&self.kid);
+        let key = self
+            .verification
+            .get(kid)
+            .ok_or(jsonwebtoken::errors::Error::from(ErrorKind::InvalidToken))?;
+
+        validation.algorithms = vec![key.algorithm];
+        decode(token, &key.decoding, &validation)
+    }
+
+    /// The public verification keys, for `/.well-known/jwks.json`.
+    pub fn jwks(&self) -> JwkSet {
+        let mut keys: Vec<Jwk> = self.verification.values().filter_map(|key| key.jwk.clone()).collect();
+        keys.sort_by(|a, b| a.common.key_id.cmp(&b.common.key_id));
+        JwkSet { keys }
+    }
+}
+
+impl VerificationKey {
+    fn from_pem(kid: &str, public_pem: &[u8]) -> Result<Self, KeyError> {
+        let invalid = |reason: &str| KeyError::InvalidKey(kid.to_string(), reason.to_string());
+
+        let pem = pem::parse(public_pem).map_err(|e| invalid(&e.to_string()))?;
+        let (algorithm, parameters) = match pem.tag() {
+            "PUBLIC KEY" => spki_parameters(pem.contents()),
+            "RSA PUBLIC KEY" => rsa_parameters(pem.contents()),
+            _ => None,
+        }
+        .ok_or_else(|| invalid("expected an RSA, P-256 or Ed25519 public key"))?;
+
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(match algorithm {
+                    Algorithm::RS256 => KeyAlgorithm::RS256,
+                    Algorithm::ES256 => KeyAlgorithm::ES256,
+                    _ => KeyAlgorithm::EdDSA,
+                }),
+                key_id: Some(kid.to_string()),
+                ..Default::default()
+            },
+            algorithm: parameters,
+        };
+        let decoding = DecodingKey::from_jwk(&jwk).map_err(|e| invalid(&e.to_string()))?;
+
+        Ok(Self {
+            algorithm,
+            decoding,
+            jwk: Some(jwk),
+        })
+    }
+}
+
+/// Reads a DER `SubjectPublicKeyInfo`.
+fn spki_parameters(der: &[u8]) -> Option<(Algorithm, AlgorithmParameters)> {
+    let blocks = simple_asn1::from_der(der).ok()?;
+    let [ASN1Block::Sequence(_, spki)] = blocks.as_slice() else {
+        return None;
+    };
+    let [ASN1Block::Sequence(_, key_algorithm), ASN1Block::BitString(_, _, key)] = spki.as_slice() else {
+        return None;
+    };
+    let Some(ASN1Block::ObjectIdentifier(_, id)) = key_algorithm.first() else {
+        return None;
+    };
+
+    if *id == oid!(1, 2, 840, 113549, 1, 1, 1) {
+        rsa_parameters(key)
+    } else if *id == oid!(1, 2, 840, 10045, 2, 1) {
+        // Uncompressed P-256 point: 0x04 || x || y
+        let Some(ASN1Block::ObjectIdentifier(_, curve)) = key_algorithm.get(1) else {
+            return None;
+        };
+        if *curve != oid!(1, 2, 840, 10045, 3, 1, 7) || key.len() != 65 || key[0] != 4 {
+            return None;
+        }
+        let parameters = EllipticCurveKeyParameters {
+            key_type: EllipticCurveKeyType::EC,
+            curve: EllipticCurve::P256,
+            x: URL_SAFE_NO_PAD.encode(&key[1..33]),
+            y: URL_SAFE_NO_PAD.encode(&key[33..]),
+        };
+        Some((Algorithm::ES256, AlgorithmParameters::EllipticCurve(parameters)))
+    } else if *id == oid!(1, 3, 101, 112) {
+        if key.len() != 32 {
+            return None;
+        }
+        let parameters = OctetKeyPairParameters {
+            key_type: OctetKeyPairType::OctetKeyPair,
+            curve: EllipticCurve::Ed25519,
+            x: URL_SAFE_NO_PAD.encode(key),
+        };
+        Some((Algorithm::EdDSA, AlgorithmParameters::OctetKeyPair(parameters)))
+    } else {
+        None
+    }
+}
+
+/// Reads a DER PKCS#1 `RSAPublicKey`.
+fn rsa_parameters(der: &[u8]) -> Option<(Algorithm, AlgorithmParameters)> {
+    let blocks = simple_asn1::from_der(der).ok()?;
+    let [ASN1Block::Sequence(_, fields)] = blocks.as_slice() else {
+        return None;
+    };
+    let [ASN1Block::Integer(_, n), ASN1Block::Integer(_, e)] = fields.as_slice() else {
+        return None;
+    };
+
+    let parameters = RSAKeyParameters {
+        key_type: RSAKeyType::RSA,
+        n: URL_SAFE_NO_PAD.encode(n.to_bytes_be().1),
+        e: URL_SAFE_NO_PAD.encode(e.to_bytes_be().1),
+    };
+    Some((Algorithm::RS256, AlgorithmParameters::RSA(parameters)))
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    UnsupportedAlgorithm(Algorithm),
+    InvalidKey(String, String),
+    /// The public key is of a different type than the signing algorithm needs.
+    AlgorithmMismatch(String, Algorithm),
+    /// The public key doesn't verify what the private key signs.
+    KeyPairMismatch(String),
+    DuplicateKid(String),
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyError::UnsupportedAlgorithm(algorithm) => {
+                write!(f, "unsupported JWT algorithm {:?}, expected HS256, RS256, ES256 or EdDSA", algorithm)
+            }
+            KeyError::InvalidKey(kid, reason) => write!(f, "invalid key {:?}: {}", kid, reason),
+            KeyError::AlgorithmMismatch(kid, algorithm) => {
+                write!(f, "public key {:?} cannot verify {:?} signatures", kid, algorithm)
+            }
+            KeyError::KeyPairMismatch(kid) => write!(f, "public key {:?} does not match the private key", kid),
+            KeyError::DuplicateKid(kid) => write!(f, "key id {:?} is used more than once", kid),
+        }
+    }
+}
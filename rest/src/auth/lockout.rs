@@ -0,0 +1,171 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::request::Parts;
+use chrono::Utc;
+use sqlx::AnyPool;
+
+use super::types::{AppState, AuthError};
+
+/// Failures allowed per email before it gets locked.
+const EMAIL_FREE_ATTEMPTS: i64 = 5;
+/// Failures allowed per client IP before it gets locked; higher since many users may share one.
+const IP_FREE_ATTEMPTS: i64 = 20;
+/// First lockout in seconds, doubled with every further failure.
+const BASE_LOCKOUT: i64 = 30;
+const MAX_LOCKOUT: i64 = 3600;
+/// Failures older than this many seconds no longer count.
+const FAILURE_WINDOW: i64 = 3600;
+
+/// The client address of a request, if known.
+///
+/// Taken from the last `X-Forwarded-For` entry when `trust_proxy_headers` is set, otherwise from
+/// the connection. The last entry is the one our proxy appended; anything before it came from
+/// the client and could be made up.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl FromRequestParts<Arc<AppState>> for ClientIp {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let forwarded = state
+            .auth_config
+            .trust_proxy_headers
+            .then(|| parts.headers.get("X-Forwarded-For"))
+            .flatten()
+            .and_then(|h| h.to_str().ok())
+            .and_then(|s| s.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+
+        let connected = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+
+        Ok(ClientIp(forwarded.or(connected)))
+    }
+}
+
+/// The counters a login attempt is checked against and charged to.
+#[derive(Debug)]
+pub struct LoginAttempt {
+    email: String,
+    ip: Option<String>,
+}
+
+impl LoginAttempt {
+    pub fn new(email: &str, ip: Option<&str>) -> Self {
+        Self {
+            email: email.trim().to_lowercase(),
+            ip: ip.map(str::to_string),
+        }
+    }
+
+    fn subjects(&self) -> impl Iterator<Item = (&'static str, &str, i64)> {
+        std::iter::once(("email", self.email.as_str(), EMAIL_FREE_ATTEMPTS))
+            .chain(self.ip.as_deref().map(|ip| ("ip", ip, IP_FREE_ATTEMPTS)))
+    }
+}
+
+/// Refuses the attempt with `TooManyAttempts` while its email or IP is locked.
+pub async fn check(pool: &AnyPool, attempt: &LoginAttempt) -> Result<(), AuthError> {
+    let now = Utc::now().timestamp();
+    let mut retry_after = 0;
+
+    for (scope, subject, _) in attempt.subjects() {
+        let locked_until: Option<i64> = sqlx::query_scalar(
+            "SELECT locked_until FROM login_attempts WHERE scope = $1 AND subject = $2",
+        )
+        .bind(scope)
+        .bind(subject)
+        .fetch_optional(pool)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?
+        .flatten();
+
+        if let Some(locked_until) = locked_until {
+            retry_after = retry_after.max(locked_until - now);
+        }
+    }
+
+    if retry_after > 0 {
+        tracing::warn!("Login refused for locked email {} or IP {:?}", attempt.email, attempt.ip);
+        return Err(AuthError::TooManyAttempts { retry_after });
+    }
+
+    Ok(())
+}
+
+/// Counts a failed attempt against its email and IP, locking them once they run out of attempts.
+pub async fn record_failure(pool: &AnyPool, attempt: &LoginAttempt) -> Result<(), AuthError> {
+    let now = Utc::now().timestamp();
+    let mut tx = pool.begin().await.map_err(|_| AuthError::DatabaseError)?;
+
+    for (scope, subject, free_attempts) in attempt.subjects() {
+        let previous: Option<(i64, i64)> = sqlx::query_as(
+            "SELECT failures, last_failure FROM login_attempts WHERE scope = $1 AND subject = $2",
+        )
+        .bind(scope)
+        .bind(subject)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+        let failures = match previous {
+            Some((failures, last_failure)) if now - last_failure < FAILURE_WINDOW => failures + 1,
+            _ => 1,
+        };
+        let locked_until = lockout_seconds(failures, free_attempts).map(|seconds| now + seconds);
+        if locked_until.is_some() {
+            tracing::warn!("Locking {} {} after {} failed logins", scope, subject, failures);
+        }
+
+        sqlx::query(
+            "INSERT INTO login_attempts (scope, subject, failures, last_failure, locked_until)
+             VALUES ($1, $2, $3, $4, $5)
+             ON CONFLICT (scope, subject) DO UPDATE SET
+                 failures = excluded.failures,
+                 last_failure = excluded.last_failure,
+                 locked_until = excluded.locked_until",
+        )
+        .bind(scope)
+        .bind(subject)
+        .bind(failures)
+        .bind(now)
+        .bind(locked_until)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+    }
+
+    tx.commit().await.map_err(|_| AuthError::DatabaseError)
+}
+
+/// Resets the email's counter after a successful login. The IP counter is left to expire so one
+/// valid account can't be used to keep guessing at others.
+pub async fn clear(pool: &AnyPool, attempt: &LoginAttempt) -> Result<(), AuthError> {
+    unlock_email(pool, &attempt.email).await
+}
+
+/// Lifts the lockout on an email and resets its counter.
+pub async fn unlock_email(pool: &AnyPool, email: &str) -> Result<(), AuthError> {
+    sqlx::query("DELETE FROM login_attempts WHERE scope = 'email' AND subject = $1")
+        .bind(email.trim().to_lowercase())
+        .execute(pool)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    Ok(())
+}
+
+/// Lockout after the `failures`th failure, doubling from `BASE_LOCKOUT` once the free attempts
+/// are used up.
+fn lockout_seconds(failures: i64, free_attempts: i64) -> Option<i64> {
+    let excess = failures - free_attempts;
+    (excess >= 0).then(|| BASE_LOCKOUT.saturating_mul(1 << excess.min(32)).min(MAX_LOCKOUT))
+}
@@ -1,28 +1,106 @@
+pub mod guard;
+pub mod keys;
+pub mod lockout;
+pub mod oidc;
+pub mod session;
 pub mod types;
 
+use lockout::LoginAttempt;
+
 use types::AuthResponse;
-use types::{AppState, AuthError, Claims, LoginRequest, RefreshRequest, User};
+use types::{
+    AppState, AuthConfig, AuthError, AuthUser, ChangePasswordRequest, Claims, ForgotPasswordRequest, Invite,
+    JwtConfig, LoginRequest, RefreshRequest, RegisterRequest, RegistrationMode, ResetPasswordRequest,
+    TokenType, User,
+};
+
+use crate::api_keys;
+use crate::audit::{self, types::{AuditContext, AuditEvent}};
+use crate::mail::types::Email;
 
-use axum::{Json, extract::State, http::HeaderMap};
+use axum::{
+    Json,
+    extract::{FromRequestParts, OptionalFromRequestParts, State},
+    http::{HeaderMap, StatusCode, request::Parts},
+};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use jsonwebtoken::{Validation, jwk::JwkSet};
+use rand::{RngCore, rngs::OsRng};
+use sha2::{Digest, Sha256};
+use sqlx::AnyConnection;
+use sqlx::AnyPool;
 use std::sync::Arc;
+use uuid::Uuid;
+
+/// Roles a user can be given.
+pub const ROLES: [&str; 2] = ["user", "admin"];
+
+const MIN_PASSWORD_LENGTH: usize = 8;
 
 pub async fn login(
     State(state): State<Arc<AppState>>,
+    context: AuditContext,
     Json(credentials): Json<LoginRequest>,
-) -> Result<Json<AuthResponse>, AuthError> {
+) -> Result<(HeaderMap, Json<AuthResponse>), AuthError> {
     tracing::debug!("Login attempt for email: {}", credentials.email);
 
+    // Refuse before spending a bcrypt check on a locked email or IP
+    let attempt = LoginAttempt::new(&credentials.email, context.ip.as_deref());
+    let verified = match lockout::check(&state.pool, &attempt).await {
+        Ok(()) => verify_credentials(&state, &credentials).await,
+        Err(e) => Err(e),
+    };
+
+    let user = match verified {
+        Ok(user) => {
+            lockout::clear(&state.pool, &attempt).await?;
+            user
+        }
+        Err(e) => {
+            let reason = match e {
+                AuthError::InvalidCredentials => {
+                    lockout::record_failure(&state.pool, &attempt).await?;
+                    "invalid_credentials"
+                }
+                AuthError::TooManyAttempts { .. } => "locked",
+                AuthError::AccountDisabled => "disabled",
+                _ => return Err(e),
+            };
+
+            let detail = format!("email={} reason={}", credentials.email, reason);
+            audit::record(&state.pool, &context, AuditEvent::LoginFailed, None, None, Some(detail))
+                .await
+                .map_err(|_| AuthError::DatabaseError)?;
+            return Err(e);
+        }
+    };
+
+    // Generate tokens, starting a new refresh token family for this login
+    let user_id = user.id.expect("Valid user should have ID");
+    let mut tx = state.pool.begin().await.map_err(|_| AuthError::DatabaseError)?;
+
+    // Expired refresh tokens are useless; prune them while we're here
+    sqlx::query("DELETE FROM refresh_tokens WHERE userid = $1 AND expires_at < $2")
+        .bind(user_id)
+        .bind(Utc::now().timestamp())
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    let family = Uuid::new_v4().to_string();
+    let (response, _) = issue_tokens(&mut tx, &state.jwt_config, user_id, &user.role, &family).await?;
+    audit::record(&mut *tx, &context, AuditEvent::LoginSucceeded, Some(user_id), Some(user_id), None)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+    tx.commit().await.map_err(|_| AuthError::DatabaseError)?;
+
+    Ok((session::set_session(&state, &response), Json(response)))
+}
+
+/// Looks up the user for `credentials` and checks their password.
+async fn verify_credentials(state: &AppState, credentials: &LoginRequest) -> Result<User, AuthError> {
     // Verify user credentials against database
-    let user: Option<User> = sqlx::query_as!(
-        User,
-        "SELECT id, email, role FROM users WHERE email = ?",
-        credentials.email
-    )
-    .fetch_optional(&state.pool)
-    .await
-    .map_err(|e| {
+    let user = state.repositories.users.find_by_email(&credentials.email).await.map_err(|e| {
         tracing::error!("Database error when querying user: {}", e);
         AuthError::DatabaseError
     })?;
@@ -42,17 +120,19 @@ pub async fn login(
     tracing::debug!("Querying password hash for user_id: {}", user_id);
 
     // Verify password (compare with bcrypt hash in auth table)
-    let hash = sqlx::query_scalar!("SELECT password_hash FROM auth WHERE userid = ?", user_id)
-        .fetch_one(&state.pool)
+    // Users signed up through single sign-on have no password to check
+    let hash = state
+        .repositories
+        .credentials
+        .password_hash(user_id)
         .await
         .map_err(|e| {
             tracing::error!("Database error when querying password hash: {}", e);
             AuthError::DatabaseError
-        })?;
+        })?
+        .ok_or(AuthError::InvalidCredentials)?;
 
-    tracing::debug!("Password hash retrieved: {}", hash);
-    tracing::debug!("Password retrieved: {}", credentials.password);
-    let password_valid = bcrypt::verify(credentials.password, &hash).map_err(|e| {
+    let password_valid = bcrypt::verify(&credentials.password, &hash).map_err(|e| {
         tracing::error!("BCrypt verification error: {}", e);
         AuthError::InvalidCredentials
     })?;
@@ -63,107 +143,629 @@ pub async fn login(
     }
 
     tracing::debug!("Password verified successfully for user_id: {}", user_id);
+    check_enabled(state, user_id).await?;
+    Ok(user)
+}
 
-    // Generate tokens
-    let now = Utc::now();
-    let access_exp = now + Duration::seconds(state.jwt_config.access_expiry);
-    let refresh_exp = now + Duration::seconds(state.jwt_config.refresh_expiry);
+/// Exchanges a refresh token for a new access/refresh token pair.
+///
+/// The presented token is revoked and replaced. Presenting an already rotated or revoked
+/// token means it has leaked, so every token in its family is revoked as well.
+#[axum::debug_handler]
+pub async fn refresh_token(
+    State(state): State<Arc<AppState>>,
+    context: AuditContext,
+    headers: HeaderMap,
+    request: Option<Json<RefreshRequest>>,
+) -> Result<(HeaderMap, Json<AuthResponse>), AuthError> {
+    let token = presented_refresh_token(&state, &headers, &request)?;
+    let claims = decode_refresh(token, &state)?;
+    let user_id: i64 = claims.sub.parse().map_err(|_| AuthError::InvalidToken)?;
 
-    let user_id = user.id.expect("Valid user should have ID");
-    let role = user.role.clone();
+    check_enabled(&state, user_id).await?;
+    // Pick up role changes made since the token was issued
+    let role = state
+        .repositories
+        .users
+        .find_by_id(user_id)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?
+        .ok_or(AuthError::InvalidToken)?
+        .role;
+
+    let mut tx = state.pool.begin().await.map_err(|_| AuthError::DatabaseError)?;
+
+    // Claim the token; only one refresh can ever succeed with it
+    let family: Option<String> = sqlx::query_scalar(
+        "UPDATE refresh_tokens SET revoked = TRUE WHERE jti = $1 AND userid = $2 AND revoked = FALSE RETURNING family",
+    )
+    .bind(&claims.jti)
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|_| AuthError::DatabaseError)?;
+
+    let Some(family) = family else {
+        let reused: Option<String> =
+            sqlx::query_scalar("SELECT family FROM refresh_tokens WHERE jti = $1 AND userid = $2")
+                .bind(&claims.jti)
+                .bind(user_id)
+                .fetch_optional(&mut *tx)
+                .await
+                .map_err(|_| AuthError::DatabaseError)?;
+
+        if let Some(family) = reused {
+            tracing::warn!("Refresh token reuse detected for user_id: {}, revoking family {}", user_id, family);
+            sqlx::query("UPDATE refresh_tokens SET revoked = TRUE WHERE family = $1")
+                .bind(&family)
+                .execute(&mut *tx)
+                .await
+                .map_err(|_| AuthError::DatabaseError)?;
+            let detail = format!("family={}", family);
+            audit::record(&mut *tx, &context, AuditEvent::TokenReuseDetected, None, Some(user_id), Some(detail))
+                .await
+                .map_err(|_| AuthError::DatabaseError)?;
+            tx.commit().await.map_err(|_| AuthError::DatabaseError)?;
+        }
+        return Err(AuthError::InvalidToken);
+    };
+
+    let (response, jti) = issue_tokens(&mut tx, &state.jwt_config, user_id, &role, &family).await?;
+    sqlx::query("UPDATE refresh_tokens SET replaced_by = $1 WHERE jti = $2")
+        .bind(&jti)
+        .bind(&claims.jti)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+    audit::record(&mut *tx, &context, AuditEvent::TokenRefreshed, Some(user_id), Some(user_id), None)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+    tx.commit().await.map_err(|_| AuthError::DatabaseError)?;
+
+    Ok((session::set_session(&state, &response), Json(response)))
+}
+
+/// Ends the session the given refresh token belongs to.
+///
+/// Access tokens already handed out stay valid until they expire.
+pub async fn logout(
+    State(state): State<Arc<AppState>>,
+    context: AuditContext,
+    headers: HeaderMap,
+    request: Option<Json<RefreshRequest>>,
+) -> Result<(StatusCode, HeaderMap), AuthError> {
+    let token = presented_refresh_token(&state, &headers, &request)?;
+    let claims = decode_refresh(token, &state)?;
+    let user_id: i64 = claims.sub.parse().map_err(|_| AuthError::InvalidToken)?;
+
+    sqlx::query(
+        "UPDATE refresh_tokens SET revoked = TRUE
+         WHERE userid = $1 AND family = (SELECT family FROM refresh_tokens WHERE jti = $2)",
+    )
+    .bind(user_id)
+    .bind(&claims.jti)
+    .execute(&state.pool)
+    .await
+    .map_err(|_| AuthError::DatabaseError)?;
+
+    audit::record(&state.pool, &context, AuditEvent::Logout, Some(user_id), Some(user_id), None)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    Ok((StatusCode::NO_CONTENT, session::clear_session(&state)))
+}
+
+/// Ends every session of the caller.
+pub async fn logout_all(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+    context: AuditContext,
+) -> Result<(StatusCode, HeaderMap), AuthError> {
+    sqlx::query("UPDATE refresh_tokens SET revoked = TRUE WHERE userid = $1 AND revoked = FALSE")
+        .bind(auth_user.id)
+        .execute(&state.pool)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    tracing::info!("Revoked all refresh tokens for user_id: {}", auth_user.id);
+    audit::record(&state.pool, &context, AuditEvent::LogoutAll, Some(auth_user.id), Some(auth_user.id), None)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    Ok((StatusCode::NO_CONTENT, session::clear_session(&state)))
+}
+
+/// Creates a user, subject to the configured `RegistrationMode`.
+///
+/// Admins may register users in any mode and choose their role; credentials are only needed
+/// for that.
+pub async fn register(
+    State(state): State<Arc<AppState>>,
+    context: AuditContext,
+    caller: Option<AuthUser>,
+    Json(request): Json<RegisterRequest>,
+) -> Result<(StatusCode, Json<User>), AuthError> {
+    let caller_is_admin = caller.as_ref().is_some_and(AuthUser::is_admin);
+
+    let invite_code = match (state.auth_config.registration, caller_is_admin) {
+        (_, true) | (RegistrationMode::Open, _) => None,
+        (RegistrationMode::InviteOnly, false) => {
+            Some(request.invite_code.as_deref().ok_or(AuthError::InvalidInvite)?)
+        }
+        (RegistrationMode::AdminOnly, false) if caller.is_none() => return Err(AuthError::MissingToken),
+        (RegistrationMode::AdminOnly, false) => return Err(AuthError::Forbidden),
+    };
+
+    let role = match request.role.as_deref() {
+        Some(role) if caller_is_admin => role,
+        _ => "user",
+    };
+    if !ROLES.contains(&role) {
+        return Err(AuthError::Validation("Unknown role"));
+    }
+
+    let email = validate_email(&request.email)?;
+    let hash = hash_password(&state.auth_config, &request.password)?;
+
+    let mut tx = state.pool.begin().await.map_err(|_| AuthError::DatabaseError)?;
+    let id = create_user(&mut tx, &email, role, &hash).await?;
+
+    if let Some(code) = invite_code {
+        let claimed = sqlx::query(
+            "UPDATE invites SET used_by = $1, used_at = CURRENT_TIMESTAMP WHERE code = $2 AND used_at IS NULL",
+        )
+        .bind(id)
+        .bind(code)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+        if claimed.rows_affected() == 0 {
+            return Err(AuthError::InvalidInvite);
+        }
+    }
+
+    // Self-signups are their own actor; admin-created users are attributed to the admin
+    let actor_id = match &caller {
+        Some(caller) if caller_is_admin => Some(caller.id),
+        _ => Some(id),
+    };
+    let detail = format!("role={}", role);
+    audit::record(&mut *tx, &context, AuditEvent::UserRegistered, actor_id, Some(id), Some(detail))
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    tx.commit().await.map_err(|_| AuthError::DatabaseError)?;
+    tracing::info!("Registered user_id: {} with role {}", id, role);
+
+    Ok((
+        StatusCode::CREATED,
+        Json(User {
+            id: Some(id),
+            email: email.to_string(),
+            role: role.to_string(),
+        }),
+    ))
+}
+
+/// Creates a single-use invite code for invite-only registration.
+pub async fn create_invite(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+) -> Result<(StatusCode, Json<Invite>), AuthError> {
+    let code = Uuid::new_v4().simple().to_string();
+
+    sqlx::query("INSERT INTO invites (code, created_by) VALUES ($1, $2)")
+        .bind(&code)
+        .bind(auth_user.id)
+        .execute(&state.pool)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    Ok((StatusCode::CREATED, Json(Invite { code })))
+}
+
+/// Changes the caller's password and signs out their other sessions.
+pub async fn change_password(
+    State(state): State<Arc<AppState>>,
+    auth_user: AuthUser,
+    context: AuditContext,
+    Json(request): Json<ChangePasswordRequest>,
+) -> Result<StatusCode, AuthError> {
+    let hash = state
+        .repositories
+        .credentials
+        .password_hash(auth_user.id)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?
+        .ok_or(AuthError::InvalidCredentials)?;
+
+    if !bcrypt::verify(&request.current_password, &hash).unwrap_or(false) {
+        tracing::warn!("Password change with wrong current password for user_id: {}", auth_user.id);
+        return Err(AuthError::InvalidCredentials);
+    }
+
+    let new_hash = hash_password(&state.auth_config, &request.new_password)?;
+    let mut tx = state.pool.begin().await.map_err(|_| AuthError::DatabaseError)?;
+
+    sqlx::query("UPDATE auth SET password_hash = $1, updated_at = CURRENT_TIMESTAMP WHERE userid = $2")
+        .bind(&new_hash)
+        .bind(auth_user.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    sqlx::query("UPDATE refresh_tokens SET revoked = TRUE WHERE userid = $1 AND revoked = FALSE")
+        .bind(auth_user.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    audit::record(&mut *tx, &context, AuditEvent::PasswordChanged, Some(auth_user.id), Some(auth_user.id), None)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    tx.commit().await.map_err(|_| AuthError::DatabaseError)?;
+    tracing::info!("Password changed for user_id: {}", auth_user.id);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Mails a password reset link to `email` if it belongs to a user.
+///
+/// Always answers 202 so the endpoint can't be used to find out which emails are registered.
+pub async fn forgot_password(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ForgotPasswordRequest>,
+) -> Result<StatusCode, AuthError> {
+    let email = request.email.trim();
+    let user = state
+        .repositories
+        .users
+        .find_by_email(email)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    // Mail the address the account was registered with, whatever its case here
+    let Some((user_id, address)) = user.and_then(|user| Some((user.id?, user.email))) else {
+        tracing::warn!("Password reset requested for unknown email: {}", email);
+        return Ok(StatusCode::ACCEPTED);
+    };
+
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let token = hex::encode(bytes);
+    let expires_at = Utc::now() + Duration::seconds(state.auth_config.reset_token_expiry);
+
+    let mut tx = state.pool.begin().await.map_err(|_| AuthError::DatabaseError)?;
+
+    // Only the most recent link works
+    sqlx::query("DELETE FROM password_resets WHERE userid = $1 AND used_at IS NULL")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    sqlx::query("INSERT INTO password_resets (token_hash, userid, expires_at) VALUES ($1, $2, $3)")
+        .bind(hash_token(&token))
+        .bind(user_id)
+        .bind(expires_at.timestamp())
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    tx.commit().await.map_err(|_| AuthError::DatabaseError)?;
+
+    let message = Email {
+        to: address,
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Someone asked to reset the password for this account.\n\n\
+             Open the link below to choose a new one. It expires in {} minutes and works once.\n\n\
+             {}{}\n\n\
+             If this wasn't you, ignore this email.",
+            state.auth_config.reset_token_expiry / 60,
+            state.auth_config.password_reset_url,
+            token
+        ),
+    };
+    if let Err(e) = state.mailer.send(message).await {
+        tracing::error!("Failed to send password reset mail for user_id {}: {}", user_id, e);
+    }
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Sets a new password using a token from `forgot_password` and signs out every session.
+pub async fn reset_password(
+    State(state): State<Arc<AppState>>,
+    context: AuditContext,
+    Json(request): Json<ResetPasswordRequest>,
+) -> Result<StatusCode, AuthError> {
+    // Validate first so a weak password doesn't use up the token
+    let new_hash = hash_password(&state.auth_config, &request.new_password)?;
+
+    let mut tx = state.pool.begin().await.map_err(|_| AuthError::DatabaseError)?;
+
+    let user_id: i64 = sqlx::query_scalar(
+        "UPDATE password_resets SET used_at = CURRENT_TIMESTAMP
+         WHERE token_hash = $1 AND used_at IS NULL AND expires_at > $2 RETURNING userid",
+    )
+    .bind(hash_token(&request.token))
+    .bind(Utc::now().timestamp())
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|_| AuthError::DatabaseError)?
+    .ok_or(AuthError::InvalidResetToken)?;
+
+    sqlx::query("UPDATE auth SET password_hash = $1, updated_at = CURRENT_TIMESTAMP WHERE userid = $2")
+        .bind(&new_hash)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    sqlx::query("UPDATE refresh_tokens SET revoked = TRUE WHERE userid = $1 AND revoked = FALSE")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    audit::record(&mut *tx, &context, AuditEvent::PasswordReset, Some(user_id), Some(user_id), None)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    tx.commit().await.map_err(|_| AuthError::DatabaseError)?;
+    tracing::info!("Password reset for user_id: {}", user_id);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Trims an email address and rejects ones that can't be valid.
+pub(crate) fn validate_email(email: &str) -> Result<String, AuthError> {
+    let email = email.trim();
+    if email.is_empty() || !email.contains('@') || email.contains(char::is_whitespace) {
+        return Err(AuthError::Validation("Invalid email address"));
+    }
+    // Accounts are told apart by email regardless of case
+    Ok(email.to_lowercase())
+}
+
+/// Inserts a user that logs in with the password hashed as `hash`, returning their id.
+pub(crate) async fn create_user(
+    conn: &mut AnyConnection,
+    email: &str,
+    role: &str,
+    hash: &str,
+) -> Result<i64, AuthError> {
+    let id: i64 = sqlx::query_scalar("INSERT INTO users (email, role) VALUES ($1, $2) RETURNING id")
+        .bind(email)
+        .bind(role)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| match e.as_database_error() {
+            Some(db) if db.is_unique_violation() => AuthError::EmailTaken,
+            _ => {
+                tracing::error!("Database error when creating user: {}", e);
+                AuthError::DatabaseError
+            }
+        })?;
+
+    sqlx::query("INSERT INTO auth (userid, email, password_hash) VALUES ($1, $2, $3)")
+        .bind(id)
+        .bind(email)
+        .bind(hash)
+        .execute(&mut *conn)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    Ok(id)
+}
+
+/// Creates the administrator `email` if the database has no administrator yet.
+///
+/// Returns the new user's id, or `None` if an administrator already exists.
+pub async fn seed_admin(
+    pool: &AnyPool,
+    config: &AuthConfig,
+    email: &str,
+    password: &str,
+) -> Result<Option<i64>, AuthError> {
+    let email = validate_email(email)?;
+    let mut tx = pool.begin().await.map_err(|_| AuthError::DatabaseError)?;
+    let admins: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE role = 'admin'")
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+    if admins > 0 {
+        return Ok(None);
+    }
+
+    let hash = hash_password(config, password)?;
+    let id = create_user(&mut tx, &email, "admin", &hash).await?;
+    let detail = Some("role=admin via=bootstrap".to_string());
+    audit::record(&mut *tx, &AuditContext::default(), AuditEvent::UserRegistered, None, Some(id), detail)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+    tx.commit().await.map_err(|_| AuthError::DatabaseError)?;
+
+    tracing::info!("Created administrator {} (user_id: {})", email, id);
+    Ok(Some(id))
+}
+
+/// Reset tokens and API keys are stored as SHA-256 hex digests; the plain token is only ever
+/// handed to its owner.
+pub(crate) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Validates a new password and hashes it with the configured bcrypt cost.
+pub(crate) fn hash_password(config: &AuthConfig, password: &str) -> Result<String, AuthError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(AuthError::Validation("Password must be at least 8 characters"));
+    }
+
+    bcrypt::hash(password, config.bcrypt_cost).map_err(|e| {
+        tracing::error!("BCrypt hashing error: {}", e);
+        AuthError::PasswordHashing
+    })
+}
+
+/// Signs an access/refresh token pair and records the refresh token under `family`.
+///
+/// Returns the response along with the new refresh token's `jti`.
+async fn issue_tokens(
+    conn: &mut AnyConnection,
+    config: &JwtConfig,
+    user_id: i64,
+    role: &str,
+    family: &str,
+) -> Result<(AuthResponse, String), AuthError> {
+    let now = Utc::now();
+    let access_exp = now + Duration::seconds(config.access_expiry);
+    let refresh_exp = now + Duration::seconds(config.refresh_expiry);
 
     let access_claims = Claims {
         sub: user_id.to_string(),
         exp: access_exp.timestamp() as usize,
         iat: now.timestamp() as usize,
-        role: role.clone(),
+        role: role.to_string(),
+        token_type: TokenType::Access,
+        jti: Uuid::new_v4().to_string(),
+        iss: config.issuer.clone(),
+        aud: config.audience.clone(),
     };
 
     let refresh_claims = Claims {
         sub: user_id.to_string(),
         exp: refresh_exp.timestamp() as usize,
         iat: now.timestamp() as usize,
-        role: role,
+        role: role.to_string(),
+        token_type: TokenType::Refresh,
+        jti: Uuid::new_v4().to_string(),
+        iss: config.issuer.clone(),
+        aud: config.audience.clone(),
     };
 
-    let access_token = encode(
-        &Header::default(),
-        &access_claims,
-        &EncodingKey::from_secret(state.jwt_config.secret.as_ref()),
-    )
-    .map_err(|_| AuthError::TokenCreation)?;
+    let access_token = config.keys.encode(&access_claims).map_err(|_| AuthError::TokenCreation)?;
+    let refresh_token = config.keys.encode(&refresh_claims).map_err(|_| AuthError::TokenCreation)?;
 
-    let refresh_token = encode(
-        &Header::default(),
-        &refresh_claims,
-        &EncodingKey::from_secret(state.jwt_config.secret.as_ref()),
-    )
-    .map_err(|_| AuthError::TokenCreation)?;
+    sqlx::query("INSERT INTO refresh_tokens (jti, userid, family, expires_at) VALUES ($1, $2, $3, $4)")
+        .bind(&refresh_claims.jti)
+        .bind(user_id)
+        .bind(family)
+        .bind(refresh_exp.timestamp())
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            tracing::error!("Database error when storing refresh token: {}", e);
+            AuthError::DatabaseError
+        })?;
 
-    Ok(Json(AuthResponse {
+    let response = AuthResponse {
         access_token,
         refresh_token,
         token_type: "Bearer".to_string(),
-        expires_in: state.jwt_config.access_expiry,
-    }))
+        expires_in: config.access_expiry,
+    };
+
+    Ok((response, refresh_claims.jti))
 }
 
-#[axum::debug_handler]
-pub async fn refresh_token(
-    State(state): State<Arc<AppState>>,
-    Json(request): Json<RefreshRequest>,
-) -> Result<Json<AuthResponse>, AuthError> {
-    // Verify refresh token
-    let token_data = decode::<Claims>(
-        &request.refresh_token,
-        &DecodingKey::from_secret(state.jwt_config.secret.as_ref()),
-        &Validation::default(),
-    )
-    .map_err(|_| AuthError::InvalidToken)?;
+/// Fails with `AccountDisabled` for disabled users and `InvalidToken` for deleted ones.
+pub(crate) async fn check_enabled(state: &AppState, user_id: i64) -> Result<(), AuthError> {
+    let disabled = state
+        .repositories
+        .users
+        .is_disabled(user_id)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?
+        .ok_or(AuthError::InvalidToken)?;
 
-    // Generate new access token
-    let now = Utc::now();
-    let exp = now + Duration::seconds(state.jwt_config.access_expiry);
+    if disabled {
+        tracing::warn!("Rejected disabled user_id: {}", user_id);
+        return Err(AuthError::AccountDisabled);
+    }
 
-    let claims = Claims {
-        sub: token_data.claims.sub,
-        exp: exp.timestamp() as usize,
-        iat: now.timestamp() as usize,
-        role: token_data.claims.role,
-    };
+    Ok(())
+}
 
-    let access_token = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(state.jwt_config.secret.as_ref()),
-    )
-    .map_err(|_| AuthError::TokenCreation)?;
+/// Expiry, issuer and audience checks for incoming tokens.
+fn validation(config: &JwtConfig) -> Validation {
+    let mut validation = Validation::new(config.keys.algorithm());
+    let mut required = vec!["exp"];
+    if let Some(issuer) = &config.issuer {
+        validation.set_issuer(&[issuer]);
+        required.push("iss");
+    }
+    if let Some(audience) = &config.audience {
+        validation.set_audience(&[audience]);
+        required.push("aud");
+    }
+    validation.set_required_spec_claims(&required);
+    validation
+}
 
-    Ok(Json(AuthResponse {
-        access_token,
-        refresh_token: request.refresh_token,
-        token_type: "Bearer".to_string(),
-        expires_in: state.jwt_config.access_expiry,
-    }))
+/// The refresh token from the request body, or in cookie session mode from the refresh cookie.
+fn presented_refresh_token<'a>(
+    state: &AppState,
+    headers: &'a HeaderMap,
+    request: &'a Option<Json<RefreshRequest>>,
+) -> Result<&'a str, AuthError> {
+    if let Some(Json(request)) = request {
+        return Ok(&request.refresh_token);
+    }
+    if state.auth_config.cookies.is_none() {
+        return Err(AuthError::MissingToken);
+    }
+
+    let token = session::cookie(headers, session::REFRESH_COOKIE).ok_or(AuthError::MissingToken)?;
+    session::verify_csrf(headers)?;
+    Ok(token)
+}
+
+/// Decodes a refresh token, rejecting access tokens.
+fn decode_refresh(token: &str, state: &AppState) -> Result<Claims, AuthError> {
+    let token_data = state
+        .jwt_config
+        .keys
+        .decode::<Claims>(token, validation(&state.jwt_config))
+        .map_err(|_| AuthError::InvalidToken)?;
+
+    if token_data.claims.token_type != TokenType::Refresh {
+        return Err(AuthError::InvalidToken);
+    }
+
+    Ok(token_data.claims)
+}
+
+/// Public keys other services can verify our tokens with. Empty when signing with an HMAC secret.
+pub async fn jwks(State(state): State<Arc<AppState>>) -> Json<JwkSet> {
+    Json(state.jwt_config.keys.jwks())
 }
 
 pub async fn get_current_user(
     State(state): State<Arc<AppState>>,
-    headers: HeaderMap,
+    auth_user: AuthUser,
 ) -> Result<Json<User>, AuthError> {
-    // Extract and verify token
-    let token = headers
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "))
-        .ok_or(AuthError::MissingToken)?;
+    // Get user from database
+    let user = state
+        .repositories
+        .users
+        .find_by_id(auth_user.id)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?
+        .ok_or(AuthError::InvalidToken)?;
 
-    let token_data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(state.jwt_config.secret.as_ref()),
-        &Validation::default(),
-    )
-    .map_err(|_| AuthError::InvalidToken)?;
+    Ok(Json(user))
+}
+
+fn decode_access(token: &str, state: &AppState) -> Result<Claims, AuthError> {
+    let token_data = state
+        .jwt_config
+        .keys
+        .decode::<Claims>(token, validation(&state.jwt_config))
+        .map_err(|_| AuthError::InvalidToken)?;
 
     // Check if token is expired
     let now = Utc::now().timestamp() as usize;
@@ -171,15 +773,79 @@ pub async fn get_current_user(
         return Err(AuthError::InvalidToken);
     }
 
-    // Get user from database
-    let user = sqlx::query_as!(
-        User,
-        "SELECT id, email, role FROM users WHERE id = ?",
-        token_data.claims.sub
-    )
-    .fetch_one(&state.pool)
-    .await
-    .map_err(|_| AuthError::DatabaseError)?;
+    // Refresh tokens only work against `/auth/refresh`
+    if token_data.claims.token_type != TokenType::Access {
+        return Err(AuthError::InvalidToken);
+    }
 
-    Ok(Json(user))
+    Ok(token_data.claims)
+}
+
+fn bearer_token(headers: &HeaderMap) -> Result<&str, AuthError> {
+    headers
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "))
+        .ok_or(AuthError::MissingToken)
+}
+
+/// Requests without any credentials have no user; invalid credentials are still rejected.
+impl OptionalFromRequestParts<Arc<AppState>> for AuthUser {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        let has_cookie = state.auth_config.cookies.is_some()
+            && session::cookie(&parts.headers, session::ACCESS_COOKIE).is_some();
+        if !parts.headers.contains_key("Authorization") && !has_cookie {
+            return Ok(None);
+        }
+        <AuthUser as FromRequestParts<_>>::from_request_parts(parts, state).await.map(Some)
+    }
+}
+
+impl FromRequestParts<Arc<AppState>> for AuthUser {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        // Already authenticated by a `require_role` guard
+        if let Some(user) = parts.extensions.get::<AuthUser>() {
+            return Ok(user.clone());
+        }
+
+        let token = match bearer_token(&parts.headers) {
+            Ok(token) if token.starts_with(api_keys::KEY_PREFIX) => {
+                return api_keys::authenticate(parts, state, token).await;
+            }
+            Ok(token) => token,
+            Err(e) => {
+                let cookie = match state.auth_config.cookies {
+                    Some(_) => session::cookie(&parts.headers, session::ACCESS_COOKIE),
+                    None => None,
+                };
+                let token = cookie.ok_or(e)?;
+                // Browsers attach cookies to cross-site requests too, so changes need the CSRF token
+                if !parts.method.is_safe() {
+                    session::verify_csrf(&parts.headers)?;
+                }
+                token
+            }
+        };
+
+        let claims = decode_access(token, state)?;
+        let id = claims.sub.parse().map_err(|_| AuthError::InvalidToken)?;
+
+        // Tokens stay signed after their user is disabled or deleted, so check on every request
+        check_enabled(state, id).await?;
+
+        Ok(AuthUser {
+            id,
+            role: claims.role,
+        })
+    }
 }
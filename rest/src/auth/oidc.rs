@@ -0,0 +1,354 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::HeaderMap,
+    response::Redirect,
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::Utc;
+use jsonwebtoken::{
+    Algorithm, DecodingKey, Validation, decode, decode_header,
+    jwk::{AlgorithmParameters, JwkSet},
+};
+use rand::{RngCore, rngs::OsRng};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+use uuid::Uuid;
+
+use super::types::{AppState, AuthError, AuthResponse, OidcConfig};
+use super::{check_enabled, issue_tokens, session};
+use crate::audit::{self, types::{AuditContext, AuditEvent}};
+
+/// Seconds a login may take between `/auth/oidc/login` and the provider redirecting back.
+const LOGIN_EXPIRY: i64 = 600;
+
+/// Client for an OpenID Connect provider, configured through its discovery document.
+#[derive(Debug)]
+pub struct OidcClient {
+    http: reqwest::Client,
+    config: OidcConfig,
+    /// Fetched on first use so the server starts even while the provider is down.
+    metadata: OnceCell<ProviderMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    email: Option<String>,
+    email_verified: Option<bool>,
+    nonce: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: String,
+    /// Set instead of `code` when the provider refused the login.
+    pub error: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+    Request(reqwest::Error),
+    Status(StatusCode),
+    IdToken(String),
+}
+
+impl std::fmt::Display for OidcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OidcError::Request(e) => write!(f, "provider request failed: {}", e),
+            OidcError::Status(status) => write!(f, "provider returned {}", status),
+            OidcError::IdToken(reason) => write!(f, "invalid ID token: {}", reason),
+        }
+    }
+}
+
+impl From<OidcError> for AuthError {
+    fn from(error: OidcError) -> Self {
+        tracing::error!("OIDC login failed: {}", error);
+        AuthError::IdentityProvider
+    }
+}
+
+impl OidcClient {
+    pub fn new(config: OidcConfig) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            config,
+            metadata: OnceCell::new(),
+        }
+    }
+
+    async fn metadata(&self) -> Result<&ProviderMetadata, OidcError> {
+        self.metadata
+            .get_or_try_init(|| async {
+                let response = self
+                    .http
+                    .get(&self.config.discovery_url)
+                    .send()
+                    .await
+                    .map_err(OidcError::Request)?;
+                if !response.status().is_success() {
+                    return Err(OidcError::Status(response.status()));
+                }
+                response.json().await.map_err(OidcError::Request)
+            })
+            .await
+    }
+
+    async fn authorization_url(&self, state: &str, nonce: &str, code_verifier: &str) -> Result<String, OidcError> {
+        let metadata = self.metadata().await?;
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+
+        let url = reqwest::Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            [
+                ("response_type", "code"),
+                ("client_id", &self.config.client_id),
+                ("redirect_uri", &self.config.redirect_url),
+                ("scope", "openid email"),
+                ("state", state),
+                ("nonce", nonce),
+                ("code_challenge", &code_challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| OidcError::IdToken(format!("bad authorization endpoint: {}", e)))?;
+
+        Ok(url.into())
+    }
+
+    /// Trades an authorization code for the provider's ID token.
+    async fn exchange_code(&self, code: &str, code_verifier: &str) -> Result<String, OidcError> {
+        let metadata = self.metadata().await?;
+        let response = self
+            .http
+            .post(&metadata.token_endpoint)
+            .basic_auth(&self.config.client_id, Some(&self.config.client_secret))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.config.redirect_url),
+                ("code_verifier", code_verifier),
+            ])
+            .send()
+            .await
+            .map_err(OidcError::Request)?;
+        if !response.status().is_success() {
+            return Err(OidcError::Status(response.status()));
+        }
+
+        let tokens: TokenResponse = response.json().await.map_err(OidcError::Request)?;
+        Ok(tokens.id_token)
+    }
+
+    /// Checks the ID token's signature against the provider's JWKS, and its issuer, audience,
+    /// expiry and nonce.
+    async fn verify_id_token(&self, id_token: &str, nonce: &str) -> Result<IdTokenClaims, OidcError> {
+        let metadata = self.metadata().await?;
+        let header = decode_header(id_token).map_err(|e| OidcError::IdToken(e.to_string()))?;
+
+        let response = self.http.get(&metadata.jwks_uri).send().await.map_err(OidcError::Request)?;
+        if !response.status().is_success() {
+            return Err(OidcError::Status(response.status()));
+        }
+        let jwks: JwkSet = response.json().await.map_err(OidcError::Request)?;
+        let jwk = match &header.kid {
+            Some(kid) => jwks.find(kid),
+            None if jwks.keys.len() == 1 => jwks.keys.first(),
+            None => None,
+        }
+        .ok_or_else(|| OidcError::IdToken(format!("no provider key for kid {:?}", header.kid)))?;
+
+        // Only the algorithms the key is meant for, so an HMAC token can't be checked against it
+        let algorithms = match (&jwk.common.key_algorithm, &jwk.algorithm) {
+            (_, AlgorithmParameters::OctetKey(_)) => {
+                return Err(OidcError::IdToken("symmetric provider keys are not supported".to_string()));
+            }
+            (Some(algorithm), _) => vec![
+                algorithm
+                    .to_string()
+                    .parse()
+                    .map_err(|_| OidcError::IdToken(format!("unsupported key algorithm {:?}", algorithm)))?,
+            ],
+            (None, AlgorithmParameters::RSA(_)) => vec![
+                Algorithm::RS256,
+                Algorithm::RS384,
+                Algorithm::RS512,
+                Algorithm::PS256,
+                Algorithm::PS384,
+                Algorithm::PS512,
+            ],
+            (None, AlgorithmParameters::EllipticCurve(_)) => vec![Algorithm::ES256, Algorithm::ES384],
+            (None, AlgorithmParameters::OctetKeyPair(_)) => vec![Algorithm::EdDSA],
+        };
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| OidcError::IdToken(e.to_string()))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = algorithms;
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let claims = decode::<IdTokenClaims>(id_token, &key, &validation)
+            .map_err(|e| OidcError::IdToken(e.to_string()))?
+            .claims;
+
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(OidcError::IdToken("nonce mismatch".to_string()));
+        }
+
+        Ok(claims)
+    }
+}
+
+/// Starts a login by redirecting to the provider.
+///
+/// The state, nonce and PKCE verifier are kept server side until the provider sends the user
+/// back to `/auth/oidc/callback`; the state is also kept in a cookie, so only this browser can
+/// finish the login.
+pub async fn oidc_login(State(state): State<Arc<AppState>>) -> Result<(HeaderMap, Redirect), AuthError> {
+    let oidc = state.oidc.as_ref().ok_or(AuthError::OidcDisabled)?;
+
+    let login_state = random_token();
+    let nonce = random_token();
+    let code_verifier = random_token();
+    let url = oidc.authorization_url(&login_state, &nonce, &code_verifier).await?;
+
+    let now = Utc::now().timestamp();
+    sqlx::query("DELETE FROM oidc_logins WHERE expires_at < $1")
+        .bind(now)
+        .execute(&state.pool)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+    sqlx::query("INSERT INTO oidc_logins (state, nonce, code_verifier, expires_at) VALUES ($1, $2, $3, $4)")
+        .bind(&login_state)
+        .bind(&nonce)
+        .bind(&code_verifier)
+        .bind(now + LOGIN_EXPIRY)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Database error when storing OIDC login: {}", e);
+            AuthError::DatabaseError
+        })?;
+
+    Ok((session::set_oidc_state(&state, &login_state, LOGIN_EXPIRY), Redirect::to(&url)))
+}
+
+/// Finishes a login: verifies the provider's ID token and signs in the user with its email,
+/// creating them first if `auto_provision` is on.
+pub async fn oidc_callback(
+    State(state): State<Arc<AppState>>,
+    context: AuditContext,
+    headers: HeaderMap,
+    Query(query): Query<CallbackQuery>,
+) -> Result<(HeaderMap, Json<AuthResponse>), AuthError> {
+    let oidc = state.oidc.as_ref().ok_or(AuthError::OidcDisabled)?;
+    // Before using up the login, so a forged callback can't cancel the real one
+    session::verify_oidc_state(&headers, &query.state)?;
+
+    // Single use, whether or not the rest of the login works out
+    let (nonce, code_verifier): (String, String) = sqlx::query_as(
+        "DELETE FROM oidc_logins WHERE state = $1 AND expires_at >= $2 RETURNING nonce, code_verifier",
+    )
+    .bind(&query.state)
+    .bind(Utc::now().timestamp())
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|_| AuthError::DatabaseError)?
+    .ok_or(AuthError::InvalidOidcState)?;
+
+    if let Some(error) = &query.error {
+        tracing::warn!("OIDC provider refused login: {}", error);
+        return Err(AuthError::IdentityProvider);
+    }
+    let code = query.code.as_deref().ok_or(AuthError::InvalidOidcState)?;
+
+    let id_token = oidc.exchange_code(code, &code_verifier).await?;
+    let claims = oidc.verify_id_token(&id_token, &nonce).await?;
+    let email = match (claims.email, claims.email_verified) {
+        (Some(email), Some(true)) => email.to_lowercase(),
+        _ => {
+            tracing::warn!("OIDC login for subject {} without a verified email", claims.sub);
+            return Err(AuthError::IdentityProvider);
+        }
+    };
+
+    let user = state
+        .repositories
+        .users
+        .find_by_email(&email)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?
+        .and_then(|user| Some((user.id?, user.role)));
+    if let Some((user_id, _)) = &user {
+        check_enabled(&state, *user_id).await?;
+    }
+
+    let mut tx = state.pool.begin().await.map_err(|_| AuthError::DatabaseError)?;
+
+    let (user_id, role) = match user {
+        Some(user) => user,
+        None if oidc.config.auto_provision => {
+            let id: i64 = sqlx::query_scalar("INSERT INTO users (email, role) VALUES ($1, 'user') RETURNING id")
+                .bind(&email)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(|e| {
+                    tracing::error!("Database error when provisioning OIDC user: {}", e);
+                    AuthError::DatabaseError
+                })?;
+            let detail = Some("role=user via=oidc".to_string());
+            audit::record(&mut *tx, &context, AuditEvent::UserRegistered, Some(id), Some(id), detail)
+                .await
+                .map_err(|_| AuthError::DatabaseError)?;
+            tracing::info!("Provisioned user {} for OIDC email {}", id, email);
+            (id, "user".to_string())
+        }
+        None => {
+            let detail = format!("email={} reason=no_account via=oidc", email);
+            audit::record(&mut *tx, &context, AuditEvent::LoginFailed, None, None, Some(detail))
+                .await
+                .map_err(|_| AuthError::DatabaseError)?;
+            tx.commit().await.map_err(|_| AuthError::DatabaseError)?;
+            return Err(AuthError::NoAccount);
+        }
+    };
+
+    let family = Uuid::new_v4().to_string();
+    let (response, _) = issue_tokens(&mut tx, &state.jwt_config, user_id, &role, &family).await?;
+    let detail = Some("via=oidc".to_string());
+    audit::record(&mut *tx, &context, AuditEvent::LoginSucceeded, Some(user_id), Some(user_id), detail)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+    tx.commit().await.map_err(|_| AuthError::DatabaseError)?;
+
+    let mut headers = session::set_session(&state, &response);
+    session::clear_oidc_state(&state, &mut headers);
+    Ok((headers, Json(response)))
+}
+
+/// 32 random bytes, URL-safe; also a valid PKCE code verifier.
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
@@ -0,0 +1,142 @@
+use axum::http::{HeaderMap, HeaderValue, header};
+use rand::{RngCore, rngs::OsRng};
+
+use super::types::{AppState, AuthError, AuthResponse, CookieConfig, SameSite};
+
+/// Holds the access token; sent with every request.
+pub const ACCESS_COOKIE: &str = "access_token";
+/// Holds the refresh token; only sent to the `/auth` routes, below `CookieConfig::path_prefix`.
+pub const REFRESH_COOKIE: &str = "refresh_token";
+/// Readable by the page, which echoes it back in `CSRF_HEADER`.
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+/// Ties a single sign-on login to the browser that started it, whether or not cookie sessions
+/// are on.
+pub const OIDC_STATE_COOKIE: &str = "oidc_state";
+
+/// `Set-Cookie` headers for a new session, or none if cookie sessions are off.
+///
+/// The CSRF token is replaced along with the tokens.
+pub fn set_session(state: &AppState, response: &AuthResponse) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let Some(config) = &state.auth_config.cookies else {
+        return headers;
+    };
+
+    let mut csrf = [0u8; 32];
+    OsRng.fill_bytes(&mut csrf);
+    let refresh_expiry = state.jwt_config.refresh_expiry;
+
+    append(&mut headers, config, ACCESS_COOKIE, &response.access_token, "/", response.expires_in, true);
+    append(&mut headers, config, REFRESH_COOKIE, &response.refresh_token, &refresh_path(config), refresh_expiry, true);
+    append(&mut headers, config, CSRF_COOKIE, &hex::encode(csrf), "/", refresh_expiry, false);
+    headers
+}
+
+/// `Set-Cookie` headers expiring every session cookie, or none if cookie sessions are off.
+pub fn clear_session(state: &AppState) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(config) = &state.auth_config.cookies {
+        append(&mut headers, config, ACCESS_COOKIE, "", "/", 0, true);
+        append(&mut headers, config, REFRESH_COOKIE, "", &refresh_path(config), 0, true);
+        append(&mut headers, config, CSRF_COOKIE, "", "/", 0, false);
+    }
+    headers
+}
+
+fn refresh_path(config: &CookieConfig) -> String {
+    format!("{}/auth", config.path_prefix)
+}
+
+/// `Set-Cookie` header remembering the `state` of a single sign-on login for `max_age` seconds.
+pub fn set_oidc_state(state: &AppState, login_state: &str, max_age: i64) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    append(&mut headers, &oidc_cookie_config(state), OIDC_STATE_COOKIE, login_state, "/", max_age, true);
+    headers
+}
+
+/// Adds a `Set-Cookie` header expiring the single sign-on state cookie to `headers`.
+pub fn clear_oidc_state(state: &AppState, headers: &mut HeaderMap) {
+    append(headers, &oidc_cookie_config(state), OIDC_STATE_COOKIE, "", "/", 0, true);
+}
+
+/// Checks that the `state` the provider sent back belongs to a login this browser started.
+///
+/// Otherwise someone could have a victim open the callback of their own login, signing the
+/// victim in to the attacker's account.
+pub fn verify_oidc_state(headers: &HeaderMap, login_state: &str) -> Result<(), AuthError> {
+    match cookie(headers, OIDC_STATE_COOKIE) {
+        Some(expected) if constant_time_eq(expected.as_bytes(), login_state.as_bytes()) => Ok(()),
+        _ => {
+            tracing::warn!("Rejected OIDC callback whose state was not started by this browser");
+            Err(AuthError::InvalidOidcState)
+        }
+    }
+}
+
+fn oidc_cookie_config(state: &AppState) -> CookieConfig {
+    let cookies = state.auth_config.cookies.as_ref();
+    CookieConfig {
+        secure: cookies.is_none_or(|config| config.secure),
+        // The provider sends the browser back with a cross-site redirect, which Strict would drop
+        same_site: SameSite::Lax,
+        domain: cookies.and_then(|config| config.domain.clone()),
+        path_prefix: String::new(),
+    }
+}
+
+/// The value of cookie `name`, if the request has one.
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Checks the double-submit token: the `X-CSRF-Token` header must match the CSRF cookie.
+///
+/// Other sites can make a browser send our cookies but can't read them to fill in the header.
+pub fn verify_csrf(headers: &HeaderMap) -> Result<(), AuthError> {
+    let expected = cookie(headers, CSRF_COOKIE).filter(|token| !token.is_empty());
+    let presented = headers.get(CSRF_HEADER).and_then(|value| value.to_str().ok());
+
+    match (expected, presented) {
+        (Some(expected), Some(presented)) if constant_time_eq(expected.as_bytes(), presented.as_bytes()) => Ok(()),
+        _ => {
+            tracing::warn!("Rejected cookie-authenticated request without a valid CSRF token");
+            Err(AuthError::InvalidCsrfToken)
+        }
+    }
+}
+
+fn append(headers: &mut HeaderMap, config: &CookieConfig, name: &str, value: &str, path: &str, max_age: i64, http_only: bool) {
+    let mut cookie = format!(
+        "{}={}; Path={}; Max-Age={}; SameSite={}",
+        name,
+        value,
+        path,
+        max_age,
+        config.same_site.as_str()
+    );
+    if http_only {
+        cookie.push_str("; HttpOnly");
+    }
+    if config.secure {
+        cookie.push_str("; Secure");
+    }
+    if let Some(domain) = &config.domain {
+        cookie.push_str("; Domain=");
+        cookie.push_str(domain);
+    }
+
+    let value = HeaderValue::from_str(&cookie).expect("Tokens and cookie settings are valid header values");
+    headers.append(header::SET_COOKIE, value);
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -1,11 +1,20 @@
 use axum::{
-    http::StatusCode,
+    http::{header, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use sqlx::sqlite::SqlitePool;
+use sqlx::FromRow;
+use sqlx::AnyPool;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::keys::JwtKeys;
+use super::oidc::OidcClient;
+use crate::llm::LlmClient;
+use crate::repository::Repositories;
+use crate::mail::Mailer;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
@@ -13,21 +22,188 @@ pub struct Claims {
     pub exp: usize,
     pub iat: usize,
     pub role: String,
+    pub token_type: TokenType,
+    /// Unique token id; refresh tokens are tracked by it in the `refresh_tokens` table.
+    pub jti: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+}
+
+/// Keeps access and refresh tokens from being used in each other's place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenType {
+    Access,
+    Refresh,
+}
+
+/// The caller of a request, taken from a validated access token.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub id: i64,
+    pub role: String,
+}
+
+impl AuthUser {
+    pub fn is_admin(&self) -> bool {
+        self.role == "admin"
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct AppState {
-    pub pool: SqlitePool,
+    pub pool: AnyPool,
+    /// Lookups of users, their passwords and conversations; everything else queries `pool`.
+    pub repositories: Repositories,
     pub jwt_config: JwtConfig,
+    pub auth_config: AuthConfig,
+    pub mailer: Arc<dyn Mailer>,
+    /// Single sign-on through an OpenID Connect provider, if configured.
+    pub oidc: Option<Arc<OidcClient>>,
+    pub llm: LlmClient,
+    pub conversation_dir: PathBuf,
+    /// Origins allowed to call the API from a browser.
+    pub cors_origins: CorsOrigins,
+}
+
+/// Origins allowed to call the API from a browser.
+#[derive(Debug, Clone)]
+pub enum CorsOrigins {
+    /// Any origin, configured as `*`; browsers then send no cookies.
+    Any,
+    List(Vec<HeaderValue>),
+}
+
+impl CorsOrigins {
+    /// Parses configured origins, where a lone `*` allows any.
+    pub fn parse(origins: &[String]) -> Result<CorsOrigins, String> {
+        if origins.iter().any(|origin| origin == "*") {
+            return match origins.len() {
+                1 => Ok(CorsOrigins::Any),
+                _ => Err("* can't be combined with other origins".to_string()),
+            };
+        }
+
+        origins
+            .iter()
+            .map(|origin| match HeaderValue::from_str(origin) {
+                Ok(value) if !origin.is_empty() => Ok(value),
+                _ => Err(format!("invalid origin {:?}", origin)),
+            })
+            .collect::<Result<_, _>>()
+            .map(CorsOrigins::List)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct JwtConfig {
-    pub secret: String,
+    pub keys: Arc<JwtKeys>,
+    /// Written to the `iss` claim and required on incoming tokens, if set.
+    pub issuer: Option<String>,
+    /// Written to the `aud` claim and required on incoming tokens, if set.
+    pub audience: Option<String>,
     pub access_expiry: i64,
     pub refresh_expiry: i64,
 }
 
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    pub registration: RegistrationMode,
+    pub bcrypt_cost: u32,
+    /// Link mailed for password resets; the reset token is appended to it.
+    pub password_reset_url: String,
+    /// Lifetime of a password reset token, in seconds.
+    pub reset_token_expiry: i64,
+    /// Take the client IP for login throttling from the last `X-Forwarded-For` entry; only safe
+    /// behind a proxy that appends the address it was connected from.
+    pub trust_proxy_headers: bool,
+    /// Also hand out and accept tokens as `HttpOnly` cookies, if set.
+    pub cookies: Option<CookieConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CookieConfig {
+    /// Only send cookies over HTTPS; turn off for plain-HTTP local development.
+    pub secure: bool,
+    pub same_site: SameSite,
+    /// Share the cookies with subdomains of this domain, if set.
+    pub domain: Option<String>,
+    /// The prefix the router is nested under, e.g. `/api`, or empty when it is served at the
+    /// root. The refresh cookie is only sent to the `/auth` routes below it.
+    pub path_prefix: String,
+}
+
+/// The `SameSite` cookie attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+impl std::str::FromStr for SameSite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(SameSite::Strict),
+            "lax" => Ok(SameSite::Lax),
+            "none" => Ok(SameSite::None),
+            other => Err(format!("unknown SameSite mode {:?}, expected strict, lax or none", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    /// The provider's `/.well-known/openid-configuration` URL.
+    pub discovery_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Our `/auth/oidc/callback` URL, as registered with the provider.
+    pub redirect_url: String,
+    /// Create a `user` account on first login for emails that have none.
+    pub auto_provision: bool,
+}
+
+/// Who may call `POST /auth/register`. Admins can always register users.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationMode {
+    Open,
+    InviteOnly,
+    AdminOnly,
+}
+
+impl std::str::FromStr for RegistrationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(RegistrationMode::Open),
+            "invite_only" => Ok(RegistrationMode::InviteOnly),
+            "admin_only" => Ok(RegistrationMode::AdminOnly),
+            other => Err(format!(
+                "unknown registration mode {:?}, expected open, invite_only or admin_only",
+                other
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct LoginRequest {
     pub email: String,
@@ -42,7 +218,7 @@ pub struct AuthResponse {
     pub expires_in: i64,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, FromRow)]
 pub struct User {
     pub id: Option<i64>,
     pub email: String,
@@ -54,6 +230,38 @@ pub struct RefreshRequest {
     pub refresh_token: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct RegisterRequest {
+    pub email: String,
+    pub password: String,
+    /// Required in invite-only mode unless an admin is registering the user.
+    pub invite_code: Option<String>,
+    /// Only honoured for admins; everyone else signs up as `user`.
+    pub role: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Invite {
+    pub code: String,
+}
+
 #[derive(Debug)]
 pub enum AuthError {
     InvalidCredentials,
@@ -61,22 +269,61 @@ pub enum AuthError {
     TokenCreation,
     InvalidToken,
     MissingToken,
+    Forbidden,
+    AccountDisabled,
+    TooManyAttempts { retry_after: i64 },
+    EmailTaken,
+    PasswordHashing,
+    InvalidInvite,
+    InvalidResetToken,
+    Validation(&'static str),
+    OidcDisabled,
+    /// The callback's `state` is unknown, expired or already used.
+    InvalidOidcState,
+    IdentityProvider,
+    /// An OIDC login for an email with no account, and auto-provisioning is off.
+    NoAccount,
+    /// A cookie-authenticated request changing state without a matching `X-CSRF-Token`.
+    InvalidCsrfToken,
 }
 
 impl IntoResponse for AuthError {
     fn into_response(self) -> Response {
+        let retry_after = match &self {
+            AuthError::TooManyAttempts { retry_after } => Some(*retry_after),
+            _ => None,
+        };
+
         let (status, error_message) = match self {
             AuthError::InvalidCredentials => (StatusCode::UNAUTHORIZED, "Invalid credentials"),
             AuthError::DatabaseError => (StatusCode::INTERNAL_SERVER_ERROR, "Database error"),
             AuthError::TokenCreation => (StatusCode::INTERNAL_SERVER_ERROR, "Token creation failed"),
             AuthError::InvalidToken => (StatusCode::UNAUTHORIZED, "Invalid token"),
             AuthError::MissingToken => (StatusCode::UNAUTHORIZED, "Missing authorization token"),
+            AuthError::Forbidden => (StatusCode::FORBIDDEN, "Insufficient permissions"),
+            AuthError::AccountDisabled => (StatusCode::FORBIDDEN, "Account disabled"),
+            AuthError::TooManyAttempts { .. } => {
+                (StatusCode::TOO_MANY_REQUESTS, "Too many failed login attempts, try again later")
+            }
+            AuthError::EmailTaken => (StatusCode::CONFLICT, "Email already registered"),
+            AuthError::PasswordHashing => (StatusCode::INTERNAL_SERVER_ERROR, "Password hashing failed"),
+            AuthError::InvalidInvite => (StatusCode::FORBIDDEN, "Invalid or already used invite code"),
+            AuthError::InvalidResetToken => (StatusCode::BAD_REQUEST, "Invalid or expired reset token"),
+            AuthError::Validation(message) => (StatusCode::BAD_REQUEST, message),
+            AuthError::OidcDisabled => (StatusCode::NOT_FOUND, "Single sign-on is not configured"),
+            AuthError::InvalidOidcState => (StatusCode::BAD_REQUEST, "Invalid or expired login state"),
+            AuthError::IdentityProvider => (StatusCode::BAD_GATEWAY, "Identity provider login failed"),
+            AuthError::NoAccount => (StatusCode::FORBIDDEN, "No account for this email"),
+            AuthError::InvalidCsrfToken => (StatusCode::FORBIDDEN, "Invalid CSRF token"),
         };
 
         let body = Json(json!({
             "error": error_message,
         }));
 
-        (status, body).into_response()
+        match retry_after {
+            Some(seconds) => (status, [(header::RETRY_AFTER, seconds.to_string())], body).into_response(),
+            None => (status, body).into_response(),
+        }
     }
 }
@@ -0,0 +1,129 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+use crate::auth::types::AuthError;
+use crate::config::types::{ConfigArgs, ConfigError};
+
+/// Chat API server.
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    /// Same as the `migrate` command
+    #[arg(long, hide = true)]
+    pub migrate_only: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the HTTP server (the default)
+    Serve,
+    /// Apply pending database migrations
+    Migrate,
+    /// Validate the configuration, including key files, and print a summary
+    CheckConfig,
+    /// Manage user accounts
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Copy conversations in and out of the database as JSON
+    #[command(subcommand)]
+    Conversation(ConversationCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+    /// Create a user; the password is read from standard input
+    Create {
+        email: String,
+        #[arg(long, default_value = "user")]
+        role: String,
+    },
+    /// List every user
+    List,
+    /// Change a user's role
+    SetRole { email: String, role: String },
+    /// Set a user's password, read from standard input, and sign out their sessions
+    ResetPassword { email: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConversationCommand {
+    /// Write conversations and their messages as JSON
+    Export {
+        /// Only export this user's conversations
+        #[arg(long, value_name = "EMAIL")]
+        user: Option<String>,
+        /// Write to a file instead of standard output
+        #[arg(long, short, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Add the conversations in an `export` file as new conversations
+    Import {
+        file: PathBuf,
+        /// Give every conversation to this user instead of its original owner
+        #[arg(long, value_name = "EMAIL")]
+        user: Option<String>,
+    },
+}
+
+#[derive(Debug)]
+pub enum CliError {
+    Config(ConfigError),
+    Database(sqlx::Error),
+    Migrate(sqlx::migrate::MigrateError),
+    Io { what: String, error: std::io::Error },
+    Json { what: String, error: serde_json::Error },
+    UserNotFound(String),
+    InvalidRole(String),
+    Invalid(String),
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CliError::Config(e) => write!(f, "invalid configuration: {}", e),
+            CliError::Database(e) => write!(f, "database error: {}", e),
+            CliError::Migrate(e) => write!(f, "migration failed: {}", e),
+            CliError::Io { what, error } => write!(f, "{}: {}", what, error),
+            CliError::Json { what, error } => write!(f, "{}: {}", what, error),
+            CliError::UserNotFound(email) => write!(f, "no user with email {}", email),
+            CliError::InvalidRole(role) => write!(f, "unknown role {:?}, expected user or admin", role),
+            CliError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+impl From<ConfigError> for CliError {
+    fn from(e: ConfigError) -> Self {
+        CliError::Config(e)
+    }
+}
+
+impl From<sqlx::Error> for CliError {
+    fn from(e: sqlx::Error) -> Self {
+        CliError::Database(e)
+    }
+}
+
+impl From<sqlx::migrate::MigrateError> for CliError {
+    fn from(e: sqlx::migrate::MigrateError) -> Self {
+        CliError::Migrate(e)
+    }
+}
+
+impl From<AuthError> for CliError {
+    fn from(e: AuthError) -> Self {
+        match e {
+            AuthError::Validation(message) => CliError::Invalid(message.to_string()),
+            AuthError::EmailTaken => CliError::Invalid("email already registered".to_string()),
+            AuthError::PasswordHashing => CliError::Invalid("password hashing failed".to_string()),
+            other => CliError::Invalid(format!("{:?}", other)),
+        }
+    }
+}
@@ -0,0 +1,440 @@
+pub mod types;
+
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use axum::http::HeaderValue;
+use jsonwebtoken::Algorithm;
+use tracing_subscriber::EnvFilter;
+use types::{Config, ConfigArgs, ConfigError, MailTransport};
+
+use crate::auth::keys::JwtKeys;
+use crate::auth::types::{AuthConfig, CookieConfig, CorsOrigins, JwtConfig, OidcConfig, SameSite};
+use crate::db;
+use crate::llm::types::LlmConfig;
+use crate::mail::types::MailConfig;
+
+/// Read when neither `--config` nor `CONFIG_FILE` names a file, if it exists.
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// HMAC secrets shorter than the SHA-256 output weaken HS256.
+const MIN_SECRET_LENGTH: usize = 32;
+
+impl Config {
+    /// Layers the config file, the process environment and `args`.
+    ///
+    /// Only parse errors are reported; commands call `validate` for the settings they rely on.
+    pub fn load(args: &ConfigArgs) -> Result<Config, ConfigError> {
+        Config::load_from(args, |name| std::env::var(name).ok())
+    }
+
+    /// `load` with environment variables looked up through `var`.
+    pub fn load_from(args: &ConfigArgs, var: impl Fn(&str) -> Option<String>) -> Result<Config, ConfigError> {
+        let path = args.config.clone().or_else(|| var("CONFIG_FILE").map(PathBuf::from));
+        let mut config = match path {
+            Some(path) => Config::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Config::default(),
+        };
+
+        config.apply_env(Env(var))?;
+        config.apply_args(args);
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|error| ConfigError::Read {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })
+    }
+
+    fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, env: Env<F>) -> Result<(), ConfigError> {
+        env.parse("BIND_ADDRESS", &mut self.server.bind)?;
+        if let Some(origins) = env.get("CORS_ALLOWED_ORIGINS") {
+            self.server.cors_origins = origins.split(',').map(|o| o.trim().to_string()).collect();
+        }
+        env.parse("LOG_LEVEL", &mut self.server.log_level)?;
+
+        env.parse("DATABASE_URL", &mut self.database.url)?;
+        env.flag("DATABASE_CREATE_IF_MISSING", &mut self.database.create_if_missing)?;
+        env.parse("DATABASE_MAX_CONNECTIONS", &mut self.database.max_connections)?;
+        env.parse("DATABASE_MIN_CONNECTIONS", &mut self.database.min_connections)?;
+        env.parse("DATABASE_JOURNAL_MODE", &mut self.database.journal_mode)?;
+        env.parse("DATABASE_SYNCHRONOUS", &mut self.database.synchronous)?;
+        env.parse("DATABASE_BUSY_TIMEOUT", &mut self.database.busy_timeout)?;
+        env.flag("DATABASE_FOREIGN_KEYS", &mut self.database.foreign_keys)?;
+        env.parse("DATABASE_STATEMENT_CACHE_SIZE", &mut self.database.statement_cache_size)?;
+        env.parse("DATABASE_MAINTENANCE_INTERVAL", &mut self.database.maintenance_interval)?;
+
+        env.parse("JWT_ALGORITHM", &mut self.jwt.algorithm)?;
+        env.parse("JWT_KEY_ID", &mut self.jwt.key_id)?;
+        env.optional("JWT_SECRET", &mut self.jwt.secret)?;
+        env.optional("JWT_PRIVATE_KEY_FILE", &mut self.jwt.private_key_file)?;
+        env.optional("JWT_PUBLIC_KEY_FILE", &mut self.jwt.public_key_file)?;
+        // `kid=path` pairs, comma-separated
+        if let Some(keys) = env.get("JWT_VERIFICATION_KEYS") {
+            for entry in keys.split(',').filter(|e| !e.trim().is_empty()) {
+                let (kid, path) = entry.split_once('=').ok_or_else(|| ConfigError::Invalid {
+                    key: "JWT_VERIFICATION_KEYS",
+                    message: format!("entries must be kid=path, got {:?}", entry),
+                })?;
+                self.jwt.verification_keys.insert(kid.trim().to_string(), path.trim().into());
+            }
+        }
+        env.optional("JWT_ISSUER", &mut self.jwt.issuer)?;
+        env.optional("JWT_AUDIENCE", &mut self.jwt.audience)?;
+        env.parse("JWT_ACCESS_EXPIRY", &mut self.jwt.access_expiry)?;
+        env.parse("JWT_REFRESH_EXPIRY", &mut self.jwt.refresh_expiry)?;
+
+        env.parse("REGISTRATION_MODE", &mut self.auth.registration)?;
+        env.parse("BCRYPT_COST", &mut self.auth.bcrypt_cost)?;
+        env.parse("PASSWORD_RESET_URL", &mut self.auth.password_reset_url)?;
+        env.parse("RESET_TOKEN_EXPIRY", &mut self.auth.reset_token_expiry)?;
+        env.flag("TRUST_PROXY_HEADERS", &mut self.auth.trust_proxy_headers)?;
+
+        env.flag("SESSION_COOKIES", &mut self.cookies.enabled)?;
+        env.flag("COOKIE_SECURE", &mut self.cookies.secure)?;
+        env.parse("COOKIE_SAME_SITE", &mut self.cookies.same_site)?;
+        env.optional("COOKIE_DOMAIN", &mut self.cookies.domain)?;
+        env.parse("COOKIE_PATH_PREFIX", &mut self.cookies.path_prefix)?;
+
+        env.parse("MAIL_TRANSPORT", &mut self.mail.transport)?;
+        env.parse("MAIL_DIR", &mut self.mail.dir)?;
+        env.optional("SMTP_HOST", &mut self.mail.smtp_host)?;
+        env.optional("SMTP_PORT", &mut self.mail.smtp_port)?;
+        env.optional("SMTP_USERNAME", &mut self.mail.smtp_username)?;
+        env.optional("SMTP_PASSWORD", &mut self.mail.smtp_password)?;
+        env.optional("MAIL_FROM", &mut self.mail.from)?;
+
+        env.optional("OIDC_DISCOVERY_URL", &mut self.oidc.discovery_url)?;
+        env.optional("OIDC_CLIENT_ID", &mut self.oidc.client_id)?;
+        env.optional("OIDC_CLIENT_SECRET", &mut self.oidc.client_secret)?;
+        env.optional("OIDC_REDIRECT_URL", &mut self.oidc.redirect_url)?;
+        env.flag("OIDC_AUTO_PROVISION", &mut self.oidc.auto_provision)?;
+
+        env.parse("LLM_BASE_URL", &mut self.llm.base_url)?;
+        env.parse("LLM_MODEL", &mut self.llm.model)?;
+        env.optional("LLM_API_KEY", &mut self.llm.api_key)?;
+
+        env.parse("CONVERSATION_DIR", &mut self.storage.conversation_dir)?;
+
+        env.optional("ADMIN_EMAIL", &mut self.admin.email)?;
+        env.optional("ADMIN_PASSWORD", &mut self.admin.password)?;
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &ConfigArgs) {
+        if let Some(bind) = args.bind {
+            self.server.bind = bind;
+        }
+        if let Some(url) = &args.database_url {
+            self.database.url = url.clone();
+        }
+        if let Some(level) = &args.log_level {
+            self.server.log_level = level.clone();
+        }
+        if !args.cors_origins.is_empty() {
+            self.server.cors_origins = args.cors_origins.clone();
+        }
+        if let Some(email) = &args.admin_email {
+            self.admin.email = Some(email.clone());
+        }
+        if let Some(dir) = &args.conversation_dir {
+            self.storage.conversation_dir = dir.clone();
+        }
+        if let Some(url) = &args.llm_base_url {
+            self.llm.base_url = url.clone();
+        }
+        if let Some(model) = &args.llm_model {
+            self.llm.model = model.clone();
+        }
+    }
+
+    /// Checks the settings that can be checked without touching the network or key files.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key, message: &str| ConfigError::Invalid {
+            key,
+            message: message.to_string(),
+        };
+
+        EnvFilter::try_new(&self.server.log_level).map_err(|e| ConfigError::Invalid {
+            key: "server.log_level (LOG_LEVEL)",
+            message: e.to_string(),
+        })?;
+        if matches!(self.cors_origins()?, CorsOrigins::Any) && self.cookies.enabled {
+            return Err(invalid(
+                "server.cors_origins (CORS_ALLOWED_ORIGINS)",
+                "cookie sessions need the allowed origins listed, not *",
+            ));
+        }
+
+        if self.database.url.is_empty() {
+            return Err(ConfigError::Missing("database.url (DATABASE_URL)"));
+        }
+        if self.database.max_connections == 0 {
+            return Err(invalid("database.max_connections", "must be at least 1"));
+        }
+        if self.database.min_connections > self.database.max_connections {
+            return Err(invalid("database.min_connections", "can't exceed database.max_connections"));
+        }
+        let url_error = |message: String| ConfigError::Invalid {
+            key: "database.url (DATABASE_URL)",
+            message,
+        };
+        if db::Backend::from_url(&self.database.url).map_err(url_error)? == db::Backend::Sqlite {
+            db::sqlite_options(&self.database).map_err(|e| url_error(e.to_string()))?;
+        }
+
+        match self.jwt.algorithm {
+            Algorithm::HS256 => {
+                let secret = self.jwt.secret.as_deref().ok_or(ConfigError::Missing("jwt.secret (JWT_SECRET)"))?;
+                if secret.len() < MIN_SECRET_LENGTH {
+                    return Err(ConfigError::Invalid {
+                        key: "jwt.secret (JWT_SECRET)",
+                        message: format!("must be at least {} bytes, got {}", MIN_SECRET_LENGTH, secret.len()),
+                    });
+                }
+            }
+            Algorithm::RS256 | Algorithm::ES256 | Algorithm::EdDSA => {
+                if self.jwt.private_key_file.is_none() {
+                    return Err(ConfigError::Missing("jwt.private_key_file (JWT_PRIVATE_KEY_FILE)"));
+                }
+                if self.jwt.public_key_file.is_none() {
+                    return Err(ConfigError::Missing("jwt.public_key_file (JWT_PUBLIC_KEY_FILE)"));
+                }
+            }
+            other => {
+                return Err(ConfigError::Invalid {
+                    key: "jwt.algorithm (JWT_ALGORITHM)",
+                    message: format!("{:?} is not supported, use HS256, RS256, ES256 or EdDSA", other),
+                });
+            }
+        }
+        if self.jwt.access_expiry <= 0 {
+            return Err(invalid("jwt.access_expiry (JWT_ACCESS_EXPIRY)", "must be positive"));
+        }
+        if self.jwt.refresh_expiry < self.jwt.access_expiry {
+            return Err(invalid("jwt.refresh_expiry (JWT_REFRESH_EXPIRY)", "can't be shorter than jwt.access_expiry"));
+        }
+
+        if !(4..=31).contains(&self.auth.bcrypt_cost) {
+            return Err(invalid("auth.bcrypt_cost (BCRYPT_COST)", "must be between 4 and 31"));
+        }
+        if self.auth.reset_token_expiry <= 0 {
+            return Err(invalid("auth.reset_token_expiry (RESET_TOKEN_EXPIRY)", "must be positive"));
+        }
+
+        if self.cookies.same_site == SameSite::None && !self.cookies.secure {
+            return Err(invalid("cookies.same_site (COOKIE_SAME_SITE)", "none needs cookies.secure"));
+        }
+        if let Some(domain) = &self.cookies.domain
+            && (domain.is_empty() || domain.contains(';') || HeaderValue::from_str(domain).is_err())
+        {
+            return Err(invalid("cookies.domain (COOKIE_DOMAIN)", "must be a plain domain name"));
+        }
+        let prefix = &self.cookies.path_prefix;
+        if !prefix.is_empty()
+            && (!prefix.starts_with('/') || prefix.ends_with('/') || prefix.contains(';') || HeaderValue::from_str(prefix).is_err())
+        {
+            return Err(invalid("cookies.path_prefix (COOKIE_PATH_PREFIX)", "must be a path like /api, without a trailing /"));
+        }
+
+        if self.mail.transport == MailTransport::Smtp {
+            if self.mail.smtp_host.is_none() {
+                return Err(ConfigError::Missing("mail.smtp_host (SMTP_HOST)"));
+            }
+            if self.mail.from.is_none() {
+                return Err(ConfigError::Missing("mail.from (MAIL_FROM)"));
+            }
+        }
+
+        if self.oidc.discovery_url.is_some() {
+            if self.oidc.client_id.is_none() {
+                return Err(ConfigError::Missing("oidc.client_id (OIDC_CLIENT_ID)"));
+            }
+            if self.oidc.client_secret.is_none() {
+                return Err(ConfigError::Missing("oidc.client_secret (OIDC_CLIENT_SECRET)"));
+            }
+            if self.oidc.redirect_url.is_none() {
+                return Err(ConfigError::Missing("oidc.redirect_url (OIDC_REDIRECT_URL)"));
+            }
+        }
+
+        if self.llm.base_url.is_empty() {
+            return Err(ConfigError::Missing("llm.base_url (LLM_BASE_URL)"));
+        }
+        if self.llm.model.is_empty() {
+            return Err(ConfigError::Missing("llm.model (LLM_MODEL)"));
+        }
+
+        match (&self.admin.email, &self.admin.password) {
+            (Some(_), None) => return Err(ConfigError::Missing("admin.password (ADMIN_PASSWORD)")),
+            (None, Some(_)) => return Err(ConfigError::Missing("admin.email (ADMIN_EMAIL)")),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The origins allowed to call the API from a browser.
+    pub fn cors_origins(&self) -> Result<CorsOrigins, ConfigError> {
+        CorsOrigins::parse(&self.server.cors_origins).map_err(|message| ConfigError::Invalid {
+            key: "server.cors_origins (CORS_ALLOWED_ORIGINS)",
+            message,
+        })
+    }
+
+    /// Builds the signing and verification keys, reading any key files.
+    pub fn jwt_config(&self) -> Result<JwtConfig, ConfigError> {
+        let read = |key: &'static str, path: &Path| {
+            std::fs::read(path).map_err(|e| ConfigError::Invalid {
+                key,
+                message: format!("failed to read {}: {}", path.display(), e),
+            })
+        };
+        let key_error = |key: &'static str, e: crate::auth::keys::KeyError| ConfigError::Invalid {
+            key,
+            message: e.to_string(),
+        };
+
+        let mut keys = match (&self.jwt.secret, &self.jwt.private_key_file, &self.jwt.public_key_file) {
+            (Some(secret), _, _) if self.jwt.algorithm == Algorithm::HS256 => {
+                JwtKeys::hmac(&self.jwt.key_id, secret.as_bytes())
+            }
+            (_, Some(private_key_file), Some(public_key_file)) => {
+                let private_pem = read("jwt.private_key_file (JWT_PRIVATE_KEY_FILE)", private_key_file)?;
+                let public_pem = read("jwt.public_key_file (JWT_PUBLIC_KEY_FILE)", public_key_file)?;
+                JwtKeys::from_pem(&self.jwt.key_id, self.jwt.algorithm, &private_pem, &public_pem)
+                    .map_err(|e| key_error("jwt.private_key_file (JWT_PRIVATE_KEY_FILE)", e))?
+            }
+            _ => return Err(ConfigError::Missing("jwt.secret (JWT_SECRET)")),
+        };
+        for (kid, path) in &self.jwt.verification_keys {
+            let pem = read("jwt.verification_keys (JWT_VERIFICATION_KEYS)", path)?;
+            keys.add_verification_key(kid, &pem)
+                .map_err(|e| key_error("jwt.verification_keys (JWT_VERIFICATION_KEYS)", e))?;
+        }
+
+        Ok(JwtConfig {
+            keys: Arc::new(keys),
+            issuer: self.jwt.issuer.clone(),
+            audience: self.jwt.audience.clone(),
+            access_expiry: self.jwt.access_expiry,
+            refresh_expiry: self.jwt.refresh_expiry,
+        })
+    }
+
+    pub fn auth_config(&self) -> AuthConfig {
+        AuthConfig {
+            registration: self.auth.registration,
+            bcrypt_cost: self.auth.bcrypt_cost,
+            password_reset_url: self.auth.password_reset_url.clone(),
+            reset_token_expiry: self.auth.reset_token_expiry,
+            trust_proxy_headers: self.auth.trust_proxy_headers,
+            cookies: self.cookies.enabled.then(|| CookieConfig {
+                secure: self.cookies.secure,
+                same_site: self.cookies.same_site,
+                domain: self.cookies.domain.clone(),
+                path_prefix: self.cookies.path_prefix.clone(),
+            }),
+        }
+    }
+
+    pub fn mail_config(&self) -> MailConfig {
+        match self.mail.transport {
+            MailTransport::Smtp => MailConfig::Smtp {
+                host: self.mail.smtp_host.clone().unwrap_or_default(),
+                port: self.mail.smtp_port,
+                username: self.mail.smtp_username.clone(),
+                password: self.mail.smtp_password.clone(),
+                from: self.mail.from.clone().unwrap_or_default(),
+            },
+            MailTransport::File => MailConfig::File {
+                dir: self.mail.dir.clone(),
+            },
+            MailTransport::Log => MailConfig::Log,
+        }
+    }
+
+    /// `None` unless single sign-on is configured.
+    pub fn oidc_config(&self) -> Option<OidcConfig> {
+        Some(OidcConfig {
+            discovery_url: self.oidc.discovery_url.clone()?,
+            client_id: self.oidc.client_id.clone()?,
+            client_secret: self.oidc.client_secret.clone()?,
+            redirect_url: self.oidc.redirect_url.clone()?,
+            auto_provision: self.oidc.auto_provision,
+        })
+    }
+
+    pub fn llm_config(&self) -> LlmConfig {
+        LlmConfig {
+            base_url: self.llm.base_url.clone(),
+            model: self.llm.model.clone(),
+            api_key: self.llm.api_key.clone(),
+        }
+    }
+}
+
+/// Environment variable lookup; unset and empty variables leave the setting alone.
+struct Env<F>(F);
+
+impl<F: Fn(&str) -> Option<String>> Env<F> {
+    fn get(&self, name: &str) -> Option<String> {
+        (self.0)(name).filter(|value| !value.is_empty())
+    }
+
+    fn parse<T>(&self, name: &'static str, target: &mut T) -> Result<(), ConfigError>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        if let Some(value) = self.get(name) {
+            *target = parse_var(name, &value)?;
+        }
+        Ok(())
+    }
+
+    fn optional<T>(&self, name: &'static str, target: &mut Option<T>) -> Result<(), ConfigError>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        if let Some(value) = self.get(name) {
+            *target = Some(parse_var(name, &value)?);
+        }
+        Ok(())
+    }
+
+    fn flag(&self, name: &'static str, target: &mut bool) -> Result<(), ConfigError> {
+        match self.get(name).as_deref() {
+            None => {}
+            Some("true" | "1") => *target = true,
+            Some("false" | "0") => *target = false,
+            Some(other) => {
+                return Err(ConfigError::Invalid {
+                    key: name,
+                    message: format!("expected true or false, got {:?}", other),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_var<T>(name: &'static str, value: &str) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value.parse().map_err(|e: T::Err| ConfigError::Invalid {
+        key: name,
+        message: e.to_string(),
+    })
+}
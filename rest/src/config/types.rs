@@ -0,0 +1,375 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::Args;
+use serde::Deserialize;
+
+use crate::auth::types::{RegistrationMode, SameSite};
+
+/// Everything the server is configured with.
+///
+/// Loaded from a TOML file, then overridden by environment variables, then by command-line flags.
+/// Every field has a default except `jwt.secret` (or the key files for asymmetric algorithms).
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerSection,
+    pub database: DatabaseSection,
+    pub jwt: JwtSection,
+    pub auth: AuthSection,
+    pub cookies: CookieSection,
+    pub mail: MailSection,
+    pub oidc: OidcSection,
+    pub llm: LlmSection,
+    pub storage: StorageSection,
+    pub admin: AdminSection,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub bind: SocketAddr,
+    /// Origins allowed to call the API from a browser; `*` allows any, but not with cookie sessions.
+    pub cors_origins: Vec<String>,
+    /// A `tracing` filter, e.g. `info` or `info,sqlx=warn`.
+    pub log_level: String,
+}
+
+impl Default for ServerSection {
+    fn default() -> Self {
+        ServerSection {
+            bind: SocketAddr::from(([0, 0, 0, 0], 8000)),
+            cors_origins: vec!["*".to_string()],
+            log_level: "debug".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseSection {
+    /// `sqlite:` or `postgres://`; the journal, locking and maintenance settings only apply to SQLite.
+    pub url: String,
+    /// Create the database file, and its directory, if it doesn't exist yet.
+    pub create_if_missing: bool,
+    pub max_connections: u32,
+    pub min_connections: u32,
+    /// WAL lets readers carry on while a chat reply is being written.
+    pub journal_mode: JournalMode,
+    pub synchronous: Synchronous,
+    /// Milliseconds a connection waits for a lock before failing with `SQLITE_BUSY`.
+    pub busy_timeout: u64,
+    pub foreign_keys: bool,
+    /// Prepared statements kept per connection.
+    pub statement_cache_size: usize,
+    /// Seconds between `PRAGMA optimize` and WAL checkpoint runs; 0 turns them off.
+    pub maintenance_interval: u64,
+}
+
+impl Default for DatabaseSection {
+    fn default() -> Self {
+        DatabaseSection {
+            url: "sqlite:sqlite/deepseek_chat.db".to_string(),
+            create_if_missing: true,
+            max_connections: 10,
+            min_connections: 0,
+            journal_mode: JournalMode::Wal,
+            synchronous: Synchronous::Normal,
+            busy_timeout: 5000,
+            foreign_keys: true,
+            statement_cache_size: 100,
+            maintenance_interval: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+}
+
+impl std::str::FromStr for JournalMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "delete" => Ok(JournalMode::Delete),
+            "truncate" => Ok(JournalMode::Truncate),
+            "persist" => Ok(JournalMode::Persist),
+            "memory" => Ok(JournalMode::Memory),
+            "wal" => Ok(JournalMode::Wal),
+            other => Err(format!(
+                "unknown journal mode {:?}, expected delete, truncate, persist, memory or wal",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl std::str::FromStr for Synchronous {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Synchronous::Off),
+            "normal" => Ok(Synchronous::Normal),
+            "full" => Ok(Synchronous::Full),
+            "extra" => Ok(Synchronous::Extra),
+            other => Err(format!("unknown synchronous level {:?}, expected off, normal, full or extra", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtSection {
+    pub algorithm: jsonwebtoken::Algorithm,
+    pub key_id: String,
+    /// Signing secret for HS256.
+    pub secret: Option<String>,
+    /// PEM signing key pair for RS256, ES256 and EdDSA.
+    pub private_key_file: Option<PathBuf>,
+    pub public_key_file: Option<PathBuf>,
+    /// Public keys still accepted after a rotation, by key id.
+    pub verification_keys: BTreeMap<String, PathBuf>,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    /// Seconds.
+    pub access_expiry: i64,
+    /// Seconds.
+    pub refresh_expiry: i64,
+}
+
+impl Default for JwtSection {
+    fn default() -> Self {
+        JwtSection {
+            algorithm: jsonwebtoken::Algorithm::HS256,
+            key_id: "default".to_string(),
+            secret: None,
+            private_key_file: None,
+            public_key_file: None,
+            verification_keys: BTreeMap::new(),
+            issuer: None,
+            audience: None,
+            access_expiry: 3600,
+            refresh_expiry: 7 * 24 * 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSection {
+    pub registration: RegistrationMode,
+    pub bcrypt_cost: u32,
+    pub password_reset_url: String,
+    /// Seconds.
+    pub reset_token_expiry: i64,
+    pub trust_proxy_headers: bool,
+}
+
+impl Default for AuthSection {
+    fn default() -> Self {
+        AuthSection {
+            registration: RegistrationMode::AdminOnly,
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+            password_reset_url: "http://localhost:3000/reset-password?token=".to_string(),
+            reset_token_expiry: 3600,
+            trust_proxy_headers: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CookieSection {
+    pub enabled: bool,
+    pub secure: bool,
+    pub same_site: SameSite,
+    pub domain: Option<String>,
+    pub path_prefix: String,
+}
+
+impl Default for CookieSection {
+    fn default() -> Self {
+        CookieSection {
+            enabled: false,
+            secure: true,
+            same_site: SameSite::Strict,
+            domain: None,
+            path_prefix: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    Smtp,
+    File,
+    #[default]
+    Log,
+}
+
+impl std::str::FromStr for MailTransport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "smtp" => Ok(MailTransport::Smtp),
+            "file" => Ok(MailTransport::File),
+            "log" => Ok(MailTransport::Log),
+            other => Err(format!("unknown mail transport {:?}, expected smtp, file or log", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailSection {
+    pub transport: MailTransport,
+    /// Where the `file` transport writes messages.
+    pub dir: PathBuf,
+    pub smtp_host: Option<String>,
+    pub smtp_port: Option<u16>,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub from: Option<String>,
+}
+
+impl Default for MailSection {
+    fn default() -> Self {
+        MailSection {
+            transport: MailTransport::Log,
+            dir: "mail".into(),
+            smtp_host: None,
+            smtp_port: None,
+            smtp_username: None,
+            smtp_password: None,
+            from: None,
+        }
+    }
+}
+
+/// Single sign-on is enabled by setting `discovery_url`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcSection {
+    pub discovery_url: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub redirect_url: Option<String>,
+    pub auto_provision: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LlmSection {
+    pub base_url: String,
+    pub model: String,
+    pub api_key: Option<String>,
+}
+
+impl Default for LlmSection {
+    fn default() -> Self {
+        LlmSection {
+            base_url: "https://api.deepseek.com".to_string(),
+            model: "deepseek-chat".to_string(),
+            api_key: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSection {
+    /// Legacy JSON conversation files are imported from here on startup.
+    pub conversation_dir: PathBuf,
+}
+
+impl Default for StorageSection {
+    fn default() -> Self {
+        StorageSection {
+            conversation_dir: "conversations".into(),
+        }
+    }
+}
+
+/// An administrator created on startup while the database has none, so a fresh install can be
+/// signed in to. Ignored once any administrator exists.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminSection {
+    pub email: Option<String>,
+    pub password: Option<String>,
+}
+
+/// Command-line flags that override the config file and environment, accepted before or after
+/// the subcommand.
+#[derive(Debug, Clone, Default, Args)]
+pub struct ConfigArgs {
+    /// TOML config file [env: CONFIG_FILE] [default: config.toml, if present]
+    #[arg(long, value_name = "PATH", global = true)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on
+    #[arg(long, value_name = "ADDR", global = true)]
+    pub bind: Option<SocketAddr>,
+    /// Database connection URL
+    #[arg(long, value_name = "URL", global = true)]
+    pub database_url: Option<String>,
+    /// A tracing filter, e.g. `info` or `info,sqlx=warn`
+    #[arg(long, value_name = "FILTER", global = true)]
+    pub log_level: Option<String>,
+    /// Origin allowed to call the API from a browser; repeat for several
+    #[arg(long = "cors-origin", value_name = "ORIGIN", global = true)]
+    pub cors_origins: Vec<String>,
+    /// Email of the administrator to create if there is none; the password comes from ADMIN_PASSWORD
+    #[arg(long, value_name = "EMAIL", global = true)]
+    pub admin_email: Option<String>,
+    /// Directory legacy JSON conversation files are imported from
+    #[arg(long, value_name = "DIR", global = true)]
+    pub conversation_dir: Option<PathBuf>,
+    /// Base URL of the chat completion API
+    #[arg(long, value_name = "URL", global = true)]
+    pub llm_base_url: Option<String>,
+    /// Chat completion model
+    #[arg(long, value_name = "MODEL", global = true)]
+    pub llm_model: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read { path: PathBuf, error: std::io::Error },
+    Parse { path: PathBuf, message: String },
+    /// A required setting, named by its config key and environment variable.
+    Missing(&'static str),
+    Invalid { key: &'static str, message: String },
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read { path, error } => write!(f, "failed to read {}: {}", path.display(), error),
+            ConfigError::Parse { path, message } => write!(f, "invalid config file {}: {}", path.display(), message),
+            ConfigError::Missing(key) => write!(f, "{} must be set", key),
+            ConfigError::Invalid { key, message } => write!(f, "{}: {}", key, message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
@@ -1,54 +1,61 @@
-mod types;
+pub mod types;
 
+use std::convert::Infallible;
 use std::sync::Arc;
-use axum::{Json, extract::{State, Path}};
-use chrono::NaiveDateTime;
+use std::path::Path as FsPath;
+use axum::{Json, extract::{State, Path, Query}, http::StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use chrono::{DateTime, NaiveDateTime, SubsecRound, Utc};
 use std::fs;
+use tokio::sync::mpsc;
+use sqlx::AnyPool;
+use tokio_stream::wrappers::ReceiverStream;
 use crate::AppState;
-use types::{Conversation, DbConversation};
+use crate::audit::{self, types::{AuditContext, AuditEvent}};
+use crate::auth::types::AuthUser;
+use crate::db::Flag;
+use crate::llm::types::{ChatMessage, ChatStreamEvent, Completion};
+use types::{
+    ConversationError, Conversation, CreateConversationRequest, DbConversation, DbMessage,
+    ExportedConversation, ListConversationsQuery, SendMessageRequest, UpdateConversationRequest,
+};
 
+pub(crate) const UPDATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+const DEFAULT_TITLE: &str = "New chat";
 
 pub async fn get_conversation_content(
     Path(id): Path<i64>,
     State(state): State<Arc<AppState>>,
-) -> Json<serde_json::Value> {
-    let db_conversation = sqlx::query_as::<_, DbConversation>(
-        "SELECT id, title, updatetime, filepath FROM conversation WHERE id = ?"
-    )
-    .bind(id)
-    .fetch_one(&state.pool)
-    .await
-    .unwrap();
+    user: AuthUser,
+) -> Result<Json<Vec<ChatMessage>>, ConversationError> {
+    let messages = load_history(&state, id, &user).await?;
 
-    let filepath = "conversations/".to_string() + &db_conversation.filepath;
-    println!("Filepath: {}", filepath);
-    let file_content = fs::read(&filepath)
-        .unwrap_or_else(|e| panic!("Failed to read file {}: {}", db_conversation.filepath, e));
-    
-    let file_content = String::from_utf8(file_content)
-        .unwrap_or_else(|e| panic!("Failed to decode file {} as UTF-8: {}", db_conversation.filepath, e));
-    
-    let json_value: serde_json::Value = serde_json::from_str(&file_content)
-        .unwrap_or_else(|e| panic!("Failed to parse JSON from file {}: {}", db_conversation.filepath, e));
-
-    Json(json_value)
+    Ok(Json(messages))
 }
 
 pub async fn get_conversations(
     State(state): State<Arc<AppState>>,
-) -> Json<Vec<Conversation>> {
-    let db_conversations = sqlx::query_as::<_, DbConversation>(
-        "SELECT id, title, updatetime, filepath FROM conversation ORDER BY updatetime DESC"
-    )
-    .fetch_all(&state.pool)
-    .await
-    .unwrap();
+    user: AuthUser,
+    Query(query): Query<ListConversationsQuery>,
+) -> Result<Json<Vec<Conversation>>, ConversationError> {
+    let owner = if query.all && user.is_admin() { None } else { Some(user.id) };
+    let db_conversations = state.repositories.conversations.list(owner).await.map_err(|e| {
+        tracing::error!("Database error when listing conversations: {}", e);
+        ConversationError::DatabaseError
+    })?;
 
     let conversations = db_conversations.into_iter().map(|db_conv| {
-        let datetime = NaiveDateTime::parse_from_str(&db_conv.updatetime, "%Y-%m-%d %H:%M:%S")
-            .unwrap()
-            .and_utc();
-            
+        // A malformed timestamp should not hide the rest of the list
+        let datetime = NaiveDateTime::parse_from_str(&db_conv.updatetime, UPDATETIME_FORMAT)
+            .map(|datetime| datetime.and_utc())
+            .unwrap_or_else(|e| {
+                tracing::warn!(
+                    "Conversation {} has an invalid updatetime {:?}: {}",
+                    db_conv.id, db_conv.updatetime, e
+                );
+                DateTime::UNIX_EPOCH
+            });
+
         Conversation {
             id: db_conv.id,
             title: db_conv.title,
@@ -57,5 +64,461 @@ pub async fn get_conversations(
         }
     }).collect();
 
-    Json(conversations)
+    Ok(Json(conversations))
+}
+
+pub async fn create_conversation(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Json(request): Json<CreateConversationRequest>,
+) -> Result<(StatusCode, Json<Conversation>), ConversationError> {
+    let title = match request.title {
+        Some(title) => validate_title(title)?,
+        None => DEFAULT_TITLE.to_string(),
+    };
+
+    // Stored with second precision, so report the same instant back
+    let now = Utc::now().trunc_subsecs(0);
+    let updatetime = now.format(UPDATETIME_FORMAT).to_string();
+
+    // Messages live in the `messages` table; `filepath` is only set for conversations imported from files.
+    let id: i64 =
+        sqlx::query_scalar("INSERT INTO conversation (title, updatetime, filepath, userid) VALUES ($1, $2, '', $3) RETURNING id")
+            .bind(&title)
+            .bind(&updatetime)
+            .bind(user.id)
+            .fetch_one(&state.pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("Database error when creating conversation: {}", e);
+                ConversationError::DatabaseError
+            })?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(Conversation {
+            id,
+            title,
+            time: now,
+            filepath: String::new(),
+        }),
+    ))
+}
+
+pub async fn update_conversation(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Json(request): Json<UpdateConversationRequest>,
+) -> Result<Json<Conversation>, ConversationError> {
+    let title = validate_title(request.title)?;
+    // Stored with second precision, so report the same instant back
+    let now = Utc::now().trunc_subsecs(0);
+    let updatetime = now.format(UPDATETIME_FORMAT).to_string();
+
+    let db_conversation = sqlx::query_as::<_, DbConversation>(
+        "UPDATE conversation SET title = $1, updatetime = $2 WHERE id = $3 AND (userid = $4 OR $5) RETURNING id, title, updatetime, filepath, import_pending"
+    )
+    .bind(&title)
+    .bind(&updatetime)
+    .bind(id)
+    .bind(user.id)
+    .bind(user.is_admin())
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Database error when updating conversation {}: {}", id, e);
+        ConversationError::DatabaseError
+    })?
+    .ok_or(ConversationError::NotFound)?;
+
+    Ok(Json(Conversation {
+        id: db_conversation.id,
+        title: db_conversation.title,
+        time: now,
+        filepath: db_conversation.filepath,
+    }))
+}
+
+pub async fn delete_conversation(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    context: AuditContext,
+) -> Result<StatusCode, ConversationError> {
+    let mut tx = state.pool.begin().await.map_err(|e| {
+        tracing::error!("Database error when starting transaction: {}", e);
+        ConversationError::DatabaseError
+    })?;
+
+    let (filepath, owner, Flag(import_pending)): (String, Option<i64>, Flag) = sqlx::query_as(
+        "DELETE FROM conversation WHERE id = $1 AND (userid = $2 OR $3) RETURNING filepath, userid, import_pending"
+    )
+    .bind(id)
+    .bind(user.id)
+    .bind(user.is_admin())
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| {
+        tracing::error!("Database error when deleting conversation {}: {}", id, e);
+        ConversationError::DatabaseError
+    })?
+    .ok_or(ConversationError::NotFound)?;
+
+    sqlx::query("DELETE FROM messages WHERE conversation_id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            tracing::error!("Database error when deleting messages of conversation {}: {}", id, e);
+            ConversationError::DatabaseError
+        })?;
+
+    let detail = format!("conversation={}", id);
+    audit::record(&mut *tx, &context, AuditEvent::ConversationDeleted, Some(user.id), owner, Some(detail))
+        .await
+        .map_err(|e| {
+            tracing::error!("Database error when auditing deletion of conversation {}: {}", id, e);
+            ConversationError::DatabaseError
+        })?;
+
+    tx.commit().await.map_err(|e| {
+        tracing::error!("Database error when deleting conversation {}: {}", id, e);
+        ConversationError::DatabaseError
+    })?;
+
+    remove_legacy_file(&state.conversation_dir, &filepath, import_pending);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn send_message(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Json(request): Json<SendMessageRequest>,
+) -> Result<Json<ChatMessage>, ConversationError> {
+    let mut messages = load_history(&state, id, &user).await?;
+    let user_message = ChatMessage::new("user", request.content);
+    messages.push(user_message.clone());
+
+    let completion = state.llm.chat_completion(&messages).await.map_err(|e| {
+        tracing::error!("Chat completion failed for conversation {}: {}", id, e);
+        ConversationError::UpstreamError
+    })?;
+
+    // Only persist once the model has answered, so a failed request leaves the history untouched.
+    append_messages(&state.pool, id, &user_message, &completion).await?;
+
+    Ok(Json(completion.message))
+}
+
+pub async fn stream_message(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Json(request): Json<SendMessageRequest>,
+) -> Result<Sse<ReceiverStream<Result<Event, Infallible>>>, ConversationError> {
+    let mut messages = load_history(&state, id, &user).await?;
+    let user_message = ChatMessage::new("user", request.content);
+    messages.push(user_message.clone());
+
+    let mut upstream = state.llm.chat_completion_stream(&messages).await.map_err(|e| {
+        tracing::error!("Chat completion stream failed for conversation {}: {}", id, e);
+        ConversationError::UpstreamError
+    })?;
+
+    // The relay runs in its own task so the reply is persisted even after the client goes away.
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let mut content = String::new();
+        let mut model = None;
+        let mut usage = None;
+        let truncated = loop {
+            let event = tokio::select! {
+                _ = tx.closed() => break true,
+                event = upstream.next_event() => event,
+            };
+
+            match event {
+                Ok(Some(ChatStreamEvent::Chunk { payload, delta, model: chunk_model, usage: chunk_usage })) => {
+                    if let Some(delta) = delta {
+                        content.push_str(&delta);
+                    }
+                    model = chunk_model.or(model);
+                    usage = chunk_usage.or(usage);
+                    if tx.send(Ok(Event::default().data(payload))).await.is_err() {
+                        break true;
+                    }
+                }
+                Ok(Some(ChatStreamEvent::Done)) | Ok(None) => break false,
+                Err(e) => {
+                    tracing::error!("Chat completion stream failed for conversation {}: {}", id, e);
+                    let _ = tx
+                        .send(Ok(Event::default().event("error").data("Model request failed")))
+                        .await;
+                    break true;
+                }
+            }
+        };
+
+        // Dropping the upstream response aborts the request if it is still in flight.
+        drop(upstream);
+        if truncated {
+            tracing::warn!("Stream for conversation {} ended early, saving partial reply", id);
+        }
+
+        // As with `send_message`, the question is only kept along with an answer to it
+        if content.is_empty() {
+            tracing::warn!("Stream for conversation {} produced no reply, nothing saved", id);
+            if !truncated {
+                let _ = tx
+                    .send(Ok(Event::default().event("error").data("Model request failed")))
+                    .await;
+            }
+            return;
+        }
+
+        let mut message = ChatMessage::new("assistant", content);
+        message.truncated = truncated;
+        let completion = Completion {
+            message,
+            model: model.unwrap_or_else(|| state.llm.model().to_string()),
+            usage,
+        };
+
+        let saved = append_messages(&state.pool, id, &user_message, &completion).await;
+        if saved.is_ok() && !truncated {
+            let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
+        }
+    });
+
+    Ok(Sse::new(ReceiverStream::new(rx)).keep_alive(KeepAlive::default()))
+}
+
+// Other users' conversations are reported as missing; admins can reach every conversation.
+async fn find_conversation(
+    state: &AppState,
+    id: i64,
+    user: &AuthUser,
+) -> Result<DbConversation, ConversationError> {
+    let owner = if user.is_admin() { None } else { Some(user.id) };
+    state
+        .repositories
+        .conversations
+        .find(id, owner)
+        .await
+        .map_err(|e| {
+            tracing::error!("Database error when querying conversation {}: {}", id, e);
+            ConversationError::DatabaseError
+        })?
+        .ok_or(ConversationError::NotFound)
+}
+
+// Loads the messages of a conversation the caller is allowed to reach.
+async fn load_history(
+    state: &AppState,
+    id: i64,
+    user: &AuthUser,
+) -> Result<Vec<ChatMessage>, ConversationError> {
+    // Without its file the history would look empty, and replies would be saved on top of it
+    if find_conversation(state, id, user).await?.import_pending {
+        tracing::warn!("Conversation {} is still waiting for its file to be imported", id);
+        return Err(ConversationError::CorruptFile);
+    }
+    state.repositories.conversations.messages(id).await.map_err(|e| {
+        tracing::error!("Database error when loading messages of conversation {}: {}", id, e);
+        ConversationError::DatabaseError
+    })
+}
+
+// Appends a user message and the model's reply to it, then bumps `updatetime`.
+async fn append_messages(
+    pool: &AnyPool,
+    id: i64,
+    user_message: &ChatMessage,
+    reply: &Completion,
+) -> Result<(), ConversationError> {
+    let map_err = |e: sqlx::Error| {
+        tracing::error!("Database error when saving messages of conversation {}: {}", id, e);
+        ConversationError::DatabaseError
+    };
+
+    let mut tx = pool.begin().await.map_err(map_err)?;
+
+    let seq: i64 = sqlx::query_scalar(
+        "SELECT COALESCE(MAX(seq), 0) + 1 FROM messages WHERE conversation_id = $1"
+    )
+    .bind(id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(map_err)?;
+
+    insert_message(&mut tx, id, seq, user_message, None).await.map_err(map_err)?;
+    insert_message(&mut tx, id, seq + 1, &reply.message, Some(reply))
+        .await
+        .map_err(map_err)?;
+
+    let updatetime = Utc::now().format(UPDATETIME_FORMAT).to_string();
+    sqlx::query("UPDATE conversation SET updatetime = $1 WHERE id = $2")
+        .bind(updatetime)
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(map_err)?;
+
+    tx.commit().await.map_err(map_err)
+}
+
+async fn insert_message(
+    tx: &mut sqlx::Transaction<'_, sqlx::Any>,
+    id: i64,
+    seq: i64,
+    message: &ChatMessage,
+    completion: Option<&Completion>,
+) -> Result<(), sqlx::Error> {
+    let usage = completion.and_then(|c| c.usage);
+    sqlx::query(
+        "INSERT INTO messages (conversation_id, seq, role, content, truncated, model, prompt_tokens, completion_tokens) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"
+    )
+    .bind(id)
+    .bind(seq)
+    .bind(&message.role)
+    .bind(&message.content)
+    .bind(message.truncated)
+    .bind(completion.map(|c| c.model.as_str()))
+    .bind(usage.map(|u| u.prompt_tokens))
+    .bind(usage.map(|u| u.completion_tokens))
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Removes the original file of a deleted conversation, if it was imported from one.
+///
+/// Runs once the deletion is committed, so a file that can't be removed is only logged and left
+/// behind. A file that was never imported holds the only copy of its messages and is kept.
+pub(crate) fn remove_legacy_file(dir: &FsPath, filepath: &str, import_pending: bool) {
+    if filepath.is_empty() {
+        return;
+    }
+
+    let path = dir.join(filepath);
+    if import_pending {
+        tracing::warn!("Keeping {}, which was never imported", path.display());
+        return;
+    }
+    match fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => tracing::error!("Failed to remove file {}: {}", path.display(), e),
+    }
+}
+
+/// Imports conversations that still live in JSON files under `dir` into the `messages` table.
+///
+/// Only conversations marked `import_pending` are read, so this is safe to run on every start.
+/// Unreadable files are logged and stay pending for the next run; until then their
+/// conversations can't be read or added to. Returns the number of imported files.
+pub async fn import_legacy_files(pool: &AnyPool, dir: &FsPath) -> Result<usize, sqlx::Error> {
+    let pending: Vec<(i64, String)> =
+        sqlx::query_as("SELECT id, filepath FROM conversation WHERE import_pending = TRUE ORDER BY id")
+            .fetch_all(pool)
+            .await?;
+
+    let mut imported = 0;
+    for (id, filepath) in pending {
+        let path = dir.join(&filepath);
+        let messages: Vec<ChatMessage> = match fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string()))
+        {
+            Ok(messages) => messages,
+            Err(e) => {
+                tracing::warn!("Skipping import of conversation {} from {}: {}", id, path.display(), e);
+                continue;
+            }
+        };
+
+        let mut tx = pool.begin().await?;
+        for (seq, message) in (1..).zip(&messages) {
+            insert_message(&mut tx, id, seq, message, None).await?;
+        }
+        sqlx::query("UPDATE conversation SET import_pending = FALSE WHERE id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        tracing::info!("Imported {} messages of conversation {} from {}", messages.len(), id, path.display());
+        imported += 1;
+    }
+
+    Ok(imported)
+}
+
+/// Every conversation of `user_id`, or of every user if `None`, with its messages.
+pub async fn export_conversations(
+    pool: &AnyPool,
+    user_id: Option<i64>,
+) -> Result<Vec<ExportedConversation>, sqlx::Error> {
+    let conversations: Vec<(i64, Option<String>, String, String)> = sqlx::query_as(
+        "SELECT c.id, u.email, c.title, c.updatetime FROM conversation c LEFT JOIN users u ON u.id = c.userid
+         WHERE $1 IS NULL OR c.userid = $1 ORDER BY c.id"
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    let mut exported = Vec::with_capacity(conversations.len());
+    for (id, owner, title, updatetime) in conversations {
+        let messages = sqlx::query_as::<_, DbMessage>(
+            "SELECT content, role, truncated FROM messages WHERE conversation_id = $1 ORDER BY seq"
+        )
+        .bind(id)
+        .fetch_all(pool)
+        .await?;
+
+        exported.push(ExportedConversation {
+            owner,
+            title,
+            updatetime,
+            messages: messages.into_iter().map(ChatMessage::from).collect(),
+        });
+    }
+
+    Ok(exported)
+}
+
+/// Adds each conversation as a new one owned by the paired user id, all or nothing.
+pub async fn import_conversations(
+    pool: &AnyPool,
+    conversations: &[(i64, ExportedConversation)],
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    for (user_id, conversation) in conversations {
+        let id: i64 = sqlx::query_scalar(
+            "INSERT INTO conversation (title, updatetime, filepath, userid) VALUES ($1, $2, '', $3) RETURNING id",
+        )
+        .bind(&conversation.title)
+        .bind(&conversation.updatetime)
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        for (seq, message) in (1..).zip(&conversation.messages) {
+            insert_message(&mut tx, id, seq, message, None).await?;
+        }
+    }
+
+    tx.commit().await
+}
+
+fn validate_title(title: String) -> Result<String, ConversationError> {
+    let title = title.trim();
+    if title.is_empty() {
+        return Err(ConversationError::InvalidTitle);
+    }
+    Ok(title.to_string())
 }
@@ -1,7 +1,16 @@
-use serde::Serialize;
+use axum::{
+    Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use chrono::{DateTime, Utc};
 use sqlx::FromRow;
 
+use crate::db::Flag;
+use crate::llm::types::ChatMessage;
+
 #[derive(Serialize)]
 pub struct Conversation {
     pub id: i64,
@@ -10,10 +19,92 @@ pub struct Conversation {
     pub filepath: String,
 }
 
-#[derive(FromRow)]
+#[derive(Debug, Clone, FromRow)]
 pub struct DbConversation {
     pub id: i64,
     pub title: String,
     pub updatetime: String,
     pub filepath: String,
+    /// Its legacy file still has to be imported, so its messages are incomplete.
+    #[sqlx(try_from = "Flag")]
+    pub import_pending: bool,
+}
+
+#[derive(FromRow)]
+pub struct DbMessage {
+    pub content: String,
+    pub role: String,
+    #[sqlx(try_from = "Flag")]
+    pub truncated: bool,
+}
+
+impl From<DbMessage> for ChatMessage {
+    fn from(message: DbMessage) -> Self {
+        ChatMessage {
+            content: message.content,
+            role: message.role,
+            truncated: message.truncated,
+        }
+    }
+}
+
+/// A conversation as written by `restchat conversation export` and read back by `import`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedConversation {
+    /// Email of the owner; `import` falls back to it when no user is given.
+    pub owner: Option<String>,
+    pub title: String,
+    /// `YYYY-MM-DD HH:MM:SS`, UTC.
+    pub updatetime: String,
+    pub messages: Vec<ChatMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListConversationsQuery {
+    /// Admins only: include every user's conversations.
+    #[serde(default)]
+    pub all: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SendMessageRequest {
+    pub content: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateConversationRequest {
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateConversationRequest {
+    pub title: String,
+}
+
+#[derive(Debug)]
+pub enum ConversationError {
+    NotFound,
+    InvalidTitle,
+    CorruptFile,
+    DatabaseError,
+    UpstreamError,
+}
+
+impl IntoResponse for ConversationError {
+    fn into_response(self) -> Response {
+        let (status, code, error_message) = match self {
+            ConversationError::NotFound => (StatusCode::NOT_FOUND, "not_found", "Conversation not found"),
+            ConversationError::InvalidTitle => (StatusCode::BAD_REQUEST, "invalid_title", "Title must not be empty"),
+            ConversationError::CorruptFile => (StatusCode::UNPROCESSABLE_ENTITY, "corrupt_conversation", "Conversation file could not be imported"),
+            ConversationError::DatabaseError => (StatusCode::INTERNAL_SERVER_ERROR, "database_error", "Database error"),
+            ConversationError::UpstreamError => (StatusCode::BAD_GATEWAY, "upstream_error", "Model request failed"),
+        };
+
+        let body = Json(json!({
+            "error": error_message,
+            "code": code,
+        }));
+
+        (status, body).into_response()
+    }
 }
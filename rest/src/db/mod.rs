@@ -0,0 +1,217 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use sqlx::AnyPool;
+use sqlx::any::{Any, AnyConnectOptions, AnyPoolOptions, AnyTypeInfo, AnyTypeInfoKind, AnyValueRef};
+use sqlx::decode::Decode;
+use sqlx::error::BoxDynError;
+use sqlx::migrate::{MigrateError, Migrator};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous};
+use sqlx::{Executor, Type, ValueRef};
+use tokio::task::JoinHandle;
+
+use crate::config::types::{DatabaseSection, JournalMode, Synchronous};
+
+/// SQLite schema migrations, embedded at compile time.
+pub static SQLITE_MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+/// PostgreSQL schema migrations, embedded at compile time.
+pub static POSTGRES_MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+/// The database server behind a pool, picked by the scheme of the database URL.
+///
+/// Queries are written once for both: `$1`-style placeholders, `TRUE`/`FALSE` for flags and
+/// `CAST(... AS TEXT)` when reading timestamps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Sqlite,
+    Postgres,
+}
+
+impl Backend {
+    pub fn from_url(url: &str) -> Result<Backend, String> {
+        match url.split_once(':').map(|(scheme, _)| scheme) {
+            Some("sqlite") => Ok(Backend::Sqlite),
+            Some("postgres" | "postgresql") => Ok(Backend::Postgres),
+            _ => Err(format!("unsupported database URL {:?}, expected sqlite: or postgres://", url)),
+        }
+    }
+
+    /// The backend a pool's connections talk to.
+    pub fn of(pool: &AnyPool) -> Backend {
+        Backend::from_url(pool.connect_options().database_url.as_str()).expect("Pools are opened from checked URLs")
+    }
+
+    fn migrator(self) -> &'static Migrator {
+        match self {
+            Backend::Sqlite => &SQLITE_MIGRATOR,
+            Backend::Postgres => &POSTGRES_MIGRATOR,
+        }
+    }
+}
+
+/// Brings the database schema up to date.
+pub async fn migrate(pool: &AnyPool) -> Result<(), MigrateError> {
+    Backend::of(pool).migrator().run(pool).await
+}
+
+/// Opens a connection pool sized and tuned as configured, creating a SQLite database file if
+/// allowed.
+pub async fn connect(config: &DatabaseSection) -> Result<AnyPool, sqlx::Error> {
+    sqlx::any::install_default_drivers();
+    let backend = Backend::from_url(&config.url).map_err(|e| sqlx::Error::Configuration(e.into()))?;
+
+    let mut url = config.url.clone();
+    let mut params = Vec::new();
+    // Connection settings travel in the URL, the only thing the driver-neutral pool passes on
+    let session = match backend {
+        Backend::Sqlite => {
+            let options = sqlite_options(config)?;
+            if config.create_if_missing && !url.contains("mode=") {
+                params.push("mode=rwc".to_string());
+                // SQLite creates the file but not the directory it goes in
+                if let Some(dir) = options.get_filename().parent()
+                    && !dir.as_os_str().is_empty()
+                {
+                    std::fs::create_dir_all(dir)?;
+                }
+            }
+            sqlite_pragmas(config)
+        }
+        Backend::Postgres => {
+            params.push(format!("statement-cache-capacity={}", config.statement_cache_size));
+            // Timestamps are written and read back as UTC, as SQLite does
+            "SET TIME ZONE 'UTC'".to_string()
+        }
+    };
+    if !params.is_empty() {
+        url.push(if url.contains('?') { '&' } else { '?' });
+        url.push_str(&params.join("&"));
+    }
+
+    AnyPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .after_connect(move |conn, _| {
+            let session = session.clone();
+            Box::pin(async move {
+                conn.execute(session.as_str()).await?;
+                Ok(())
+            })
+        })
+        .connect_with(AnyConnectOptions::from_str(&url)?)
+        .await
+}
+
+/// The SQLite connection options described by the database URL and settings.
+pub fn sqlite_options(config: &DatabaseSection) -> Result<SqliteConnectOptions, sqlx::Error> {
+    let journal_mode = match config.journal_mode {
+        JournalMode::Delete => SqliteJournalMode::Delete,
+        JournalMode::Truncate => SqliteJournalMode::Truncate,
+        JournalMode::Persist => SqliteJournalMode::Persist,
+        JournalMode::Memory => SqliteJournalMode::Memory,
+        JournalMode::Wal => SqliteJournalMode::Wal,
+    };
+    let synchronous = match config.synchronous {
+        Synchronous::Off => SqliteSynchronous::Off,
+        Synchronous::Normal => SqliteSynchronous::Normal,
+        Synchronous::Full => SqliteSynchronous::Full,
+        Synchronous::Extra => SqliteSynchronous::Extra,
+    };
+
+    Ok(SqliteConnectOptions::from_str(&config.url)?
+        .create_if_missing(config.create_if_missing)
+        .journal_mode(journal_mode)
+        .synchronous(synchronous)
+        .busy_timeout(Duration::from_millis(config.busy_timeout))
+        .foreign_keys(config.foreign_keys)
+        .statement_cache_capacity(config.statement_cache_size))
+}
+
+/// The per-connection settings from `config`, run as each pooled SQLite connection opens.
+fn sqlite_pragmas(config: &DatabaseSection) -> String {
+    let journal_mode = match config.journal_mode {
+        JournalMode::Delete => "DELETE",
+        JournalMode::Truncate => "TRUNCATE",
+        JournalMode::Persist => "PERSIST",
+        JournalMode::Memory => "MEMORY",
+        JournalMode::Wal => "WAL",
+    };
+    let synchronous = match config.synchronous {
+        Synchronous::Off => "OFF",
+        Synchronous::Normal => "NORMAL",
+        Synchronous::Full => "FULL",
+        Synchronous::Extra => "EXTRA",
+    };
+    let foreign_keys = if config.foreign_keys { "ON" } else { "OFF" };
+
+    format!(
+        "PRAGMA busy_timeout = {}; PRAGMA journal_mode = {}; PRAGMA synchronous = {}; PRAGMA foreign_keys = {};",
+        config.busy_timeout, journal_mode, synchronous, foreign_keys
+    )
+}
+
+/// Refreshes query planner statistics and folds the WAL back into the database file.
+///
+/// The checkpoint keeps the WAL from growing without bound while readers are always active.
+/// Postgres looks after itself through autovacuum, so this does nothing there.
+pub async fn maintain(pool: &AnyPool) -> Result<(), sqlx::Error> {
+    if Backend::of(pool) != Backend::Sqlite {
+        return Ok(());
+    }
+
+    sqlx::query("PRAGMA optimize").execute(pool).await?;
+    let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode").fetch_one(pool).await?;
+    if journal_mode.eq_ignore_ascii_case("wal") {
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)").execute(pool).await?;
+    }
+    Ok(())
+}
+
+/// Runs `maintain` every `interval` seconds until the returned task is aborted.
+pub fn spawn_maintenance(pool: AnyPool, interval: u64) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+        // The first tick completes immediately; there is nothing to tidy up at startup
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            match maintain(&pool).await {
+                Ok(()) => tracing::debug!("Database maintenance done"),
+                Err(e) => tracing::warn!("Database maintenance failed: {}", e),
+            }
+        }
+    })
+}
+
+/// A flag column: SQLite stores flags as 0 or 1, Postgres as `BOOLEAN`.
+///
+/// Converts into `bool`, for `#[sqlx(try_from = "Flag")]` on `FromRow` fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flag(pub bool);
+
+impl From<Flag> for bool {
+    fn from(flag: Flag) -> bool {
+        flag.0
+    }
+}
+
+impl Type<Any> for Flag {
+    fn type_info() -> AnyTypeInfo {
+        <bool as Type<Any>>::type_info()
+    }
+
+    fn compatible(ty: &AnyTypeInfo) -> bool {
+        ty.kind() == AnyTypeInfoKind::Bool || ty.kind().is_integer()
+    }
+}
+
+impl<'r> Decode<'r, Any> for Flag {
+    fn decode(value: AnyValueRef<'r>) -> Result<Self, BoxDynError> {
+        if value.type_info().kind() == AnyTypeInfoKind::Bool {
+            Ok(Flag(<bool as Decode<Any>>::decode(value)?))
+        } else {
+            Ok(Flag(<i64 as Decode<Any>>::decode(value)? != 0))
+        }
+    }
+}
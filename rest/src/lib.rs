@@ -0,0 +1,117 @@
+use axum::{
+    Extension, Router,
+    http::{HeaderName, Method, header},
+    routing::{get, patch, post},
+};
+
+use std::sync::Arc;
+use tower_http::cors::{Any, CorsLayer};
+
+pub mod auth;
+pub use auth::types::{
+    AppState, AuthConfig, CookieConfig, CorsOrigins, JwtConfig, OidcConfig, RegistrationMode, SameSite,
+};
+use auth::{
+    change_password, create_invite, forgot_password, get_current_user, guard::require_role, jwks,
+    login, logout, logout_all, oidc::{oidc_callback, oidc_login}, refresh_token, register,
+    reset_password, session,
+};
+
+mod admin;
+use admin::{
+    delete_user, disable_user, enable_user, force_reset_password, list_audit, list_users, set_role,
+    unlock_user,
+};
+
+pub mod audit;
+
+pub mod cli;
+
+pub mod config;
+
+mod api_keys;
+use api_keys::{create_api_key, list_api_keys, rename_api_key, revoke_api_key, types::ApiKeyResource};
+
+pub mod conversation;
+use conversation::{
+    create_conversation, delete_conversation, get_conversation_content, get_conversations,
+    send_message, stream_message, update_conversation,
+};
+
+pub mod db;
+
+pub mod llm;
+
+pub mod mail;
+
+pub mod repository;
+
+#[cfg(test)]
+mod tests;
+
+/// The whole API with every route, guard and the CORS layer, as the server runs it.
+///
+/// Serve it with `into_make_service_with_connect_info::<SocketAddr>()`; client addresses feed the
+/// per-IP login throttle. It can also be nested under a prefix in another application; set
+/// `CookieConfig::path_prefix` to match so the refresh cookie reaches the `/auth` routes.
+pub fn build_router(state: Arc<AppState>) -> Router {
+    // Browsers only send cookies cross-origin to servers that name the origin and allow credentials
+    let cors = CorsLayer::new().allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE]);
+    let cors = match &state.cors_origins {
+        CorsOrigins::Any => cors.allow_origin(Any).allow_headers(Any),
+        CorsOrigins::List(origins) => cors
+            .allow_origin(origins.clone())
+            .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE, HeaderName::from_static(session::CSRF_HEADER)])
+            .allow_credentials(true),
+    };
+
+    // 构建路由
+    let conversation_routes = Router::new()
+        .route("/conversations", get(get_conversations).post(create_conversation))
+        .route(
+            "/conversations/{id}",
+            get(get_conversation_content)
+                .patch(update_conversation)
+                .delete(delete_conversation),
+        )
+        .route("/conversations/{id}/messages", post(send_message))
+        .route("/conversations/{id}/messages/stream", post(stream_message))
+        .route_layer(require_role(&state, "user"))
+        .route_layer(Extension(ApiKeyResource("conversations")));
+
+    let api_key_routes = Router::new()
+        .route("/api-keys", get(list_api_keys).post(create_api_key))
+        .route("/api-keys/{id}", patch(rename_api_key).delete(revoke_api_key))
+        .route_layer(require_role(&state, "user"));
+
+    let admin_routes = Router::new()
+        .route("/admin/invites", post(create_invite))
+        .route("/admin/users", get(list_users))
+        .route("/admin/users/{id}", patch(set_role).delete(delete_user))
+        .route("/admin/users/{id}/disable", post(disable_user))
+        .route("/admin/users/{id}/enable", post(enable_user))
+        .route("/admin/users/{id}/reset-password", post(force_reset_password))
+        .route("/admin/users/{id}/unlock", post(unlock_user))
+        .route("/admin/audit", get(list_audit))
+        .route_layer(require_role(&state, "admin"));
+
+    Router::new()
+        .route("/", get(|| async { "Hello, Axum!" }))
+        .merge(conversation_routes)
+        .merge(admin_routes)
+        .merge(api_key_routes)
+        .route("/auth/login", post(login))
+        .route("/auth/register", post(register))
+        .route("/auth/change-password", post(change_password))
+        .route("/auth/forgot-password", post(forgot_password))
+        .route("/auth/reset-password", post(reset_password))
+        .route("/auth/refresh", post(refresh_token))
+        .route("/auth/logout", post(logout))
+        .route("/auth/logout-all", post(logout_all))
+        .route("/auth/me", get(get_current_user))
+        .route("/auth/oidc/login", get(oidc_login))
+        .route("/auth/oidc/callback", get(oidc_callback))
+        .route("/.well-known/jwks.json", get(jwks))
+        .with_state(state)
+        .layer(cors)
+}
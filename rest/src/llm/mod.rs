@@ -0,0 +1,144 @@
+pub mod types;
+
+use types::{
+    ChatCompletionChunk, ChatCompletionRequest, ChatCompletionResponse, ChatMessage,
+    ChatStreamEvent, Completion, LlmConfig, LlmError, StreamOptions, UpstreamMessage,
+};
+
+/// Client for an OpenAI/DeepSeek-compatible `/v1/chat/completions` endpoint.
+#[derive(Debug, Clone)]
+pub struct LlmClient {
+    http: reqwest::Client,
+    config: LlmConfig,
+}
+
+impl LlmClient {
+    pub fn new(config: LlmConfig) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            config,
+        }
+    }
+
+    pub fn model(&self) -> &str {
+        &self.config.model
+    }
+
+    fn completions_url(&self) -> String {
+        format!(
+            "{}/v1/chat/completions",
+            self.config.base_url.trim_end_matches('/')
+        )
+    }
+
+    async fn send(&self, messages: &[ChatMessage], stream: bool) -> Result<reqwest::Response, LlmError> {
+        let body = ChatCompletionRequest {
+            model: &self.config.model,
+            messages: messages
+                .iter()
+                .map(|m| UpstreamMessage {
+                    role: &m.role,
+                    content: &m.content,
+                })
+                .collect(),
+            stream,
+            // Ask for a final usage chunk so streamed replies get token counts too
+            stream_options: stream.then_some(StreamOptions { include_usage: true }),
+        };
+
+        let mut request = self.http.post(self.completions_url()).json(&body);
+        if let Some(api_key) = &self.config.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        let response = request.send().await.map_err(LlmError::Request)?;
+        if !response.status().is_success() {
+            return Err(LlmError::Status(response.status()));
+        }
+
+        Ok(response)
+    }
+
+    pub async fn chat_completion(&self, messages: &[ChatMessage]) -> Result<Completion, LlmError> {
+        let completion: ChatCompletionResponse = self
+            .send(messages, false)
+            .await?
+            .json()
+            .await
+            .map_err(LlmError::Request)?;
+
+        let message = completion
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.message)
+            .ok_or(LlmError::EmptyResponse)?;
+
+        Ok(Completion {
+            message,
+            model: completion.model.unwrap_or_else(|| self.config.model.clone()),
+            usage: completion.usage,
+        })
+    }
+
+    /// Starts a streaming completion. Dropping the returned stream aborts the upstream request.
+    pub async fn chat_completion_stream(&self, messages: &[ChatMessage]) -> Result<ChatStream, LlmError> {
+        let response = self.send(messages, true).await?;
+        Ok(ChatStream {
+            response,
+            buffer: Vec::new(),
+        })
+    }
+}
+
+/// Reads `data:` lines from an upstream `text/event-stream` response.
+pub struct ChatStream {
+    response: reqwest::Response,
+    buffer: Vec<u8>,
+}
+
+impl ChatStream {
+    /// Returns the next event, or `None` once the upstream closes the body.
+    pub async fn next_event(&mut self) -> Result<Option<ChatStreamEvent>, LlmError> {
+        loop {
+            // Lines are split on raw bytes so multi-byte characters spanning chunks stay intact.
+            while let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+                let line = String::from_utf8_lossy(&line);
+                let Some(data) = line.trim_end().strip_prefix("data:") else {
+                    continue;
+                };
+
+                let data = data.trim_start();
+                if data == "[DONE]" {
+                    return Ok(Some(ChatStreamEvent::Done));
+                }
+
+                let (delta, model, usage) = match serde_json::from_str::<ChatCompletionChunk>(data) {
+                    Ok(chunk) => (
+                        chunk
+                            .choices
+                            .into_iter()
+                            .next()
+                            .and_then(|choice| choice.delta.content),
+                        chunk.model,
+                        chunk.usage,
+                    ),
+                    Err(_) => (None, None, None),
+                };
+
+                return Ok(Some(ChatStreamEvent::Chunk {
+                    payload: data.to_string(),
+                    delta,
+                    model,
+                    usage,
+                }));
+            }
+
+            match self.response.chunk().await.map_err(LlmError::Request)? {
+                Some(bytes) => self.buffer.extend_from_slice(&bytes),
+                None => return Ok(None),
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone)]
+pub struct LlmConfig {
+    pub base_url: String,
+    pub model: String,
+    pub api_key: Option<String>,
+}
+
+/// A single chat turn, stored in conversation files as `{content, role}`.
+///
+/// `truncated` is only written for assistant replies whose stream was cut short.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ChatMessage {
+    pub content: String,
+    pub role: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+impl ChatMessage {
+    pub fn new(role: &str, content: String) -> Self {
+        Self {
+            content,
+            role: role.to_string(),
+            truncated: false,
+        }
+    }
+}
+
+/// The `{role, content}` pair sent upstream, without any of our local annotations.
+#[derive(Debug, Serialize)]
+pub struct UpstreamMessage<'a> {
+    pub role: &'a str,
+    pub content: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChatCompletionRequest<'a> {
+    pub model: &'a str,
+    pub messages: Vec<UpstreamMessage<'a>>,
+    pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StreamOptions {
+    pub include_usage: bool,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+}
+
+/// A finished assistant reply together with what the upstream reported about it.
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub message: ChatMessage,
+    pub model: String,
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionResponse {
+    pub model: Option<String>,
+    pub choices: Vec<ChatCompletionChoice>,
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionChoice {
+    pub message: ChatMessage,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionChunk {
+    pub model: Option<String>,
+    #[serde(default)]
+    pub choices: Vec<ChatCompletionChunkChoice>,
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionChunkChoice {
+    pub delta: ChatCompletionDelta,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionDelta {
+    pub content: Option<String>,
+}
+
+/// One `data:` line from a streaming completion.
+#[derive(Debug)]
+pub enum ChatStreamEvent {
+    /// A raw chunk payload, plus the text it adds to the reply and any metadata it carries.
+    Chunk {
+        payload: String,
+        delta: Option<String>,
+        model: Option<String>,
+        usage: Option<Usage>,
+    },
+    /// The upstream sent `data: [DONE]`.
+    Done,
+}
+
+#[derive(Debug)]
+pub enum LlmError {
+    Request(reqwest::Error),
+    Status(reqwest::StatusCode),
+    EmptyResponse,
+}
+
+impl std::fmt::Display for LlmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LlmError::Request(e) => write!(f, "upstream request failed: {}", e),
+            LlmError::Status(status) => write!(f, "upstream returned {}", status),
+            LlmError::EmptyResponse => write!(f, "upstream returned no choices"),
+        }
+    }
+}
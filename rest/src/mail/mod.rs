@@ -0,0 +1,117 @@
+pub mod types;
+
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+    message::{Mailbox, header::ContentType},
+    transport::smtp::authentication::Credentials,
+};
+use types::{Email, MailConfig, MailError};
+
+pub type MailFuture<'a> = Pin<Box<dyn Future<Output = Result<(), MailError>> + Send + 'a>>;
+
+/// Delivers outgoing mail such as password reset links.
+pub trait Mailer: std::fmt::Debug + Send + Sync {
+    fn send(&self, email: Email) -> MailFuture<'_>;
+}
+
+/// Builds the mailer selected by `config`.
+pub fn from_config(config: MailConfig) -> Result<Arc<dyn Mailer>, MailError> {
+    Ok(match config {
+        MailConfig::Smtp {
+            host,
+            port,
+            username,
+            password,
+            from,
+        } => {
+            let mut transport =
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host).map_err(MailError::Smtp)?;
+            if let Some(port) = port {
+                transport = transport.port(port);
+            }
+            if let Some(username) = username {
+                transport = transport.credentials(Credentials::new(username, password.unwrap_or_default()));
+            }
+
+            Arc::new(SmtpMailer {
+                transport: transport.build(),
+                from: from.parse().map_err(|_| MailError::Address(from))?,
+            })
+        }
+        MailConfig::File { dir } => Arc::new(FileMailer { dir }),
+        MailConfig::Log => Arc::new(LogMailer),
+    })
+}
+
+#[derive(Debug)]
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: Email) -> MailFuture<'_> {
+        Box::pin(async move {
+            let to: Mailbox = email.to.parse().map_err(|_| MailError::Address(email.to.clone()))?;
+            let message = Message::builder()
+                .from(self.from.clone())
+                .to(to)
+                .subject(email.subject)
+                .header(ContentType::TEXT_PLAIN)
+                .body(email.body)
+                .map_err(MailError::Build)?;
+
+            self.transport.send(message).await.map_err(MailError::Smtp)?;
+            Ok(())
+        })
+    }
+}
+
+/// Writes messages to disk instead of sending them, for local development and tests.
+#[derive(Debug)]
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: Email) -> MailFuture<'_> {
+        Box::pin(async move {
+            tokio::fs::create_dir_all(&self.dir).await.map_err(MailError::Io)?;
+
+            let path = self.dir.join(format!(
+                "{}-{}.eml",
+                chrono::Utc::now().format("%Y%m%d%H%M%S"),
+                uuid::Uuid::new_v4().simple()
+            ));
+            let contents = format!("To: {}\nSubject: {}\n\n{}\n", email.to, email.subject, email.body);
+            tokio::fs::write(&path, contents).await.map_err(MailError::Io)?;
+
+            tracing::info!("Mail to {} written to {}", email.to, path.display());
+            Ok(())
+        })
+    }
+}
+
+/// Logs messages and drops them.
+#[derive(Debug)]
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn send(&self, email: Email) -> MailFuture<'_> {
+        Box::pin(async move {
+            tracing::info!("Mail to {}: {}\n{}", email.to, email.subject, email.body);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,43 @@
+use std::path::PathBuf;
+
+/// A plain-text message to a single recipient.
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum MailConfig {
+    Smtp {
+        host: String,
+        port: Option<u16>,
+        username: Option<String>,
+        password: Option<String>,
+        from: String,
+    },
+    /// Writes each message to `dir` as an `.eml` file.
+    File { dir: PathBuf },
+    /// Only logs messages; nothing is delivered.
+    Log,
+}
+
+#[derive(Debug)]
+pub enum MailError {
+    Address(String),
+    Build(lettre::error::Error),
+    Smtp(lettre::transport::smtp::Error),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for MailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MailError::Address(address) => write!(f, "invalid mail address {:?}", address),
+            MailError::Build(e) => write!(f, "failed to build message: {}", e),
+            MailError::Smtp(e) => write!(f, "SMTP delivery failed: {}", e),
+            MailError::Io(e) => write!(f, "failed to write message: {}", e),
+        }
+    }
+}
@@ -1,71 +1,106 @@
-use axum::{
-    Router,
-    http::Method,
-    routing::{get, post},
-};
-
-use sqlx::sqlite::SqlitePool;
+use clap::Parser;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tower_http::cors::{Any, CorsLayer};
-
-pub mod auth;
-pub use auth::types::{AppState, JwtConfig};
-use auth::{get_current_user, login, refresh_token};
+use tracing_subscriber::EnvFilter;
 
-mod conversation;
-use conversation::{get_conversations, get_conversation_content};
-
-#[cfg(test)]
-mod tests;
+use restchat::cli::{self, types::{Cli, Command}};
+use restchat::config::types::Config;
+use restchat::{AppState, auth::oidc::OidcClient, build_router, conversation, db, llm::LlmClient, mail, repository::Repositories};
 
 #[tokio::main]
 async fn main() {
-    // Initialize logging
+    // A missing .env is fine; settings can come from the environment or a config file instead
+    dotenv::dotenv().ok();
+    let cli = Cli::parse();
+    let config = Config::load(&cli.config).unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(2);
+    });
+    let command = match cli.command {
+        Some(command) => command,
+        None if cli.migrate_only => Command::Migrate,
+        None => Command::Serve,
+    };
+
+    // Initialize logging; stdout is left to command output such as `conversation export`
+    let log_level = match (&command, &cli.config.log_level) {
+        (Command::Serve, _) => config.server.log_level.as_str(),
+        (_, Some(level)) => level.as_str(),
+        (_, None) => "warn",
+    };
     tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::DEBUG)
+        .with_env_filter(EnvFilter::new(log_level))
+        .with_writer(std::io::stderr)
         .init();
 
-    dotenv::dotenv().expect("Failed to load .env file");
+    if let Command::Serve = command {
+        serve(config).await;
+    } else if let Err(e) = cli::run(command, &config).await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
 
-    // Initialize database pool
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set in .env");
-    let pool = SqlitePool::connect(&database_url)
-        .await
-        .expect("Failed to connect to database");
+async fn serve(config: Config) {
+    if let Err(e) = config.validate() {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(2);
+    }
 
-    // Initialize JWT config
-    let jwt_config = JwtConfig {
-        secret: std::env::var("JWT_SECRET").expect("JWT_SECRET must be set in .env"),
-        access_expiry: std::env::var("JWT_ACCESS_EXPIRY")
-            .expect("JWT_ACCESS_EXPIRY must be set in .env")
-            .parse()
-            .expect("JWT_ACCESS_EXPIRY must be a number"),
-        refresh_expiry: std::env::var("JWT_REFRESH_EXPIRY")
-            .expect("JWT_REFRESH_EXPIRY must be set in .env")
-            .parse()
-            .expect("JWT_REFRESH_EXPIRY must be a number"),
-    };
+    // Open (or create) the database, apply pending schema migrations and seed the first admin
+    let pool = db::connect(&config.database).await.expect("Failed to connect to database");
+    db::migrate(&pool).await.expect("Failed to run database migrations");
+    if let Err(e) = cli::seed_admin(&config, &pool).await {
+        eprintln!("Failed to create the administrator: {}", e);
+        std::process::exit(1);
+    }
+    if config.database.maintenance_interval > 0 {
+        db::spawn_maintenance(pool.clone(), config.database.maintenance_interval);
+    }
+
+    // Initialize JWT signing and verification keys
+    let jwt_config = config.jwt_config().unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(2);
+    });
+    let cors_origins = config.cors_origins().unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(2);
+    });
 
-    let state = Arc::new(AppState { pool, jwt_config });
+    let mailer = mail::from_config(config.mail_config()).expect("Failed to set up mail transport");
 
-    // 构建路由
-    let app = Router::new()
-        .route("/", get(|| async { "Hello, Axum!" }))
-        .route("/conversations", get(get_conversations))
-        .route("/conversations/{id}", get(get_conversation_content))
-        .route("/auth/login", post(login))
-        .route("/auth/refresh", post(refresh_token))
-        .route("/auth/me", get(get_current_user))
-        .with_state(state)
-        .layer(
-            CorsLayer::new()
-                .allow_methods([Method::GET, Method::POST])
-                .allow_origin(Any)
-                .allow_headers(Any),
-        );
+    // Initialize single sign-on, enabled by setting a provider
+    let oidc = config.oidc_config().map(|oidc| Arc::new(OidcClient::new(oidc)));
+
+    // Move conversations still stored as JSON files into the database
+    let conversation_dir = config.storage.conversation_dir.clone();
+    let imported = conversation::import_legacy_files(&pool, &conversation_dir)
+        .await
+        .expect("Failed to import conversation files");
+    if imported > 0 {
+        tracing::info!("Imported {} conversation files", imported);
+    }
+
+    let state = Arc::new(AppState {
+        repositories: Repositories::sql(pool.clone()),
+        pool,
+        jwt_config,
+        auth_config: config.auth_config(),
+        mailer,
+        oidc,
+        llm: LlmClient::new(config.llm_config()),
+        conversation_dir,
+        cors_origins,
+    });
 
     // 启动服务器
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8000").await.unwrap();
-    println!("Server running on http://localhost:8000");
-    axum::serve(listener, app).await.unwrap();
+    let listener = tokio::net::TcpListener::bind(config.server.bind)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to bind {}: {}", config.server.bind, e));
+    tracing::info!("Server running on http://{}", config.server.bind);
+    // Client addresses feed the per-IP login throttle
+    axum::serve(listener, build_router(state).into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .unwrap();
 }
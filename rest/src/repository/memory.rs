@@ -0,0 +1,143 @@
+use std::sync::Mutex;
+
+use super::{ConversationRepository, CredentialRepository, RepositoryFuture, UserRepository};
+use crate::auth::types::User;
+use crate::conversation::types::DbConversation;
+use crate::llm::types::ChatMessage;
+
+/// Repositories kept in memory, for exercising handlers without a database.
+///
+/// Ids are handed out from 1 in insertion order, as a fresh database would.
+#[derive(Debug, Default)]
+pub struct MemoryRepository {
+    users: Mutex<Vec<MemoryUser>>,
+    conversations: Mutex<Vec<MemoryConversation>>,
+}
+
+#[derive(Debug)]
+struct MemoryUser {
+    user: User,
+    disabled: bool,
+    password_hash: Option<String>,
+}
+
+#[derive(Debug)]
+struct MemoryConversation {
+    owner: i64,
+    conversation: DbConversation,
+    messages: Vec<ChatMessage>,
+}
+
+impl MemoryRepository {
+    pub fn new() -> MemoryRepository {
+        MemoryRepository::default()
+    }
+
+    /// Adds a user, with a password login if `password_hash` is given, and returns their id.
+    pub fn add_user(&self, email: &str, role: &str, password_hash: Option<&str>) -> i64 {
+        let mut users = self.users.lock().unwrap();
+        let id = users.len() as i64 + 1;
+        users.push(MemoryUser {
+            user: User {
+                id: Some(id),
+                email: email.to_string(),
+                role: role.to_string(),
+            },
+            disabled: false,
+            password_hash: password_hash.map(str::to_string),
+        });
+        id
+    }
+
+    pub fn set_disabled(&self, id: i64, disabled: bool) {
+        let mut users = self.users.lock().unwrap();
+        if let Some(user) = users.iter_mut().find(|u| u.user.id == Some(id)) {
+            user.disabled = disabled;
+        }
+    }
+
+    /// Adds a conversation owned by `owner` and returns its id.
+    pub fn add_conversation(&self, owner: i64, title: &str, updatetime: &str, messages: Vec<ChatMessage>) -> i64 {
+        let mut conversations = self.conversations.lock().unwrap();
+        let id = conversations.len() as i64 + 1;
+        conversations.push(MemoryConversation {
+            owner,
+            conversation: DbConversation {
+                id,
+                title: title.to_string(),
+                updatetime: updatetime.to_string(),
+                filepath: String::new(),
+                import_pending: false,
+            },
+            messages,
+        });
+        id
+    }
+
+    fn with_user<T>(&self, found: impl Fn(&MemoryUser) -> bool, map: impl FnOnce(&MemoryUser) -> T) -> Option<T> {
+        self.users.lock().unwrap().iter().find(|u| found(u)).map(map)
+    }
+}
+
+impl UserRepository for MemoryRepository {
+    fn find_by_id(&self, id: i64) -> RepositoryFuture<'_, Option<User>> {
+        let user = self.with_user(|u| u.user.id == Some(id), |u| u.user.clone());
+        Box::pin(async move { Ok(user) })
+    }
+
+    fn find_by_email<'a>(&'a self, email: &'a str) -> RepositoryFuture<'a, Option<User>> {
+        let user = self.with_user(|u| u.user.email.to_lowercase() == email.to_lowercase(), |u| u.user.clone());
+        Box::pin(async move { Ok(user) })
+    }
+
+    fn is_disabled(&self, id: i64) -> RepositoryFuture<'_, Option<bool>> {
+        let disabled = self.with_user(|u| u.user.id == Some(id), |u| u.disabled);
+        Box::pin(async move { Ok(disabled) })
+    }
+}
+
+impl CredentialRepository for MemoryRepository {
+    fn password_hash(&self, user_id: i64) -> RepositoryFuture<'_, Option<String>> {
+        let hash = self.with_user(|u| u.user.id == Some(user_id), |u| u.password_hash.clone()).flatten();
+        Box::pin(async move { Ok(hash) })
+    }
+}
+
+impl ConversationRepository for MemoryRepository {
+    fn list(&self, owner: Option<i64>) -> RepositoryFuture<'_, Vec<DbConversation>> {
+        let mut list: Vec<DbConversation> = self
+            .conversations
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| owner.is_none_or(|owner| c.owner == owner))
+            .map(|c| c.conversation.clone())
+            .collect();
+        // `updatetime` is `YYYY-MM-DD HH:MM:SS`, so text order is time order
+        list.sort_by(|a, b| b.updatetime.cmp(&a.updatetime));
+        Box::pin(async move { Ok(list) })
+    }
+
+    fn find(&self, id: i64, owner: Option<i64>) -> RepositoryFuture<'_, Option<DbConversation>> {
+        let conversation = self
+            .conversations
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|c| c.conversation.id == id && owner.is_none_or(|owner| c.owner == owner))
+            .map(|c| c.conversation.clone());
+        Box::pin(async move { Ok(conversation) })
+    }
+
+    fn messages(&self, conversation_id: i64) -> RepositoryFuture<'_, Vec<ChatMessage>> {
+        let messages = self
+            .conversations
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|c| c.conversation.id == conversation_id)
+            .map(|c| c.messages.clone())
+            .unwrap_or_default();
+        Box::pin(async move { Ok(messages) })
+    }
+}
@@ -0,0 +1,72 @@
+pub mod memory;
+pub mod sql;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use sqlx::AnyPool;
+
+use crate::auth::types::User;
+use crate::conversation::types::DbConversation;
+use crate::llm::types::ChatMessage;
+
+pub type RepositoryFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, sqlx::Error>> + Send + 'a>>;
+
+/// Looks up user accounts.
+pub trait UserRepository: std::fmt::Debug + Send + Sync {
+    fn find_by_id(&self, id: i64) -> RepositoryFuture<'_, Option<User>>;
+    /// The user with `email`, ignoring case.
+    fn find_by_email<'a>(&'a self, email: &'a str) -> RepositoryFuture<'a, Option<User>>;
+    /// Whether the user is disabled, or `None` if there is no such user.
+    fn is_disabled(&self, id: i64) -> RepositoryFuture<'_, Option<bool>>;
+}
+
+/// Looks up password logins.
+pub trait CredentialRepository: std::fmt::Debug + Send + Sync {
+    /// The bcrypt hash of the user's password; users who only sign in through single sign-on
+    /// have none.
+    fn password_hash(&self, user_id: i64) -> RepositoryFuture<'_, Option<String>>;
+}
+
+/// Reads conversations and their messages.
+///
+/// `owner` limits the conversations to those of one user; `None` reaches everyone's.
+pub trait ConversationRepository: std::fmt::Debug + Send + Sync {
+    /// Conversations, most recently updated first.
+    fn list(&self, owner: Option<i64>) -> RepositoryFuture<'_, Vec<DbConversation>>;
+    fn find(&self, id: i64, owner: Option<i64>) -> RepositoryFuture<'_, Option<DbConversation>>;
+    /// Messages of a conversation in the order they were sent.
+    fn messages(&self, conversation_id: i64) -> RepositoryFuture<'_, Vec<ChatMessage>>;
+}
+
+/// The repositories handlers read accounts and conversations through.
+///
+/// Every check of whether a user is disabled goes through `users`. Writes stay in the handlers,
+/// inside the transactions that also record their audit events.
+#[derive(Debug, Clone)]
+pub struct Repositories {
+    pub users: Arc<dyn UserRepository>,
+    pub credentials: Arc<dyn CredentialRepository>,
+    pub conversations: Arc<dyn ConversationRepository>,
+}
+
+impl Repositories {
+    /// Repositories backed by the database behind `pool`.
+    pub fn sql(pool: AnyPool) -> Repositories {
+        Repositories::from(Arc::new(sql::SqlRepository::new(pool)))
+    }
+}
+
+impl<R> From<Arc<R>> for Repositories
+where
+    R: UserRepository + CredentialRepository + ConversationRepository + 'static,
+{
+    fn from(repository: Arc<R>) -> Repositories {
+        Repositories {
+            users: repository.clone(),
+            credentials: repository.clone(),
+            conversations: repository,
+        }
+    }
+}
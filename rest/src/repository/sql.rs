@@ -0,0 +1,98 @@
+use sqlx::AnyPool;
+
+use super::{ConversationRepository, CredentialRepository, RepositoryFuture, UserRepository};
+use crate::auth::types::User;
+use crate::conversation::types::{DbConversation, DbMessage};
+use crate::db::Flag;
+use crate::llm::types::ChatMessage;
+
+/// Repositories over a SQLite or PostgreSQL pool.
+#[derive(Debug, Clone)]
+pub struct SqlRepository {
+    pool: AnyPool,
+}
+
+impl SqlRepository {
+    pub fn new(pool: AnyPool) -> SqlRepository {
+        SqlRepository { pool }
+    }
+}
+
+impl UserRepository for SqlRepository {
+    fn find_by_id(&self, id: i64) -> RepositoryFuture<'_, Option<User>> {
+        Box::pin(async move {
+            sqlx::query_as("SELECT id, email, role FROM users WHERE id = $1")
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await
+        })
+    }
+
+    fn find_by_email<'a>(&'a self, email: &'a str) -> RepositoryFuture<'a, Option<User>> {
+        Box::pin(async move {
+            sqlx::query_as("SELECT id, email, role FROM users WHERE lower(email) = lower($1)")
+                .bind(email)
+                .fetch_optional(&self.pool)
+                .await
+        })
+    }
+
+    fn is_disabled(&self, id: i64) -> RepositoryFuture<'_, Option<bool>> {
+        Box::pin(async move {
+            let disabled: Option<Flag> = sqlx::query_scalar("SELECT disabled FROM users WHERE id = $1")
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?;
+            Ok(disabled.map(bool::from))
+        })
+    }
+}
+
+impl CredentialRepository for SqlRepository {
+    fn password_hash(&self, user_id: i64) -> RepositoryFuture<'_, Option<String>> {
+        Box::pin(async move {
+            sqlx::query_scalar("SELECT password_hash FROM auth WHERE userid = $1")
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await
+        })
+    }
+}
+
+impl ConversationRepository for SqlRepository {
+    fn list(&self, owner: Option<i64>) -> RepositoryFuture<'_, Vec<DbConversation>> {
+        Box::pin(async move {
+            sqlx::query_as(
+                "SELECT id, title, updatetime, filepath, import_pending FROM conversation
+                 WHERE $1 IS NULL OR userid = $1 ORDER BY updatetime DESC",
+            )
+            .bind(owner)
+            .fetch_all(&self.pool)
+            .await
+        })
+    }
+
+    fn find(&self, id: i64, owner: Option<i64>) -> RepositoryFuture<'_, Option<DbConversation>> {
+        Box::pin(async move {
+            sqlx::query_as(
+                "SELECT id, title, updatetime, filepath, import_pending FROM conversation
+                 WHERE id = $1 AND ($2 IS NULL OR userid = $2)",
+            )
+            .bind(id)
+            .bind(owner)
+            .fetch_optional(&self.pool)
+            .await
+        })
+    }
+
+    fn messages(&self, conversation_id: i64) -> RepositoryFuture<'_, Vec<ChatMessage>> {
+        Box::pin(async move {
+            let messages: Vec<DbMessage> =
+                sqlx::query_as("SELECT content, role, truncated FROM messages WHERE conversation_id = $1 ORDER BY seq")
+                    .bind(conversation_id)
+                    .fetch_all(&self.pool)
+                    .await?;
+            Ok(messages.into_iter().map(ChatMessage::from).collect())
+        })
+    }
+}
@@ -1,12 +1,183 @@
 use super::*;
+use auth::keys::JwtKeys;
 use auth::types::{AppState, AuthResponse, JwtConfig};
+use auth::oidc::OidcClient;
+use config::types::{Config, ConfigArgs, DatabaseSection};
+use llm::LlmClient;
 use axum::{
+    Json,
     body::Body,
-    http::{Request, StatusCode},
+    http::{HeaderMap, HeaderValue, Request, StatusCode},
 };
-use bcrypt;
+use llm::types::{ChatMessage, LlmConfig};
+use repository::{Repositories, memory::MemoryRepository};
+use serde_json::json;
+use sqlx::Row;
+use sqlx::AnyPool;
+use std::path::Path;
+use tokio_stream::StreamExt;
 use tower::ServiceExt; // Required for oneshot() in tests
 
+// A fresh database with the schema the server migrates to on startup: in-memory SQLite, or a
+// schema of its own in the PostgreSQL database at TEST_DATABASE_URL when that is set
+async fn test_pool() -> AnyPool {
+    let name = format!("test_{}", uuid::Uuid::new_v4().simple());
+    let config = match std::env::var("TEST_DATABASE_URL") {
+        Ok(url) => {
+            let admin = db::connect(&DatabaseSection {
+                url: url.clone(),
+                max_connections: 1,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+            sqlx::query(&format!("CREATE SCHEMA {}", name)).execute(&admin).await.unwrap();
+            admin.close().await;
+
+            let separator = if url.contains('?') { '&' } else { '?' };
+            DatabaseSection {
+                url: format!("{}{}options[search_path]={}", url, separator, name),
+                // Tests run in parallel, each with its own pool
+                max_connections: 4,
+                ..Default::default()
+            }
+        }
+        Err(_) => DatabaseSection {
+            url: format!("sqlite:file:{}?mode=memory", name),
+            ..Default::default()
+        },
+    };
+    let pool = db::connect(&config).await.unwrap();
+    db::migrate(&pool).await.unwrap();
+    pool
+}
+
+fn test_auth_config() -> AuthConfig {
+    AuthConfig {
+        registration: RegistrationMode::AdminOnly,
+        bcrypt_cost: 4,
+        password_reset_url: "http://localhost:3000/reset-password?token=".to_string(),
+        reset_token_expiry: 3600,
+        trust_proxy_headers: false,
+        cookies: None,
+    }
+}
+
+// HS256 keys for the "test-secret" secret that every test token is signed with
+fn test_keys() -> Arc<JwtKeys> {
+    Arc::new(JwtKeys::hmac("test", b"test-secret"))
+}
+
+// One-hour access tokens and one-day refresh tokens signed with `test_keys`
+fn test_jwt_config() -> JwtConfig {
+    JwtConfig {
+        keys: test_keys(),
+        issuer: None,
+        audience: None,
+        access_expiry: 3600,
+        refresh_expiry: 86400,
+    }
+}
+
+fn test_state(pool: AnyPool, jwt_config: JwtConfig) -> Arc<AppState> {
+    Arc::new(AppState {
+        repositories: Repositories::sql(pool.clone()),
+        pool,
+        jwt_config,
+        auth_config: test_auth_config(),
+        mailer: Arc::new(mail::LogMailer),
+        oidc: None,
+        llm: LlmClient::new(LlmConfig {
+            base_url: "http://127.0.0.1:9".to_string(),
+            model: "deepseek-chat".to_string(),
+            api_key: None,
+        }),
+        conversation_dir: std::env::temp_dir(),
+        cors_origins: CorsOrigins::Any,
+    })
+}
+
+// Serve `app` on an ephemeral local port and return its base URL
+async fn spawn_upstream(app: Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    format!("http://{}", addr)
+}
+
+// Users 1 and 2, admin 3, and conversation 1, owned by user 1 and holding a single user message imported from `history.json` in `dir`
+async fn conversation_state(dir: &Path, base_url: String) -> Arc<AppState> {
+    let pool = test_pool().await;
+    sqlx::query(
+        "INSERT INTO users (email, role) VALUES ('user@example.com', 'user'), ('other@example.com', 'user'), ('admin@example.com', 'admin')",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO conversation (title, updatetime, filepath, userid, import_pending) VALUES ('hello', '2025-01-01 00:00:00', 'history.json', 1, TRUE)",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    std::fs::write(
+        dir.join("history.json"),
+        r#"[{"content": "hello", "role": "user"}]"#,
+    )
+    .unwrap();
+    assert_eq!(conversation::import_legacy_files(&pool, dir).await.unwrap(), 1);
+
+    Arc::new(AppState {
+        llm: LlmClient::new(LlmConfig {
+            base_url,
+            model: "deepseek-chat".to_string(),
+            api_key: Some("sk-test".to_string()),
+        }),
+        conversation_dir: dir.to_path_buf(),
+        ..Arc::unwrap_or_clone(test_state(pool, test_jwt_config()))
+    })
+}
+
+// Authorization header value for a token signed with the `conversation_state` secret
+fn bearer(sub: &str, role: &str) -> String {
+    let now = chrono::Utc::now().timestamp() as usize;
+    let claims = auth::types::Claims {
+        sub: sub.to_string(),
+        exp: now + 3600,
+        iat: now,
+        role: role.to_string(),
+        token_type: auth::types::TokenType::Access,
+        jti: "test".to_string(),
+        iss: None,
+        aud: None,
+    };
+    let token = jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &claims,
+        &jsonwebtoken::EncodingKey::from_secret("test-secret".as_ref()),
+    )
+    .unwrap();
+    format!("Bearer {}", token)
+}
+
+async fn read_history(state: &AppState) -> Vec<ChatMessage> {
+    sqlx::query_as::<_, (String, String, db::Flag)>(
+        "SELECT content, role, truncated FROM messages WHERE conversation_id = 1 ORDER BY seq",
+    )
+    .fetch_all(&state.pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|(content, role, db::Flag(truncated))| ChatMessage {
+        content,
+        role,
+        truncated,
+    })
+    .collect()
+}
+
 // test for pwd crypt
 #[test]
 fn test_bcrypt() {
@@ -18,41 +189,22 @@ fn test_bcrypt() {
 
 #[tokio::test]
 async fn test_login_success() {
-    let pool = sqlx::sqlite::SqlitePool::connect("sqlite::memory:")
-        .await
-        .unwrap();
-    sqlx::query("CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT, role TEXT)")
-        .execute(&pool)
-        .await
-        .unwrap();
-    sqlx::query("CREATE TABLE auth (userid INTEGER, password_hash TEXT)")
-        .execute(&pool)
-        .await
-        .unwrap();
+    let pool = test_pool().await;
 
     let hash = bcrypt::hash("admin123", 4).unwrap();
-    sqlx::query("INSERT INTO users (id, email, role) VALUES (1, '263074289@qq.com', 'admin')")
+    sqlx::query("INSERT INTO users (email, role) VALUES ('263074289@qq.com', 'admin')")
         .execute(&pool)
         .await
         .unwrap();
-    sqlx::query("INSERT INTO auth (userid, password_hash) VALUES (1, ?)")
+    sqlx::query("INSERT INTO auth (userid, email, password_hash) SELECT id, email, $1 FROM users WHERE id = 1")
         .bind(&hash)
         .execute(&pool)
         .await
         .unwrap();
 
-    let state = Arc::new(AppState {
-        pool,
-        jwt_config: JwtConfig {
-            secret: "test-secret".to_string(),
-            access_expiry: 3600,
-            refresh_expiry: 86400,
-        },
-    });
+    let state = test_state(pool, test_jwt_config());
 
-    let app = Router::new()
-        .route("/auth/login", post(login))
-        .with_state(state);
+    let app = build_router(state);
 
     let response = app
         .oneshot(
@@ -73,30 +225,11 @@ async fn test_login_success() {
 
 #[tokio::test]
 async fn test_login_invalid_credentials() {
-    let pool = sqlx::sqlite::SqlitePool::connect("sqlite::memory:")
-        .await
-        .unwrap();
-    sqlx::query("CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT, role TEXT)")
-        .execute(&pool)
-        .await
-        .unwrap();
-    sqlx::query("CREATE TABLE auth (userid INTEGER, password_hash TEXT)")
-        .execute(&pool)
-        .await
-        .unwrap();
+    let pool = test_pool().await;
 
-    let state = Arc::new(AppState {
-        pool,
-        jwt_config: JwtConfig {
-            secret: "test-secret".to_string(),
-            access_expiry: 3600,
-            refresh_expiry: 86400,
-        },
-    });
+    let state = test_state(pool, test_jwt_config());
 
-    let app = Router::new()
-        .route("/auth/login", post(login))
-        .with_state(state);
+    let app = build_router(state);
 
     let response = app
         .oneshot(
@@ -118,42 +251,23 @@ async fn test_login_invalid_credentials() {
 #[tokio::test]
 async fn test_refresh_token() {
     // #1 - Setup test data
-    let pool = sqlx::sqlite::SqlitePool::connect("sqlite::memory:")
-        .await
-        .unwrap();
-    sqlx::query("CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT, role TEXT)")
-        .execute(&pool)
-        .await
-        .unwrap();
-    sqlx::query("CREATE TABLE auth (userid INTEGER, password_hash TEXT)")
-        .execute(&pool)
-        .await
-        .unwrap();
+    let pool = test_pool().await;
 
     let hash = bcrypt::hash("password", 4).unwrap();
-    sqlx::query("INSERT INTO users (id, email, role) VALUES (1, 'test@example.com', 'user')")
+    sqlx::query("INSERT INTO users (email, role) VALUES ('test@example.com', 'user')")
         .execute(&pool)
         .await
         .unwrap();
-    sqlx::query("INSERT INTO auth (userid, password_hash) VALUES (1, ?)")
+    sqlx::query("INSERT INTO auth (userid, email, password_hash) SELECT id, email, $1 FROM users WHERE id = 1")
         .bind(&hash)
         .execute(&pool)
         .await
         .unwrap();
 
-    let state = Arc::new(AppState {
-        pool,
-        jwt_config: JwtConfig {
-            secret: "test-secret".to_string(),
-            access_expiry: 3600,
-            refresh_expiry: 86400,
-        },
-    });
+    let state = test_state(pool, test_jwt_config());
 
     // #2 - Perform login and keep access_token1
-    let login_app = Router::new()
-        .route("/auth/login", post(login))
-        .with_state(Arc::clone(&state));
+    let login_app = build_router(Arc::clone(&state));
 
     let login_response = login_app
         .oneshot(
@@ -179,9 +293,7 @@ async fn test_refresh_token() {
     std::thread::sleep(std::time::Duration::from_millis(2000));
 
     // #3 - Perform refresh and keep access_token2
-    let refresh_app = Router::new()
-        .route("/auth/refresh", post(refresh_token))
-        .with_state(Arc::clone(&state));
+    let refresh_app = build_router(Arc::clone(&state));
 
     let refresh_response = refresh_app
         .oneshot(
@@ -212,9 +324,7 @@ async fn test_refresh_token() {
     );
 
     // #5 - Test auth/me with new token
-    let me_app = Router::new()
-        .route("/auth/me", get(get_current_user))
-        .with_state(state);
+    let me_app = build_router(state);
 
     let me_response = me_app
         .oneshot(
@@ -231,218 +341,1288 @@ async fn test_refresh_token() {
     assert_eq!(me_response.status(), StatusCode::OK);
 }
 
-#[tokio::test]
-async fn test_get_current_user() {
-    let pool = sqlx::sqlite::SqlitePool::connect("sqlite::memory:")
-        .await
-        .unwrap();
-    sqlx::query("CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT, role TEXT)")
-        .execute(&pool)
-        .await
-        .unwrap();
-    sqlx::query("CREATE TABLE auth (userid INTEGER, password_hash TEXT)")
-        .execute(&pool)
-        .await
-        .unwrap();
-
+// User 1 (`test@example.com`) and admin 2 (`admin@example.com`), both with password `password`,
+// and every auth route mounted
+async fn auth_state(registration: RegistrationMode) -> AppState {
+    let pool = test_pool().await;
     let hash = bcrypt::hash("password", 4).unwrap();
-    sqlx::query("INSERT INTO users (id, email, role) VALUES (1, '263074289@qq.com', 'admin')")
-        .execute(&pool)
-        .await
-        .unwrap();
-    sqlx::query("INSERT INTO auth (userid, password_hash) VALUES (1, ?)")
+    sqlx::query(
+        "INSERT INTO users (email, role) VALUES ('test@example.com', 'user'), ('admin@example.com', 'admin')",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    sqlx::query("INSERT INTO auth (userid, email, password_hash) SELECT id, email, $1 FROM users")
         .bind(&hash)
         .execute(&pool)
         .await
         .unwrap();
 
-    let state = Arc::new(AppState {
-        pool,
-        jwt_config: JwtConfig {
-            secret: "test-secret".to_string(),
-            access_expiry: 3600,
-            refresh_expiry: 86400,
+    AppState {
+        auth_config: AuthConfig {
+            registration,
+            ..test_auth_config()
         },
-    });
+        ..Arc::unwrap_or_clone(test_state(pool, test_jwt_config()))
+    }
+}
 
-    // First login to get token
-    let login_app = Router::new()
-        .route("/auth/login", post(login))
-        .with_state(Arc::clone(&state));
+async fn auth_app(registration: RegistrationMode) -> Router {
+    auth_router(auth_state(registration).await)
+}
 
-    let login_response = login_app
-        .oneshot(
-            Request::builder()
-                .method("POST")
-                .uri("/auth/login")
-                .header("content-type", "application/json")
-                .body(Body::from(
-                    r#"{"email": "263074289@qq.com", "password": "password"}"#,
-                ))
-                .unwrap(),
-        )
+fn auth_router(state: AppState) -> Router {
+    build_router(Arc::new(state))
+}
+
+async fn auth_request(app: &Router, uri: &str, access_token: Option<&str>, body: String) -> (StatusCode, Vec<u8>) {
+    let mut request = Request::builder()
+        .method("POST")
+        .uri(uri)
+        .header("content-type", "application/json");
+    if let Some(token) = access_token {
+        request = request.header("Authorization", format!("Bearer {}", token));
+    }
+
+    let response = app
+        .clone()
+        .oneshot(request.body(Body::from(body)).unwrap())
         .await
         .unwrap();
-
-    let body = axum::body::to_bytes(login_response.into_body(), 1024 * 1024)
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024)
         .await
         .unwrap();
-    let auth_response: AuthResponse = serde_json::from_slice(&body).unwrap();
+    (status, body.to_vec())
+}
 
-    // Test getting current user
-    let user_app = Router::new()
-        .route("/auth/me", get(get_current_user))
-        .with_state(state);
+async fn auth_login(app: &Router) -> AuthResponse {
+    auth_login_as(app, "test@example.com", "password").await
+}
 
-    let user_response = user_app
+async fn auth_login_as(app: &Router, email: &str, password: &str) -> AuthResponse {
+    let body = json!({ "email": email, "password": password }).to_string();
+    let (status, body) = auth_request(app, "/auth/login", None, body).await;
+    assert_eq!(status, StatusCode::OK);
+    serde_json::from_slice(&body).unwrap()
+}
+
+async fn auth_refresh(app: &Router, refresh_token: &str) -> (StatusCode, Vec<u8>) {
+    let body = json!({ "refresh_token": refresh_token }).to_string();
+    auth_request(app, "/auth/refresh", None, body).await
+}
+
+#[tokio::test]
+async fn test_build_router() {
+    let state = AppState {
+        cors_origins: CorsOrigins::List(vec![HeaderValue::from_static("http://app.example.com")]),
+        ..auth_state(RegistrationMode::AdminOnly).await
+    };
+    // Mounted under a prefix the way another application would embed it
+    let app = Router::new().nest("/chat", auth_router(state));
+
+    let body = json!({ "email": "test@example.com", "password": "password" }).to_string();
+    let (status, body) = auth_request(&app, "/chat/auth/login", None, body).await;
+    assert_eq!(status, StatusCode::OK);
+    let user: AuthResponse = serde_json::from_slice(&body).unwrap();
+    let get = |uri: &str, token: &str| {
+        Request::builder()
+            .uri(uri)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let response = app.clone().oneshot(get("/chat/auth/me", &user.access_token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.clone().oneshot(get("/chat/conversations", &user.access_token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.clone().oneshot(get("/chat/admin/users", &user.access_token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = app.clone().oneshot(get("/auth/me", &user.access_token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // Only the configured origins pass CORS preflight, with credentials for cookie sessions
+    let preflight = |origin: &str| {
+        Request::builder()
+            .method("OPTIONS")
+            .uri("/chat/conversations")
+            .header("Origin", origin)
+            .header("Access-Control-Request-Method", "POST")
+            .header("Access-Control-Request-Headers", "x-csrf-token")
+            .body(Body::empty())
+            .unwrap()
+    };
+    let response = app.clone().oneshot(preflight("http://app.example.com")).await.unwrap();
+    let headers = response.headers();
+    assert_eq!(headers["access-control-allow-origin"], "http://app.example.com");
+    assert_eq!(headers["access-control-allow-credentials"], "true");
+    assert!(headers["access-control-allow-headers"].to_str().unwrap().contains("x-csrf-token"));
+    let response = app.clone().oneshot(preflight("http://evil.example.com")).await.unwrap();
+    assert!(!response.headers().contains_key("access-control-allow-origin"));
+}
+
+#[tokio::test]
+async fn test_refresh_token_rotation() {
+    let app = auth_app(RegistrationMode::AdminOnly).await;
+    let first = auth_login(&app).await;
+
+    // Refreshing hands out a new refresh token
+    let (status, body) = auth_refresh(&app, &first.refresh_token).await;
+    assert_eq!(status, StatusCode::OK);
+    let second: AuthResponse = serde_json::from_slice(&body).unwrap();
+    assert_ne!(first.refresh_token, second.refresh_token);
+
+    // Access and refresh tokens can't stand in for each other
+    let (status, _) = auth_refresh(&app, &second.access_token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let response = app
+        .clone()
         .oneshot(
             Request::builder()
-                .method("GET")
                 .uri("/auth/me")
-                .header(
-                    "Authorization",
-                    format!("Bearer {}", auth_response.access_token),
-                )
+                .header("Authorization", format!("Bearer {}", second.refresh_token))
                 .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
 
-    assert_eq!(user_response.status(), StatusCode::OK);
+    // Replaying the rotated token revokes the whole family, including its successor
+    let (status, _) = auth_refresh(&app, &first.refresh_token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = auth_refresh(&app, &second.refresh_token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Other sessions are unaffected
+    let other = auth_login(&app).await;
+    let (status, _) = auth_refresh(&app, &other.refresh_token).await;
+    assert_eq!(status, StatusCode::OK);
 }
 
 #[tokio::test]
-async fn test_expired_token() {
-    let pool = sqlx::sqlite::SqlitePool::connect("sqlite::memory:")
-        .await
+async fn test_logout() {
+    let app = auth_app(RegistrationMode::AdminOnly).await;
+    let session = auth_login(&app).await;
+    let other = auth_login(&app).await;
+
+    let body = json!({ "refresh_token": session.refresh_token }).to_string();
+    let (status, _) = auth_request(&app, "/auth/logout", None, body).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let (status, _) = auth_refresh(&app, &session.refresh_token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, body) = auth_refresh(&app, &other.refresh_token).await;
+    assert_eq!(status, StatusCode::OK);
+    let other: AuthResponse = serde_json::from_slice(&body).unwrap();
+
+    // Logging out everywhere needs an access token and ends every session
+    let (status, _) = auth_request(&app, "/auth/logout-all", None, String::new()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) =
+        auth_request(&app, "/auth/logout-all", Some(&other.access_token), String::new()).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let (status, _) = auth_refresh(&app, &other.refresh_token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+// Auth routes with cookie sessions turned on
+async fn cookie_app() -> Router {
+    let state = auth_state(RegistrationMode::AdminOnly).await;
+    auth_router(AppState {
+        auth_config: AuthConfig {
+            cookies: Some(CookieConfig {
+                secure: true,
+                same_site: SameSite::Strict,
+                domain: None,
+                path_prefix: String::new(),
+            }),
+            ..state.auth_config.clone()
+        },
+        ..state
+    })
+}
+
+// Send `cookies` and, if given, the CSRF header; returns the status and the `Set-Cookie` headers by name
+async fn cookie_request(
+    app: &Router,
+    method: &str,
+    uri: &str,
+    cookies: &std::collections::HashMap<String, String>,
+    csrf: Option<&str>,
+) -> (StatusCode, std::collections::HashMap<String, String>) {
+    let cookie = cookies.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join("; ");
+    let mut request = Request::builder().method(method).uri(uri).header("cookie", cookie);
+    if let Some(csrf) = csrf {
+        request = request.header("x-csrf-token", csrf);
+    }
+    let response = app.clone().oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+    (response.status(), set_cookies(response.headers()))
+}
+
+fn set_cookies(headers: &HeaderMap) -> std::collections::HashMap<String, String> {
+    headers
+        .get_all("set-cookie")
+        .iter()
+        .map(|value| {
+            let (name, rest) = value.to_str().unwrap().split_once('=').unwrap();
+            (name.to_string(), rest.to_string())
+        })
+        .collect()
+}
+
+// The `name=value` part of each `Set-Cookie` header
+fn cookie_values(set_cookies: &std::collections::HashMap<String, String>) -> std::collections::HashMap<String, String> {
+    set_cookies
+        .iter()
+        .map(|(name, rest)| (name.clone(), rest.split(';').next().unwrap().to_string()))
+        .collect()
+}
+
+#[tokio::test]
+async fn test_cookie_path_prefix() {
+    // Nested under /api, the refresh cookie must still reach /api/auth/refresh
+    let state = auth_state(RegistrationMode::AdminOnly).await;
+    let state = AppState {
+        auth_config: AuthConfig {
+            cookies: Some(CookieConfig {
+                secure: true,
+                same_site: SameSite::Strict,
+                domain: None,
+                path_prefix: "/api".to_string(),
+            }),
+            ..state.auth_config.clone()
+        },
+        ..state
+    };
+    let app = Router::new().nest("/api", auth_router(state));
+
+    let request = Request::builder()
+        .method("POST")
+        .uri("/api/auth/login")
+        .header("content-type", "application/json")
+        .body(Body::from(json!({ "email": "test@example.com", "password": "password" }).to_string()))
         .unwrap();
-    sqlx::query("CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT, role TEXT)")
-        .execute(&pool)
-        .await
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let set = set_cookies(response.headers());
+    assert!(set["refresh_token"].contains("; Path=/api/auth;"));
+    assert!(set["access_token"].contains("; Path=/;"));
+
+    let cookies = cookie_values(&set);
+    let (status, set) = cookie_request(&app, "POST", "/api/auth/logout", &cookies, Some(&cookies["csrf_token"])).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert!(set["refresh_token"].starts_with("; Path=/api/auth; Max-Age=0;"));
+}
+
+#[tokio::test]
+async fn test_cookie_sessions() {
+    let app = cookie_app().await;
+
+    let request = Request::builder()
+        .method("POST")
+        .uri("/auth/login")
+        .header("content-type", "application/json")
+        .body(Body::from(json!({ "email": "test@example.com", "password": "password" }).to_string()))
         .unwrap();
-    sqlx::query("CREATE TABLE auth (userid INTEGER, password_hash TEXT)")
-        .execute(&pool)
-        .await
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let set = set_cookies(response.headers());
+    assert!(set["access_token"].ends_with("; Path=/; Max-Age=3600; SameSite=Strict; HttpOnly; Secure"));
+    assert!(set["refresh_token"].ends_with("; Path=/auth; Max-Age=86400; SameSite=Strict; HttpOnly; Secure"));
+    assert!(set["csrf_token"].ends_with("; Path=/; Max-Age=86400; SameSite=Strict; Secure"));
+    let cookies = cookie_values(&set);
+    let csrf = cookies["csrf_token"].clone();
+
+    // Reads only need the cookie; changes also need the CSRF token
+    let (status, _) = cookie_request(&app, "GET", "/auth/me", &cookies, None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = cookie_request(&app, "POST", "/auth/refresh", &cookies, None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = cookie_request(&app, "POST", "/auth/refresh", &cookies, Some("forged")).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, set) = cookie_request(&app, "POST", "/auth/refresh", &cookies, Some(&csrf)).await;
+    assert_eq!(status, StatusCode::OK);
+    let refreshed = cookie_values(&set);
+    assert_ne!(refreshed["refresh_token"], cookies["refresh_token"]);
+    assert_ne!(refreshed["csrf_token"], csrf);
+
+    let (status, _) = cookie_request(&app, "POST", "/auth/logout-all", &refreshed, Some(&csrf)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, set) =
+        cookie_request(&app, "POST", "/auth/logout-all", &refreshed, Some(&refreshed["csrf_token"])).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert!(set.values().all(|cookie| cookie.starts_with("; Path=") && cookie.contains("Max-Age=0")));
+
+    // Bearer tokens keep working without a CSRF token
+    let session = auth_login(&app).await;
+    let (status, _) = auth_request(&app, "/auth/logout-all", Some(&session.access_token), String::new()).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn test_cookies_ignored_without_cookie_sessions() {
+    let app = auth_app(RegistrationMode::AdminOnly).await;
+
+    let request = Request::builder()
+        .method("POST")
+        .uri("/auth/login")
+        .header("content-type", "application/json")
+        .body(Body::from(json!({ "email": "test@example.com", "password": "password" }).to_string()))
         .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert!(response.headers().get("set-cookie").is_none());
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024).await.unwrap();
+    let session: AuthResponse = serde_json::from_slice(&body).unwrap();
+
+    let cookies = std::collections::HashMap::from([("access_token".to_string(), session.access_token)]);
+    let (status, _) = cookie_request(&app, "GET", "/auth/me", &cookies, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
 
-    let hash = bcrypt::hash("password", 4).unwrap();
-    sqlx::query("INSERT INTO users (id, email, role) VALUES (1, 'test@example.com', 'user')")
-        .execute(&pool)
+#[tokio::test]
+async fn test_register_open() {
+    let state = auth_state(RegistrationMode::Open).await;
+    let app = auth_router(state.clone());
+
+    let body = json!({ "email": "new@example.com", "password": "hunter22", "role": "admin" });
+    let (status, body) = auth_request(&app, "/auth/register", None, body.to_string()).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let user: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(user["email"], "new@example.com");
+    assert_eq!(user["role"], "user", "only admins pick roles");
+
+    auth_login_as(&app, "new@example.com", "hunter22").await;
+
+    // Same email again, in any case
+    let body = json!({ "email": "New@Example.com", "password": "hunter22" });
+    let (status, body) = auth_request(&app, "/auth/register", None, body.to_string()).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error["error"], "Email already registered");
+    auth_login_as(&app, "NEW@example.com", "hunter22").await;
+
+    // Rows from before emails were lowercased still count
+    sqlx::query("INSERT INTO users (email, role) VALUES ('Old@Example.com', 'user')")
+        .execute(&state.pool)
         .await
         .unwrap();
-    sqlx::query("INSERT INTO auth (userid, password_hash) VALUES (1, ?)")
-        .bind(&hash)
+    let body = json!({ "email": "old@example.com", "password": "hunter22" });
+    let (status, _) = auth_request(&app, "/auth/register", None, body.to_string()).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let user = state.repositories.users.find_by_email("OLD@example.COM").await.unwrap().unwrap();
+    assert_eq!(user.email, "Old@Example.com");
+
+    let body = json!({ "email": "short@example.com", "password": "short" });
+    let (status, _) = auth_request(&app, "/auth/register", None, body.to_string()).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let body = json!({ "email": "not an email", "password": "hunter22" });
+    let (status, _) = auth_request(&app, "/auth/register", None, body.to_string()).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_register_admin_only() {
+    let state = auth_state(RegistrationMode::AdminOnly).await;
+    let pool = state.pool.clone();
+    let app = auth_router(state);
+    let body = json!({ "email": "new@example.com", "password": "hunter22", "role": "admin" }).to_string();
+
+    let (status, _) = auth_request(&app, "/auth/register", None, body.clone()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let user = auth_login(&app).await;
+    let (status, _) = auth_request(&app, "/auth/register", Some(&user.access_token), body.clone()).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let admin = auth_login_as(&app, "admin@example.com", "password").await;
+    let (status, created) = auth_request(&app, "/auth/register", Some(&admin.access_token), body).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let user: serde_json::Value = serde_json::from_slice(&created).unwrap();
+    assert_eq!(user["role"], "admin");
+
+    // A disabled admin's token still verifies, but no longer creates accounts
+    sqlx::query("UPDATE users SET disabled = TRUE WHERE email = 'admin@example.com'")
         .execute(&pool)
         .await
         .unwrap();
+    let body = json!({ "email": "other@example.com", "password": "hunter22" }).to_string();
+    let (status, _) = auth_request(&app, "/auth/register", Some(&admin.access_token), body).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
 
-    let state = Arc::new(AppState {
-        pool,
-        jwt_config: JwtConfig {
-            secret: "test-secret".to_string(),
-            access_expiry: -1, // Expired immediately
-            refresh_expiry: 86400,
-        },
+#[tokio::test]
+async fn test_register_invite_only() {
+    let app = auth_app(RegistrationMode::InviteOnly).await;
+
+    let body = json!({ "email": "new@example.com", "password": "hunter22" });
+    let (status, _) = auth_request(&app, "/auth/register", None, body.to_string()).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Only admins hand out invites
+    let user = auth_login(&app).await;
+    let (status, _) = auth_request(&app, "/admin/invites", Some(&user.access_token), String::new()).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let admin = auth_login_as(&app, "admin@example.com", "password").await;
+    let (status, body) = auth_request(&app, "/admin/invites", Some(&admin.access_token), String::new()).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let invite: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let code = invite["code"].as_str().unwrap();
+
+    let body = json!({ "email": "new@example.com", "password": "hunter22", "invite_code": code });
+    let (status, _) = auth_request(&app, "/auth/register", None, body.to_string()).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    // Invites are single use
+    let body = json!({ "email": "other@example.com", "password": "hunter22", "invite_code": code });
+    let (status, _) = auth_request(&app, "/auth/register", None, body.to_string()).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_change_password() {
+    let app = auth_app(RegistrationMode::AdminOnly).await;
+    let session = auth_login(&app).await;
+
+    let body = json!({ "current_password": "wrong", "new_password": "hunter22" });
+    let (status, _) =
+        auth_request(&app, "/auth/change-password", Some(&session.access_token), body.to_string()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let body = json!({ "current_password": "password", "new_password": "hunter22" });
+    let (status, _) =
+        auth_request(&app, "/auth/change-password", Some(&session.access_token), body.to_string()).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    // Existing sessions are signed out and only the new password works
+    let (status, _) = auth_refresh(&app, &session.refresh_token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let body = json!({ "email": "test@example.com", "password": "password" }).to_string();
+    let (status, _) = auth_request(&app, "/auth/login", None, body).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    auth_login_as(&app, "test@example.com", "hunter22").await;
+}
+
+#[tokio::test]
+async fn test_password_reset() {
+    let mail_dir = tempfile::tempdir().unwrap();
+    let app = auth_router(AppState {
+        mailer: Arc::new(mail::FileMailer::new(mail_dir.path().to_path_buf())),
+        ..auth_state(RegistrationMode::AdminOnly).await
     });
+    let session = auth_login(&app).await;
+
+    // Unknown emails get the same answer and no mail
+    let body = json!({ "email": "nobody@example.com" }).to_string();
+    let (status, _) = auth_request(&app, "/auth/forgot-password", None, body).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(std::fs::read_dir(mail_dir.path()).map(|d| d.count()).unwrap_or(0), 0);
+
+    let body = json!({ "email": "Test@Example.com" }).to_string();
+    let (status, _) = auth_request(&app, "/auth/forgot-password", None, body).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+
+    let mails: Vec<_> = std::fs::read_dir(mail_dir.path()).unwrap().collect();
+    assert_eq!(mails.len(), 1);
+    let mail = std::fs::read_to_string(mails[0].as_ref().unwrap().path()).unwrap();
+    assert!(mail.starts_with("To: test@example.com\n"));
+    let token = mail
+        .split("reset-password?token=")
+        .nth(1)
+        .unwrap()
+        .split_whitespace()
+        .next()
+        .unwrap()
+        .to_string();
+
+    let body = json!({ "token": "bogus", "new_password": "hunter22" }).to_string();
+    let (status, _) = auth_request(&app, "/auth/reset-password", None, body).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // A rejected password leaves the token usable
+    let body = json!({ "token": token, "new_password": "short" }).to_string();
+    let (status, _) = auth_request(&app, "/auth/reset-password", None, body).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let body = json!({ "token": token, "new_password": "hunter22" }).to_string();
+    let (status, _) = auth_request(&app, "/auth/reset-password", None, body.clone()).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    auth_login_as(&app, "test@example.com", "hunter22").await;
+
+    // Tokens are single use and resetting signs out existing sessions
+    let (status, _) = auth_request(&app, "/auth/reset-password", None, body).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = auth_refresh(&app, &session.refresh_token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
 
-    // First login (should succeed despite immediate expiry)
-    let login_app = Router::new()
-        .route("/auth/login", post(login))
-        .with_state(Arc::clone(&state));
+#[tokio::test]
+async fn test_password_reset_expired() {
+    let state = auth_state(RegistrationMode::AdminOnly).await;
+    sqlx::query("INSERT INTO password_resets (token_hash, userid, expires_at) VALUES ($1, 1, $2)")
+        .bind(hex::encode(<sha2::Sha256 as sha2::Digest>::digest(b"expired")))
+        .bind(chrono::Utc::now().timestamp() - 1)
+        .execute(&state.pool)
+        .await
+        .unwrap();
+    let app = auth_router(state);
 
-    let login_response = login_app
+    let body = json!({ "token": "expired", "new_password": "hunter22" }).to_string();
+    let (status, body) = auth_request(&app, "/auth/reset-password", None, body).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error["error"], "Invalid or expired reset token");
+}
+
+async fn login_from(app: &Router, ip: &str, email: &str, password: &str) -> axum::response::Response {
+    let body = json!({ "email": email, "password": password }).to_string();
+    app.clone()
         .oneshot(
             Request::builder()
                 .method("POST")
                 .uri("/auth/login")
                 .header("content-type", "application/json")
-                .body(Body::from(
-                    r#"{"email": "test@example.com", "password": "password"}"#,
-                ))
+                .header("X-Forwarded-For", ip)
+                .body(Body::from(body))
                 .unwrap(),
         )
         .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_login_lockout() {
+    let app = auth_app(RegistrationMode::AdminOnly).await;
+
+    for _ in 0..5 {
+        let response = login_from(&app, "10.0.0.1", "test@example.com", "wrong").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    // Locked now, even with the right password and regardless of email case
+    let response = login_from(&app, "10.0.0.1", "Test@Example.com", "password").await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: i64 = response.headers()[axum::http::header::RETRY_AFTER]
+        .to_str()
+        .unwrap()
+        .parse()
         .unwrap();
+    assert!(retry_after > 0 && retry_after <= 30, "first lockout is 30s, got {}", retry_after);
 
-    assert_eq!(login_response.status(), StatusCode::OK);
+    // Other accounts are unaffected, and their admin can lift the lockout
+    let admin = auth_login_as(&app, "admin@example.com", "password").await;
+    let (status, _) = auth_request(&app, "/admin/users/99/unlock", Some(&admin.access_token), String::new()).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = auth_request(&app, "/admin/users/1/unlock", Some(&admin.access_token), String::new()).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
 
-    // Get token from login response
-    let body = axum::body::to_bytes(login_response.into_body(), 1024 * 1024)
+    auth_login(&app).await;
+}
+
+#[tokio::test]
+async fn test_login_ip_lockout() {
+    let mut state = auth_state(RegistrationMode::AdminOnly).await;
+    state.auth_config.trust_proxy_headers = true;
+    let app = auth_router(state);
+
+    // Spread over many emails so only the per-IP counter trips
+    for i in 0..20 {
+        let response = login_from(&app, "10.0.0.2", &format!("user{}@example.com", i), "wrong").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    let response = login_from(&app, "10.0.0.2", "test@example.com", "password").await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    // Made-up entries in front of the one the proxy appended don't get around it
+    let response = login_from(&app, "203.0.113.7, 10.0.0.2", "test@example.com", "password").await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let error: serde_json::Value =
+        serde_json::from_slice(&axum::body::to_bytes(response.into_body(), 1024).await.unwrap()).unwrap();
+    assert_eq!(error["error"], "Too many failed login attempts, try again later");
+
+    let response = login_from(&app, "10.0.0.3", "test@example.com", "password").await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+async fn admin_request(app: &Router, method: &str, uri: &str, token: &str, body: Option<serde_json::Value>) -> (StatusCode, serde_json::Value) {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json")
+        .header("Authorization", format!("Bearer {}", token))
+        .body(body.map(|b| Body::from(b.to_string())).unwrap_or_default())
+        .unwrap();
+
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024)
         .await
         .unwrap();
-    let auth_response: AuthResponse = serde_json::from_slice(&body).unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or_default())
+}
 
-    // Now test expired token case
-    let app = Router::new()
-        .route("/auth/me", get(get_current_user))
-        .with_state(state);
+#[tokio::test]
+async fn test_admin_list_users() {
+    let app = auth_app(RegistrationMode::AdminOnly).await;
+    let admin = auth_login_as(&app, "admin@example.com", "password").await;
+
+    let (status, page) = admin_request(&app, "GET", "/admin/users?page=2&per_page=1", &admin.access_token, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(page["total"], 2);
+    assert_eq!(page["users"].as_array().unwrap().len(), 1);
+    assert_eq!(page["users"][0]["email"], "admin@example.com");
+    assert_eq!(page["users"][0]["disabled"], false);
+
+    let user = auth_login(&app).await;
+    let (status, _) = admin_request(&app, "GET", "/admin/users", &user.access_token, None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_admin_disable_user() {
+    let state = auth_state(RegistrationMode::AdminOnly).await;
+    let pool = state.pool.clone();
+    let app = auth_router(state);
+    let admin = auth_login_as(&app, "admin@example.com", "password").await;
+    let user = auth_login(&app).await;
+
+    let (status, error) = admin_request(&app, "POST", "/admin/users/2/disable", &admin.access_token, None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "self_action");
+    let (status, _) = admin_request(&app, "POST", "/admin/users/99/disable", &admin.access_token, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, _) = admin_request(&app, "POST", "/admin/users/1/disable", &admin.access_token, None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    // Blocked at login, for existing access tokens and for refresh
+    let body = json!({ "email": "test@example.com", "password": "password" }).to_string();
+    let (status, body) = auth_request(&app, "/auth/login", None, body).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error["error"], "Account disabled");
+    let (status, _) = admin_request(&app, "GET", "/auth/me", &user.access_token, None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = auth_refresh(&app, &user.refresh_token).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, _) = admin_request(&app, "POST", "/admin/users/1/enable", &admin.access_token, None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    auth_login(&app).await;
+
+    let events: Vec<(i64, String, i64)> =
+        sqlx::query_as("SELECT actor_id, event, target_id FROM audit_log WHERE event LIKE 'user.%' ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    assert_eq!(
+        events,
+        vec![(2, "user.disabled".to_string(), 1), (2, "user.enabled".to_string(), 1)]
+    );
+}
 
+#[tokio::test]
+async fn test_audit_log() {
+    let mut state = auth_state(RegistrationMode::AdminOnly).await;
+    state.auth_config.trust_proxy_headers = true;
+    let app = auth_router(state);
+
+    let response = login_from(&app, "10.0.0.4", "test@example.com", "wrong").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let body = json!({ "email": "test@example.com", "password": "password" }).to_string();
     let response = app
+        .clone()
         .oneshot(
             Request::builder()
-                .method("GET")
-                .uri("/auth/me")
-                .header(
-                    "Authorization",
-                    format!("Bearer {}", auth_response.access_token),
-                )
-                .body(Body::empty())
+                .method("POST")
+                .uri("/auth/login")
+                .header("content-type", "application/json")
+                .header("X-Forwarded-For", "10.0.0.5")
+                .header("User-Agent", "audit-test/1.0")
+                .body(Body::from(body))
                 .unwrap(),
         )
         .await
         .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let admin = auth_login_as(&app, "admin@example.com", "password").await;
+
+    // Newest first, with where the request came from
+    let (status, page) = admin_request(&app, "GET", "/admin/audit?user_id=1", &admin.access_token, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(page["total"], 1);
+    assert_eq!(page["entries"][0]["event"], "login.succeeded");
+    assert_eq!(page["entries"][0]["ip"], "10.0.0.5");
+    assert_eq!(page["entries"][0]["user_agent"], "audit-test/1.0");
+
+    // Failed logins have no actor, only the attempted email
+    let (status, page) =
+        admin_request(&app, "GET", "/admin/audit?event=login.failed", &admin.access_token, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(page["total"], 1);
+    assert_eq!(page["entries"][0]["actor_id"], serde_json::Value::Null);
+    assert_eq!(page["entries"][0]["ip"], "10.0.0.4");
+    assert_eq!(page["entries"][0]["detail"], "email=test@example.com reason=invalid_credentials");
+
+    let (status, page) = admin_request(&app, "GET", "/admin/audit?per_page=2", &admin.access_token, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(page["total"], 3);
+    assert_eq!(page["entries"].as_array().unwrap().len(), 2);
+
+    let (status, page) =
+        admin_request(&app, "GET", "/admin/audit?to=2000-01-01T00:00:00Z", &admin.access_token, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(page["total"], 0);
+    let (status, page) =
+        admin_request(&app, "GET", "/admin/audit?from=2000-01-01T00:00:00Z", &admin.access_token, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(page["total"], 3);
+
+    let user = auth_login(&app).await;
+    let (status, _) = admin_request(&app, "GET", "/admin/audit", &user.access_token, None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
 
-    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+#[tokio::test]
+async fn test_admin_set_role() {
+    let app = auth_app(RegistrationMode::AdminOnly).await;
+    let admin = auth_login_as(&app, "admin@example.com", "password").await;
+    let user = auth_login(&app).await;
+
+    let (status, error) =
+        admin_request(&app, "PATCH", "/admin/users/1", &admin.access_token, Some(json!({ "role": "root" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_role");
+    let (status, _) =
+        admin_request(&app, "PATCH", "/admin/users/2", &admin.access_token, Some(json!({ "role": "user" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, _) =
+        admin_request(&app, "PATCH", "/admin/users/1", &admin.access_token, Some(json!({ "role": "admin" }))).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    // The new role arrives with the next refresh
+    let (status, body) = auth_refresh(&app, &user.refresh_token).await;
+    assert_eq!(status, StatusCode::OK);
+    let refreshed: AuthResponse = serde_json::from_slice(&body).unwrap();
+    let (status, _) = admin_request(&app, "GET", "/admin/users", &refreshed.access_token, None).await;
+    assert_eq!(status, StatusCode::OK);
+}
 
-    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024)
-        .await
-        .unwrap();
-    let error_response: serde_json::Value = serde_json::from_slice(&body).unwrap();
-    assert_eq!(error_response["error"], "Invalid token");
+#[tokio::test]
+async fn test_admin_force_reset_password() {
+    let app = auth_app(RegistrationMode::AdminOnly).await;
+    let admin = auth_login_as(&app, "admin@example.com", "password").await;
+    let user = auth_login(&app).await;
+
+    let body = json!({ "new_password": "short" });
+    let (status, error) =
+        admin_request(&app, "POST", "/admin/users/1/reset-password", &admin.access_token, Some(body)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_password");
+
+    let body = json!({ "new_password": "hunter22" });
+    let (status, _) =
+        admin_request(&app, "POST", "/admin/users/1/reset-password", &admin.access_token, Some(body)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let (status, _) = auth_refresh(&app, &user.refresh_token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    auth_login_as(&app, "test@example.com", "hunter22").await;
 }
 
 #[tokio::test]
-async fn test_invalid_token() {
-    let pool = sqlx::sqlite::SqlitePool::connect("sqlite::memory:")
-        .await
-        .unwrap();
-    sqlx::query("CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT, role TEXT)")
-        .execute(&pool)
-        .await
-        .unwrap();
-    sqlx::query("CREATE TABLE auth (userid INTEGER, password_hash TEXT)")
+async fn test_admin_delete_user() {
+    let state = auth_state(RegistrationMode::AdminOnly).await;
+    let pool = state.pool.clone();
+    let conversation_dir = state.conversation_dir.clone();
+    let app = auth_router(state);
+    let admin = auth_login_as(&app, "admin@example.com", "password").await;
+    let user = auth_login(&app).await;
+
+    let legacy_file = format!("delete-user-{}.json", uuid::Uuid::new_v4().simple());
+    std::fs::write(conversation_dir.join(&legacy_file), "[]").unwrap();
+    sqlx::query(
+        "INSERT INTO conversation (title, updatetime, filepath, userid) VALUES ('mine', '2025-01-01 00:00:00', $1, 1), ('admin', '2025-01-01 00:00:00', '', 2)",
+    )
+    .bind(&legacy_file)
+    .execute(&pool)
+    .await
+    .unwrap();
+    sqlx::query("INSERT INTO messages (conversation_id, seq, role, content) VALUES (1, 0, 'user', 'hi'), (2, 0, 'user', 'hi')")
         .execute(&pool)
         .await
         .unwrap();
 
-    let hash = bcrypt::hash("password", 4).unwrap();
-    sqlx::query("INSERT INTO users (id, email, role) VALUES (1, '263074289@qq.com', 'admin')")
-        .execute(&pool)
+    let (status, _) = admin_request(&app, "DELETE", "/admin/users/2", &admin.access_token, None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, _) = admin_request(&app, "DELETE", "/admin/users/1", &admin.access_token, None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = admin_request(&app, "DELETE", "/admin/users/1", &admin.access_token, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Their conversations, messages and legacy files are gone; other users' are untouched
+    let conversations: Vec<i64> = sqlx::query_scalar("SELECT id FROM conversation")
+        .fetch_all(&pool)
         .await
         .unwrap();
-    sqlx::query("INSERT INTO auth (userid, password_hash) VALUES (1, ?)")
-        .bind(&hash)
-        .execute(&pool)
+    assert_eq!(conversations, vec![2]);
+    let messages: Vec<i64> = sqlx::query_scalar("SELECT conversation_id FROM messages")
+        .fetch_all(&pool)
         .await
         .unwrap();
+    assert_eq!(messages, vec![2]);
+    assert!(!conversation_dir.join(&legacy_file).exists());
+
+    // Their tokens stop working right away
+    let (status, _) = admin_request(&app, "GET", "/auth/me", &user.access_token, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let body = json!({ "email": "test@example.com", "password": "password" }).to_string();
+    let (status, _) = auth_request(&app, "/auth/login", None, body).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
 
-    let state = Arc::new(AppState {
-        pool,
-        jwt_config: JwtConfig {
-            secret: "test-secret".to_string(),
-            access_expiry: 3600,
-            refresh_expiry: 86400,
-        },
-    });
+fn pem_keys(kid: &str, algorithm: jsonwebtoken::Algorithm, private_pem: &[u8], public_pem: &[u8]) -> JwtConfig {
+    JwtConfig {
+        keys: Arc::new(JwtKeys::from_pem(kid, algorithm, private_pem, public_pem).unwrap()),
+        issuer: Some("https://chat.example.com".to_string()),
+        audience: Some("chat-api".to_string()),
+        access_expiry: 3600,
+        refresh_expiry: 86400,
+    }
+}
 
-    // First login to get valid token
-    let login_app = Router::new()
-        .route("/auth/login", post(login))
-        .with_state(Arc::clone(&state));
+#[tokio::test]
+async fn test_asymmetric_keys() {
+    let configs: [(&str, jsonwebtoken::Algorithm, &[u8], &[u8]); 3] = [
+        ("rsa", jsonwebtoken::Algorithm::RS256, include_bytes!("keys/rsa.pem"), include_bytes!("keys/rsa.pub.pem")),
+        ("ec", jsonwebtoken::Algorithm::ES256, include_bytes!("keys/ec.pem"), include_bytes!("keys/ec.pub.pem")),
+        ("ed", jsonwebtoken::Algorithm::EdDSA, include_bytes!("keys/ed25519.pem"), include_bytes!("keys/ed25519.pub.pem")),
+    ];
+
+    for (kid, algorithm, private_pem, public_pem) in configs {
+        let app = auth_router(AppState {
+            jwt_config: pem_keys(kid, algorithm, private_pem, public_pem),
+            ..auth_state(RegistrationMode::AdminOnly).await
+        });
+        let session = auth_login(&app).await;
+
+        let header = jsonwebtoken::decode_header(&session.access_token).unwrap();
+        assert_eq!(header.alg, algorithm);
+        assert_eq!(header.kid.as_deref(), Some(kid));
+        let (status, _) = admin_request(&app, "GET", "/auth/me", &session.access_token, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = auth_refresh(&app, &session.refresh_token).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Other services can verify the token with nothing but the published key
+        let (status, jwks) = admin_request(&app, "GET", "/.well-known/jwks.json", "", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let jwks: jsonwebtoken::jwk::JwkSet = serde_json::from_value(jwks).unwrap();
+        assert_eq!(jwks.keys.len(), 1);
+        let mut validation = jsonwebtoken::Validation::new(algorithm);
+        validation.set_issuer(&["https://chat.example.com"]);
+        validation.set_audience(&["chat-api"]);
+        let claims = jsonwebtoken::decode::<auth::types::Claims>(
+            &session.access_token,
+            &jsonwebtoken::DecodingKey::from_jwk(jwks.find(kid).unwrap()).unwrap(),
+            &validation,
+        )
+        .unwrap()
+        .claims;
+        assert_eq!(claims.sub, "1");
+    }
+}
 
-    let login_response = login_app
-        .oneshot(
+#[tokio::test]
+async fn test_key_rotation() {
+    let old_app = auth_router(AppState {
+        jwt_config: pem_keys(
+            "2025-01",
+            jsonwebtoken::Algorithm::EdDSA,
+            include_bytes!("keys/ed25519-old.pem"),
+            include_bytes!("keys/ed25519-old.pub.pem"),
+        ),
+        ..auth_state(RegistrationMode::AdminOnly).await
+    });
+    let old_session = auth_login(&old_app).await;
+
+    // The new key signs, the old one is kept for verification only
+    let mut jwt_config = pem_keys(
+        "2025-02",
+        jsonwebtoken::Algorithm::EdDSA,
+        include_bytes!("keys/ed25519.pem"),
+        include_bytes!("keys/ed25519.pub.pem"),
+    );
+    let mut keys = (*jwt_config.keys).clone();
+    keys.add_verification_key("2025-01", include_bytes!("keys/ed25519-old.pub.pem")).unwrap();
+    assert!(keys.add_verification_key("2025-02", include_bytes!("keys/ec.pub.pem")).is_err());
+    jwt_config.keys = Arc::new(keys);
+    let app = auth_router(AppState {
+        jwt_config,
+        ..auth_state(RegistrationMode::AdminOnly).await
+    });
+
+    let (status, _) = admin_request(&app, "GET", "/auth/me", &old_session.access_token, None).await;
+    assert_eq!(status, StatusCode::OK);
+    let session = auth_login(&app).await;
+    let header = jsonwebtoken::decode_header(&session.access_token).unwrap();
+    assert_eq!(header.kid.as_deref(), Some("2025-02"));
+
+    let (_, jwks) = admin_request(&app, "GET", "/.well-known/jwks.json", "", None).await;
+    let kids: Vec<_> = jwks["keys"].as_array().unwrap().iter().map(|k| k["kid"].clone()).collect();
+    assert_eq!(kids, vec![json!("2025-01"), json!("2025-02")]);
+
+    // Keys a server doesn't know are rejected
+    let (status, _) = admin_request(&old_app, "GET", "/auth/me", &session.access_token, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_issuer_and_audience() {
+    let jwt_config = pem_keys(
+        "ec",
+        jsonwebtoken::Algorithm::ES256,
+        include_bytes!("keys/ec.pem"),
+        include_bytes!("keys/ec.pub.pem"),
+    );
+    let keys = Arc::clone(&jwt_config.keys);
+    let app = auth_router(AppState {
+        jwt_config,
+        ..auth_state(RegistrationMode::AdminOnly).await
+    });
+
+    let now = chrono::Utc::now().timestamp() as usize;
+    let claims = |iss: Option<&str>, aud: Option<&str>| auth::types::Claims {
+        sub: "1".to_string(),
+        exp: now + 3600,
+        iat: now,
+        role: "user".to_string(),
+        token_type: auth::types::TokenType::Access,
+        jti: "test".to_string(),
+        iss: iss.map(str::to_string),
+        aud: aud.map(str::to_string),
+    };
+
+    let cases = [
+        (Some("https://chat.example.com"), Some("chat-api"), StatusCode::OK),
+        (Some("https://evil.example.com"), Some("chat-api"), StatusCode::UNAUTHORIZED),
+        (Some("https://chat.example.com"), Some("other-api"), StatusCode::UNAUTHORIZED),
+        (None, Some("chat-api"), StatusCode::UNAUTHORIZED),
+        (Some("https://chat.example.com"), None, StatusCode::UNAUTHORIZED),
+    ];
+    for (iss, aud, expected) in cases {
+        let token = keys.encode(&claims(iss, aud)).unwrap();
+        let (status, _) = admin_request(&app, "GET", "/auth/me", &token, None).await;
+        assert_eq!(status, expected, "iss {:?}, aud {:?}", iss, aud);
+    }
+
+    // An HMAC token keyed with the public key can't pass for an ES256 one
+    let forged = JwtKeys::hmac("ec", include_bytes!("keys/ec.pub.pem"))
+        .encode(&claims(Some("https://chat.example.com"), Some("chat-api")))
+        .unwrap();
+    let (status, _) = admin_request(&app, "GET", "/auth/me", &forged, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+fn test_key_configuration_errors() {
+    use auth::keys::KeyError;
+    use jsonwebtoken::Algorithm;
+
+    let rsa = include_bytes!("keys/rsa.pem");
+    let rsa_pub = include_bytes!("keys/rsa.pub.pem");
+    let ed = include_bytes!("keys/ed25519.pem");
+
+    assert!(matches!(
+        JwtKeys::from_pem("k", Algorithm::RS256, rsa, include_bytes!("keys/ec.pub.pem")),
+        Err(KeyError::AlgorithmMismatch(..))
+    ));
+    assert!(matches!(
+        JwtKeys::from_pem("k", Algorithm::EdDSA, ed, include_bytes!("keys/ed25519-old.pub.pem")),
+        Err(KeyError::KeyPairMismatch(..))
+    ));
+    assert!(matches!(
+        JwtKeys::from_pem("k", Algorithm::PS256, rsa, rsa_pub),
+        Err(KeyError::UnsupportedAlgorithm(Algorithm::PS256))
+    ));
+    assert!(matches!(
+        JwtKeys::from_pem("k", Algorithm::EdDSA, b"not a key", include_bytes!("keys/ed25519.pub.pem")),
+        Err(KeyError::InvalidKey(..))
+    ));
+    assert!(JwtKeys::from_pem("k", Algorithm::RS256, rsa, rsa_pub).is_ok());
+}
+
+// What the mock identity provider puts in the next ID token it hands out
+#[derive(Default)]
+struct MockIdp {
+    code_challenge: String,
+    nonce: String,
+    email: String,
+    email_verified: Option<bool>,
+}
+
+// Serve a minimal OpenID provider: discovery, JWKS, and a token endpoint that checks the client
+// secret and the PKCE verifier for the code "good-code"
+async fn spawn_idp() -> (String, Arc<std::sync::Mutex<MockIdp>>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let idp = Arc::new(std::sync::Mutex::new(MockIdp::default()));
+    let keys = Arc::new(
+        JwtKeys::from_pem(
+            "idp",
+            jsonwebtoken::Algorithm::RS256,
+            include_bytes!("keys/rsa.pem"),
+            include_bytes!("keys/rsa.pub.pem"),
+        )
+        .unwrap(),
+    );
+
+    let discovery = json!({
+        "issuer": base_url,
+        "authorization_endpoint": format!("{}/authorize", base_url),
+        "token_endpoint": format!("{}/token", base_url),
+        "jwks_uri": format!("{}/jwks", base_url),
+    });
+    let jwks = serde_json::to_value(keys.jwks()).unwrap();
+    let issuer = base_url.clone();
+    let token_idp = Arc::clone(&idp);
+
+    let app = Router::new()
+        .route("/.well-known/openid-configuration", get(move || async move { Json(discovery) }))
+        .route("/jwks", get(move || async move { Json(jwks) }))
+        .route(
+            "/token",
+            post(move |headers: HeaderMap, form: axum::Form<std::collections::HashMap<String, String>>| async move {
+                let idp = token_idp.lock().unwrap();
+                let challenge = base64::Engine::encode(
+                    &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+                    <sha2::Sha256 as sha2::Digest>::digest(form["code_verifier"].as_bytes()),
+                );
+                let client = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, "chat:chat-secret");
+                if headers["authorization"] != format!("Basic {}", client)
+                    || form["code"] != "good-code"
+                    || form["grant_type"] != "authorization_code"
+                    || challenge != idp.code_challenge
+                {
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+
+                let now = chrono::Utc::now().timestamp();
+                let mut claims = json!({
+                    "iss": issuer,
+                    "aud": "chat",
+                    "sub": "idp-user",
+                    "exp": now + 300,
+                    "iat": now,
+                    "nonce": idp.nonce,
+                    "email": idp.email,
+                });
+                // Some providers leave the claim out altogether
+                if let Some(verified) = idp.email_verified {
+                    claims["email_verified"] = json!(verified);
+                }
+                Ok(Json(json!({ "id_token": keys.encode(&claims).unwrap(), "token_type": "Bearer" })))
+            }),
+        );
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+
+    (base_url, idp)
+}
+
+async fn oidc_app(auto_provision: bool) -> (Router, AnyPool, Arc<std::sync::Mutex<MockIdp>>) {
+    let (base_url, idp) = spawn_idp().await;
+    let state = auth_state(RegistrationMode::AdminOnly).await;
+    let pool = state.pool.clone();
+    let app = auth_router(AppState {
+        oidc: Some(Arc::new(OidcClient::new(OidcConfig {
+            discovery_url: format!("{}/.well-known/openid-configuration", base_url),
+            client_id: "chat".to_string(),
+            client_secret: "chat-secret".to_string(),
+            redirect_url: "http://localhost:3000/auth/oidc/callback".to_string(),
+            auto_provision,
+        }))),
+        ..state
+    });
+    (app, pool, idp)
+}
+
+// Go through the provider redirect as `email` and return the callback's response
+async fn oidc_sign_in(
+    app: &Router,
+    idp: &std::sync::Mutex<MockIdp>,
+    email: &str,
+    verified: Option<bool>,
+) -> (StatusCode, Vec<u8>, Request<Body>) {
+    let response = app
+        .clone()
+        .oneshot(Request::builder().uri("/auth/oidc/login").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let set = set_cookies(response.headers());
+    assert!(set["oidc_state"].ends_with("; Path=/; Max-Age=600; SameSite=Lax; HttpOnly; Secure"));
+    let cookie = format!("oidc_state={}", cookie_values(&set)["oidc_state"]);
+    let location = reqwest::Url::parse(response.headers()["location"].to_str().unwrap()).unwrap();
+    assert_eq!(location.path(), "/authorize");
+    let params: std::collections::HashMap<_, _> = location.query_pairs().into_owned().collect();
+    assert_eq!(params["client_id"], "chat");
+    assert_eq!(params["redirect_uri"], "http://localhost:3000/auth/oidc/callback");
+    assert_eq!(params["code_challenge_method"], "S256");
+
+    *idp.lock().unwrap() = MockIdp {
+        code_challenge: params["code_challenge"].clone(),
+        nonce: params["nonce"].clone(),
+        email: email.to_string(),
+        email_verified: verified,
+    };
+
+    let uri = format!("/auth/oidc/callback?code=good-code&state={}", params["state"]);
+    let callback = || Request::builder().uri(&uri).header("cookie", &cookie).body(Body::empty()).unwrap();
+    let response = app.clone().oneshot(callback()).await.unwrap();
+    let status = response.status();
+    if status == StatusCode::OK {
+        assert!(set_cookies(response.headers())["oidc_state"].contains("Max-Age=0"));
+    }
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024).await.unwrap();
+    (status, body.to_vec(), callback())
+}
+
+#[tokio::test]
+async fn test_oidc_login() {
+    let (app, pool, idp) = oidc_app(false).await;
+
+    // Emails match existing users regardless of case
+    let (status, body, callback) = oidc_sign_in(&app, &idp, "Test@Example.com", Some(true)).await;
+    assert_eq!(status, StatusCode::OK);
+    let session: AuthResponse = serde_json::from_slice(&body).unwrap();
+    let (status, user) = admin_request(&app, "GET", "/auth/me", &session.access_token, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(user["id"], 1);
+    let (status, _) = auth_refresh(&app, &session.refresh_token).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Each login state works once
+    let response = app.clone().oneshot(callback).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // A login only finishes in the browser that started it, not one sent its callback URL
+    let response = app
+        .clone()
+        .oneshot(Request::builder().uri("/auth/oidc/login").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let location = reqwest::Url::parse(response.headers()["location"].to_str().unwrap()).unwrap();
+    let (_, login_state) = location.query_pairs().find(|(name, _)| name == "state").unwrap();
+    let uri = format!("/auth/oidc/callback?code=good-code&state={}", login_state);
+    for cookie in [None, Some("oidc_state=someone-elses")] {
+        let mut request = Request::builder().uri(&uri);
+        if let Some(cookie) = cookie {
+            request = request.header("cookie", cookie);
+        }
+        let response = app.clone().oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+    // ... and the rejected attempts leave the login for its own browser to finish
+    let pending: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM oidc_logins WHERE state = $1")
+        .bind(login_state.as_ref())
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(pending, 1);
+
+    let (status, body, _) = oidc_sign_in(&app, &idp, "stranger@example.com", Some(true)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error["error"], "No account for this email");
+
+    let (status, _, _) = oidc_sign_in(&app, &idp, "test@example.com", Some(false)).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    // An email the provider doesn't vouch for can't take over the matching account
+    let (status, _, _) = oidc_sign_in(&app, &idp, "test@example.com", None).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+    let events: Vec<(String, Option<String>)> = sqlx::query_as("SELECT event, detail FROM audit_log ORDER BY id")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    assert_eq!(events[0], ("login.succeeded".to_string(), Some("via=oidc".to_string())));
+    assert!(events.contains(&(
+        "login.failed".to_string(),
+        Some("email=stranger@example.com reason=no_account via=oidc".to_string())
+    )));
+}
+
+#[tokio::test]
+async fn test_oidc_auto_provision() {
+    let (app, pool, idp) = oidc_app(true).await;
+
+    let (status, body, _) = oidc_sign_in(&app, &idp, "new@example.com", Some(true)).await;
+    assert_eq!(status, StatusCode::OK);
+    let session: AuthResponse = serde_json::from_slice(&body).unwrap();
+    let (_, user) = admin_request(&app, "GET", "/auth/me", &session.access_token, None).await;
+    assert_eq!(user["email"], "new@example.com");
+    assert_eq!(user["role"], "user");
+
+    // Provisioned once, and without a password to log in with
+    let (status, _, _) = oidc_sign_in(&app, &idp, "new@example.com", Some(true)).await;
+    assert_eq!(status, StatusCode::OK);
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE email = 'new@example.com'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(count, 1);
+    let body = json!({ "email": "new@example.com", "password": "" }).to_string();
+    let (status, _) = auth_request(&app, "/auth/login", None, body).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Disabled accounts stay disabled
+    sqlx::query("UPDATE users SET disabled = TRUE WHERE email = 'new@example.com'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let (status, _, _) = oidc_sign_in(&app, &idp, "new@example.com", Some(true)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_oidc_not_configured() {
+    let app = auth_app(RegistrationMode::AdminOnly).await;
+    let response = app
+        .oneshot(Request::builder().uri("/auth/oidc/login").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_get_current_user() {
+    let pool = test_pool().await;
+
+    let hash = bcrypt::hash("password", 4).unwrap();
+    sqlx::query("INSERT INTO users (email, role) VALUES ('263074289@qq.com', 'admin')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO auth (userid, email, password_hash) SELECT id, email, $1 FROM users WHERE id = 1")
+        .bind(&hash)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let state = test_state(pool, test_jwt_config());
+
+    // First login to get token
+    let login_app = build_router(Arc::clone(&state));
+
+    let login_response = login_app
+        .oneshot(
             Request::builder()
                 .method("POST")
                 .uri("/auth/login")
@@ -459,66 +1639,180 @@ async fn test_invalid_token() {
         .await
         .unwrap();
     let auth_response: AuthResponse = serde_json::from_slice(&body).unwrap();
-    let token = auth_response.access_token + "invalid";
 
-    // Now test with invalid token
-    let user_app = Router::new()
-        .route("/auth/me", get(get_current_user))
-        .with_state(state);
+    // Test getting current user
+    let user_app = build_router(state);
 
     let user_response = user_app
         .oneshot(
             Request::builder()
                 .method("GET")
                 .uri("/auth/me")
-                .header("Authorization", token)
+                .header(
+                    "Authorization",
+                    format!("Bearer {}", auth_response.access_token),
+                )
                 .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
 
-    assert_eq!(user_response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(user_response.status(), StatusCode::OK);
 }
 
 #[tokio::test]
-async fn test_missing_token() {
-    let pool = sqlx::sqlite::SqlitePool::connect("sqlite::memory:")
+async fn test_expired_token() {
+    let pool = test_pool().await;
+
+    let hash = bcrypt::hash("password", 4).unwrap();
+    sqlx::query("INSERT INTO users (email, role) VALUES ('test@example.com', 'user')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO auth (userid, email, password_hash) SELECT id, email, $1 FROM users WHERE id = 1")
+        .bind(&hash)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let state = test_state(
+        pool,
+        JwtConfig {
+            access_expiry: -1, // Expired immediately
+            ..test_jwt_config()
+        },
+    );
+
+    // First login (should succeed despite immediate expiry)
+    let login_app = build_router(Arc::clone(&state));
+
+    let login_response = login_app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/auth/login")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    r#"{"email": "test@example.com", "password": "password"}"#,
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(login_response.status(), StatusCode::OK);
+
+    // Get token from login response
+    let body = axum::body::to_bytes(login_response.into_body(), 1024 * 1024)
+        .await
+        .unwrap();
+    let auth_response: AuthResponse = serde_json::from_slice(&body).unwrap();
+
+    // Now test expired token case
+    let app = build_router(state);
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("GET")
+                .uri("/auth/me")
+                .header(
+                    "Authorization",
+                    format!("Bearer {}", auth_response.access_token),
+                )
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024)
         .await
         .unwrap();
-    sqlx::query("CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT, role TEXT)")
+    let error_response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error_response["error"], "Invalid token");
+}
+
+#[tokio::test]
+async fn test_invalid_token() {
+    let pool = test_pool().await;
+
+    let hash = bcrypt::hash("password", 4).unwrap();
+    sqlx::query("INSERT INTO users (email, role) VALUES ('263074289@qq.com', 'admin')")
         .execute(&pool)
         .await
         .unwrap();
-    sqlx::query("CREATE TABLE auth (userid INTEGER, password_hash TEXT)")
+    sqlx::query("INSERT INTO auth (userid, email, password_hash) SELECT id, email, $1 FROM users WHERE id = 1")
+        .bind(&hash)
         .execute(&pool)
         .await
         .unwrap();
 
+    let state = test_state(pool, test_jwt_config());
+
+    // First login to get valid token
+    let login_app = build_router(Arc::clone(&state));
+
+    let login_response = login_app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/auth/login")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    r#"{"email": "263074289@qq.com", "password": "password"}"#,
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let body = axum::body::to_bytes(login_response.into_body(), 1024 * 1024)
+        .await
+        .unwrap();
+    let auth_response: AuthResponse = serde_json::from_slice(&body).unwrap();
+    let token = auth_response.access_token + "invalid";
+
+    // Now test with invalid token
+    let user_app = build_router(state);
+
+    let user_response = user_app
+        .oneshot(
+            Request::builder()
+                .method("GET")
+                .uri("/auth/me")
+                .header("Authorization", token)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(user_response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_missing_token() {
+    let pool = test_pool().await;
+
     let hash = bcrypt::hash("password", 4).unwrap();
-    sqlx::query("INSERT INTO users (id, email, role) VALUES (1, 'test@example.com', 'user')")
+    sqlx::query("INSERT INTO users (email, role) VALUES ('test@example.com', 'user')")
         .execute(&pool)
         .await
         .unwrap();
-    sqlx::query("INSERT INTO auth (userid, password_hash) VALUES (1, ?)")
+    sqlx::query("INSERT INTO auth (userid, email, password_hash) SELECT id, email, $1 FROM users WHERE id = 1")
         .bind(&hash)
         .execute(&pool)
         .await
         .unwrap();
 
-    let state = Arc::new(AppState {
-        pool,
-        jwt_config: JwtConfig {
-            secret: "test-secret".to_string(),
-            access_expiry: 3600,
-            refresh_expiry: 86400,
-        },
-    });
+    let state = test_state(pool, test_jwt_config());
 
     // First login to get valid token (though we won't use it)
-    let login_app = Router::new()
-        .route("/auth/login", post(login))
-        .with_state(Arc::clone(&state));
+    let login_app = build_router(Arc::clone(&state));
 
     let login_response = login_app
         .oneshot(
@@ -537,9 +1831,7 @@ async fn test_missing_token() {
     assert_eq!(login_response.status(), StatusCode::OK);
 
     // Now test missing token case
-    let app = Router::new()
-        .route("/auth/me", get(get_current_user))
-        .with_state(state);
+    let app = build_router(Arc::clone(&state));
 
     let response = app
         .oneshot(
@@ -559,46 +1851,68 @@ async fn test_missing_token() {
         .unwrap();
     let error_response: serde_json::Value = serde_json::from_slice(&body).unwrap();
     assert_eq!(error_response["error"], "Missing authorization token");
-}
 
-#[tokio::test]
-async fn test_role_based_access() {
-    let pool = sqlx::sqlite::SqlitePool::connect("sqlite::memory:")
+    // Role-guarded routes reject the request before reaching the handler
+    let guarded_app = Router::new()
+        .route("/admin/ping", get(|| async { "pong" }))
+        .route_layer(require_role(&state, "admin"))
+        .with_state(Arc::clone(&state));
+
+    let response = guarded_app
+        .oneshot(
+            Request::builder()
+                .method("GET")
+                .uri("/admin/ping")
+                .body(Body::empty())
+                .unwrap(),
+        )
         .await
         .unwrap();
-    sqlx::query("CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT, role TEXT)")
-        .execute(&pool)
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024)
         .await
         .unwrap();
-    sqlx::query("CREATE TABLE auth (userid INTEGER, password_hash TEXT)")
-        .execute(&pool)
+    let error_response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error_response["error"], "Missing authorization token");
+
+    // A token whose subject is not a user id is rejected by `AuthUser`
+    let app = build_router(state);
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("GET")
+                .uri("/auth/me")
+                .header("Authorization", bearer("not-a-user-id", "user"))
+                .body(Body::empty())
+                .unwrap(),
+        )
         .await
         .unwrap();
 
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_role_based_access() {
+    let pool = test_pool().await;
+
     let hash = bcrypt::hash("user123", 4).unwrap();
-    sqlx::query("INSERT INTO users (id, email, role) VALUES (1, 'user@example.com', 'user')")
+    sqlx::query("INSERT INTO users (email, role) VALUES ('user@example.com', 'user'), ('admin@example.com', 'admin')")
         .execute(&pool)
         .await
         .unwrap();
-    sqlx::query("INSERT INTO auth (userid, password_hash) VALUES (1, ?)")
+    sqlx::query("INSERT INTO auth (userid, email, password_hash) SELECT id, email, $1 FROM users WHERE id = 1")
         .bind(&hash)
         .execute(&pool)
         .await
         .unwrap();
 
-    let state = Arc::new(AppState {
-        pool,
-        jwt_config: JwtConfig {
-            secret: "test-secret".to_string(),
-            access_expiry: 3600,
-            refresh_expiry: 86400,
-        },
-    });
+    let state = test_state(pool, test_jwt_config());
 
     // Login as regular user
-    let login_app = Router::new()
-        .route("/auth/login", post(login))
-        .with_state(Arc::clone(&state));
+    let login_app = build_router(Arc::clone(&state));
 
     let login_response = login_app
         .oneshot(
@@ -620,9 +1934,7 @@ async fn test_role_based_access() {
     let auth_response: AuthResponse = serde_json::from_slice(&body).unwrap();
 
     // Test getting current user
-    let user_app = Router::new()
-        .route("/auth/me", get(get_current_user))
-        .with_state(state);
+    let user_app = build_router(Arc::clone(&state));
 
     let user_response = user_app
         .oneshot(
@@ -640,4 +1952,1291 @@ async fn test_role_based_access() {
         .unwrap();
 
     assert_eq!(user_response.status(), StatusCode::OK);
+
+    // Only admins get past the admin guard, which hands the caller on to the handler
+    let guarded_app = Router::new()
+        .route("/admin/ping", get(|| async { "pong" }))
+        .route_layer(require_role(&state, "admin"))
+        .route(
+            "/chat/ping",
+            get(|axum::Extension(user): axum::Extension<auth::types::AuthUser>| async move { user.role }),
+        )
+        .route_layer(require_role(&state, "user"))
+        .with_state(state);
+
+    for (uri, authorization, expected) in [
+        ("/admin/ping", format!("Bearer {}", auth_response.access_token), StatusCode::FORBIDDEN),
+        ("/chat/ping", format!("Bearer {}", auth_response.access_token), StatusCode::OK),
+        ("/admin/ping", bearer("2", "admin"), StatusCode::OK),
+        ("/chat/ping", bearer("2", "admin"), StatusCode::OK),
+    ] {
+        let response = guarded_app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("GET")
+                    .uri(uri)
+                    .header("Authorization", authorization)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), expected, "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn test_send_message() {
+    let upstream = Router::new().route(
+        "/v1/chat/completions",
+        post(|headers: HeaderMap, Json(body): Json<serde_json::Value>| async move {
+            assert_eq!(headers["authorization"], "Bearer sk-test");
+            assert_eq!(body["model"], "deepseek-chat");
+            let messages = body["messages"].as_array().unwrap();
+            Json(json!({
+                "model": "deepseek-chat-v3",
+                "choices": [{
+                    "message": {
+                        "role": "assistant",
+                        "content": format!("{} messages, last: {}", messages.len(), messages.last().unwrap()["content"].as_str().unwrap()),
+                    },
+                }],
+                "usage": {"prompt_tokens": 12, "completion_tokens": 7, "total_tokens": 19},
+            }))
+        }),
+    );
+    let base_url = spawn_upstream(upstream).await;
+    let dir = tempfile::tempdir().unwrap();
+    let state = conversation_state(dir.path(), base_url).await;
+
+    let app = build_router(Arc::clone(&state));
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations/1/messages")
+                .header("Authorization", bearer("1", "user"))
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"content": "how are you?"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024)
+        .await
+        .unwrap();
+    let reply: ChatMessage = serde_json::from_slice(&body).unwrap();
+    assert_eq!(reply.role, "assistant");
+    assert_eq!(reply.content, "2 messages, last: how are you?");
+
+    let history = read_history(&state).await;
+    assert_eq!(history.len(), 3);
+    assert_eq!(history[1].content, "how are you?");
+    assert_eq!(history[2], reply);
+
+    let (model, prompt_tokens, completion_tokens): (String, i64, i64) = sqlx::query_as(
+        "SELECT model, prompt_tokens, completion_tokens FROM messages WHERE conversation_id = 1 AND seq = 3",
+    )
+    .fetch_one(&state.pool)
+    .await
+    .unwrap();
+    assert_eq!(model, "deepseek-chat-v3");
+    assert_eq!((prompt_tokens, completion_tokens), (12, 7));
+
+    let updatetime: String =
+        sqlx::query_scalar("SELECT updatetime FROM conversation WHERE id = 1")
+            .fetch_one(&state.pool)
+            .await
+            .unwrap();
+    assert_ne!(updatetime, "2025-01-01 00:00:00");
+}
+
+#[tokio::test]
+async fn test_send_message_upstream_error() {
+    let upstream = Router::new().route(
+        "/v1/chat/completions",
+        post(|| async { StatusCode::INTERNAL_SERVER_ERROR }),
+    );
+    let base_url = spawn_upstream(upstream).await;
+    let dir = tempfile::tempdir().unwrap();
+    let state = conversation_state(dir.path(), base_url).await;
+
+    let app = build_router(Arc::clone(&state));
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations/1/messages")
+                .header("Authorization", bearer("1", "user"))
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"content": "how are you?"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    // The user message is not persisted when the model call fails
+    assert_eq!(read_history(&state).await.len(), 1);
+}
+
+#[tokio::test]
+async fn test_send_message_unknown_conversation() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = conversation_state(dir.path(), "http://127.0.0.1:9".to_string()).await;
+
+    let app = build_router(state);
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations/42/messages")
+                .header("Authorization", bearer("1", "user"))
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"content": "how are you?"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+fn delta_chunk(content: &str) -> String {
+    json!({"choices": [{"delta": {"content": content}}]}).to_string()
+}
+
+#[tokio::test]
+async fn test_stream_message() {
+    let upstream = Router::new().route(
+        "/v1/chat/completions",
+        post(|Json(body): Json<serde_json::Value>| async move {
+            assert_eq!(body["stream"], true);
+            (
+                [("content-type", "text/event-stream")],
+                format!(
+                    "data: {}\n\ndata: {}\n\ndata: [DONE]\n\n",
+                    delta_chunk("Hel"),
+                    delta_chunk("lo")
+                ),
+            )
+        }),
+    );
+    let base_url = spawn_upstream(upstream).await;
+    let dir = tempfile::tempdir().unwrap();
+    let state = conversation_state(dir.path(), base_url).await;
+
+    let app = build_router(Arc::clone(&state));
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations/1/messages/stream")
+                .header("Authorization", bearer("1", "user"))
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"content": "how are you?"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024)
+        .await
+        .unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains(&format!("data: {}", delta_chunk("Hel"))));
+    assert!(body.contains(&format!("data: {}", delta_chunk("lo"))));
+    assert!(body.ends_with("data: [DONE]\n\n"));
+
+    // The reply is persisted before the stream is closed
+    let history = read_history(&state).await;
+    assert_eq!(history.len(), 3);
+    assert_eq!(history[2], ChatMessage::new("assistant", "Hello".to_string()));
+}
+
+#[tokio::test]
+async fn test_stream_message_empty_reply() {
+    let upstream = Router::new().route(
+        "/v1/chat/completions",
+        post(|| async { ([("content-type", "text/event-stream")], "data: [DONE]\n\n") }),
+    );
+    let base_url = spawn_upstream(upstream).await;
+    let dir = tempfile::tempdir().unwrap();
+    let state = conversation_state(dir.path(), base_url).await;
+
+    let response = build_router(Arc::clone(&state))
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations/1/messages/stream")
+                .header("Authorization", bearer("1", "user"))
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"content": "how are you?"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024)
+        .await
+        .unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.starts_with("event: error\n"));
+    assert!(!body.contains("[DONE]"));
+
+    // Without a reply the question is not kept either
+    assert_eq!(read_history(&state).await.len(), 1);
+}
+
+#[tokio::test]
+async fn test_stream_message_client_disconnect() {
+    let (dropped_tx, dropped_rx) = tokio::sync::oneshot::channel::<()>();
+    let dropped_tx = Arc::new(std::sync::Mutex::new(Some(dropped_tx)));
+    let upstream = Router::new().route(
+        "/v1/chat/completions",
+        post(move || {
+            let guard = dropped_tx.lock().unwrap().take();
+            async move {
+                // One chunk, then hang until the connection is dropped
+                let events = tokio_stream::iter(vec![Ok::<_, std::convert::Infallible>(
+                    axum::response::sse::Event::default().data(delta_chunk("Hel")),
+                )])
+                .chain(tokio_stream::pending())
+                .map(move |event| {
+                    let _guard = &guard;
+                    event
+                });
+                axum::response::sse::Sse::new(events)
+            }
+        }),
+    );
+    let base_url = spawn_upstream(upstream).await;
+    let dir = tempfile::tempdir().unwrap();
+    let state = conversation_state(dir.path(), base_url).await;
+
+    let app = build_router(Arc::clone(&state));
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations/1/messages/stream")
+                .header("Authorization", bearer("1", "user"))
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"content": "how are you?"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut body = response.into_body().into_data_stream();
+    let first = body.next().await.unwrap().unwrap();
+    assert!(String::from_utf8_lossy(&first).contains("Hel"));
+    drop(body);
+
+    // The upstream request is aborted once the client goes away
+    tokio::time::timeout(std::time::Duration::from_secs(5), dropped_rx)
+        .await
+        .expect("upstream request was not aborted")
+        .unwrap_err();
+
+    // ... and the partial reply is saved, marked as truncated
+    let mut history = read_history(&state).await;
+    for _ in 0..50 {
+        if history.len() == 3 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        history = read_history(&state).await;
+    }
+    assert_eq!(history.len(), 3);
+    assert_eq!(history[2].content, "Hel");
+    assert!(history[2].truncated);
+}
+
+fn conversation_app(state: Arc<AppState>) -> Router {
+    build_router(state)
+}
+
+#[tokio::test]
+async fn test_create_conversation() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = conversation_state(dir.path(), "http://127.0.0.1:9".to_string()).await;
+
+    let response = conversation_app(Arc::clone(&state))
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/conversations")
+                .header("Authorization", bearer("1", "user"))
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"title": "  Rust questions  "}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024)
+        .await
+        .unwrap();
+    let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(created["id"], 2);
+    assert_eq!(created["title"], "Rust questions");
+    assert_eq!(created["filepath"], "");
+    let owner: i64 = sqlx::query_scalar("SELECT userid FROM conversation WHERE id = 2")
+        .fetch_one(&state.pool)
+        .await
+        .unwrap();
+    assert_eq!(owner, 1);
+
+    // The new conversation is immediately readable
+    let response = conversation_app(state)
+        .oneshot(
+            Request::builder()
+                .method("GET")
+                .uri("/conversations/2")
+                .header("Authorization", bearer("1", "user"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_rename_conversation() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = conversation_state(dir.path(), "http://127.0.0.1:9".to_string()).await;
+
+    let response = conversation_app(Arc::clone(&state))
+        .oneshot(
+            Request::builder()
+                .method("PATCH")
+                .uri("/conversations/1")
+                .header("Authorization", bearer("1", "user"))
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"title": "Greetings"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let (title, updatetime): (String, String) =
+        sqlx::query_as("SELECT title, updatetime FROM conversation WHERE id = 1")
+            .fetch_one(&state.pool)
+            .await
+            .unwrap();
+    assert_eq!(title, "Greetings");
+    assert_ne!(updatetime, "2025-01-01 00:00:00");
+
+    let response = conversation_app(Arc::clone(&state))
+        .oneshot(
+            Request::builder()
+                .method("PATCH")
+                .uri("/conversations/1")
+                .header("Authorization", bearer("1", "user"))
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"title": "   "}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = conversation_app(state)
+        .oneshot(
+            Request::builder()
+                .method("PATCH")
+                .uri("/conversations/42")
+                .header("Authorization", bearer("1", "user"))
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"title": "Greetings"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_delete_conversation() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = conversation_state(dir.path(), "http://127.0.0.1:9".to_string()).await;
+
+    let response = conversation_app(Arc::clone(&state))
+        .oneshot(
+            Request::builder()
+                .method("DELETE")
+                .uri("/conversations/1")
+                .header("Authorization", bearer("1", "user"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    // Messages and the imported file go with the conversation
+    assert!(!dir.path().join("history.json").exists());
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM conversation")
+        .fetch_one(&state.pool)
+        .await
+        .unwrap();
+    assert_eq!(count, 0);
+    assert!(read_history(&state).await.is_empty());
+    let audit: (i64, String, i64, String) =
+        sqlx::query_as("SELECT actor_id, event, target_id, detail FROM audit_log")
+            .fetch_one(&state.pool)
+            .await
+            .unwrap();
+    assert_eq!(audit, (1, "conversation.deleted".to_string(), 1, "conversation=1".to_string()));
+
+    let response = conversation_app(Arc::clone(&state))
+        .oneshot(
+            Request::builder()
+                .method("DELETE")
+                .uri("/conversations/1")
+                .header("Authorization", bearer("1", "user"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // A file that can't be removed is left behind; the deletion itself is already committed
+    std::fs::create_dir(dir.path().join("stuck.json")).unwrap();
+    sqlx::query("INSERT INTO conversation (title, updatetime, filepath, userid) VALUES ('stuck', '2025-01-01 00:00:00', 'stuck.json', 1)")
+        .execute(&state.pool)
+        .await
+        .unwrap();
+    let id: i64 = sqlx::query_scalar("SELECT id FROM conversation WHERE title = 'stuck'")
+        .fetch_one(&state.pool)
+        .await
+        .unwrap();
+    let response =
+        conversation_request(&state, "DELETE", &format!("/conversations/{}", id), Some(bearer("1", "user"))).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert!(dir.path().join("stuck.json").exists());
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM conversation")
+        .fetch_one(&state.pool)
+        .await
+        .unwrap();
+    assert_eq!(count, 0);
+}
+
+async fn conversation_request(
+    state: &Arc<AppState>,
+    method: &str,
+    uri: &str,
+    authorization: Option<String>,
+) -> axum::response::Response {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json");
+    if let Some(authorization) = authorization {
+        request = request.header("Authorization", authorization);
+    }
+    let body = match method {
+        "PATCH" => Body::from(r#"{"title": "mine now"}"#),
+        "POST" => Body::from(r#"{"content": "hi"}"#),
+        _ => Body::empty(),
+    };
+
+    conversation_app(Arc::clone(state))
+        .oneshot(request.body(body).unwrap())
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_conversation_ownership() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = conversation_state(dir.path(), "http://127.0.0.1:9".to_string()).await;
+
+    // Without a token nothing is visible
+    let response = conversation_request(&state, "GET", "/conversations", None).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Another user sees an empty list and gets 404 for conversation 1
+    let other = bearer("2", "user");
+    let response = conversation_request(&state, "GET", "/conversations", Some(other.clone())).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024)
+        .await
+        .unwrap();
+    let conversations: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+    assert!(conversations.is_empty());
+
+    for (method, uri) in [
+        ("GET", "/conversations/1"),
+        ("PATCH", "/conversations/1"),
+        ("POST", "/conversations/1/messages"),
+        ("DELETE", "/conversations/1"),
+    ] {
+        let response = conversation_request(&state, method, uri, Some(other.clone())).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{} {}", method, uri);
+    }
+    assert_eq!(read_history(&state).await.len(), 1);
+
+    // Admins list their own conversations unless they ask for all of them
+    let admin = bearer("3", "admin");
+    let response = conversation_request(&state, "GET", "/conversations", Some(admin.clone())).await;
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024)
+        .await
+        .unwrap();
+    let conversations: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+    assert!(conversations.is_empty());
+
+    let response =
+        conversation_request(&state, "GET", "/conversations?all=true", Some(admin.clone())).await;
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024)
+        .await
+        .unwrap();
+    let conversations: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(conversations.len(), 1);
+
+    // ... and `all` is ignored for regular users
+    let response =
+        conversation_request(&state, "GET", "/conversations?all=true", Some(other)).await;
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024)
+        .await
+        .unwrap();
+    let conversations: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+    assert!(conversations.is_empty());
+
+    let response = conversation_request(&state, "GET", "/conversations/1", Some(admin)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+// Conversation routes open to API keys, plus key management, as mounted by the server
+fn api_key_app(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/conversations", get(get_conversations).post(create_conversation))
+        .route_layer(Extension(ApiKeyResource("conversations")))
+        .route("/api-keys", get(list_api_keys).post(create_api_key))
+        .route("/api-keys/{id}", patch(rename_api_key).delete(revoke_api_key))
+        .with_state(state)
+}
+
+#[tokio::test]
+async fn test_api_keys() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = conversation_state(dir.path(), "http://127.0.0.1:9".to_string()).await;
+    let app = api_key_app(Arc::clone(&state));
+    let session = bearer("1", "user");
+    let session = session.strip_prefix("Bearer ").unwrap();
+
+    let body = json!({ "name": "  ci  ", "scopes": ["conversations:read"] });
+    let (status, created) = admin_request(&app, "POST", "/api-keys", session, Some(body)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let key = created["key"].as_str().unwrap().to_string();
+    assert!(key.starts_with("sk-"));
+    assert_eq!(created["name"], "ci");
+    assert_eq!(created["prefix"], key[..11]);
+    assert_eq!(created["scopes"], json!(["conversations:read"]));
+
+    // Only the hash is stored
+    let stored: String = sqlx::query_scalar("SELECT key_hash FROM api_keys")
+        .fetch_one(&state.pool)
+        .await
+        .unwrap();
+    assert_eq!(stored, hex::encode(<sha2::Sha256 as sha2::Digest>::digest(key.as_bytes())));
+
+    let (status, conversations) = admin_request(&app, "GET", "/conversations", &key, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(conversations.as_array().unwrap().len(), 1);
+    let (_, keys) = admin_request(&app, "GET", "/api-keys", session, None).await;
+    assert!(keys[0]["last_used_at"].is_string());
+    assert!(keys[0].get("key").is_none());
+
+    // Scopes are enforced, and keys can't manage keys
+    let (status, _) = admin_request(&app, "POST", "/conversations", &key, Some(json!({}))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = admin_request(&app, "GET", "/api-keys", &key, None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = admin_request(&app, "GET", "/conversations", "sk-bogus", None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = admin_request(&app, "POST", "/api-keys", session, Some(json!({ "name": "ci" }))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (_, writer) = admin_request(&app, "POST", "/api-keys", session, Some(json!({ "name": "writer" }))).await;
+    let (status, _) =
+        admin_request(&app, "POST", "/conversations", writer["key"].as_str().unwrap(), Some(json!({}))).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    // Renaming and revoking only reach the caller's own keys
+    let id = created["id"].as_i64().unwrap();
+    let uri = format!("/api-keys/{}", id);
+    let other = bearer("2", "user");
+    let other = other.strip_prefix("Bearer ").unwrap();
+    let (status, _) = admin_request(&app, "PATCH", &uri, other, Some(json!({ "name": "mine" }))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, renamed) = admin_request(&app, "PATCH", &uri, session, Some(json!({ "name": "nightly" }))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(renamed["name"], "nightly");
+    let (status, _) = admin_request(&app, "DELETE", &uri, other, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = admin_request(&app, "DELETE", &uri, session, None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let (status, _) = admin_request(&app, "GET", "/conversations", &key, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (_, keys) = admin_request(&app, "GET", "/api-keys", session, None).await;
+    assert_eq!(keys.as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_api_key_validation_and_expiry() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = conversation_state(dir.path(), "http://127.0.0.1:9".to_string()).await;
+    let app = api_key_app(Arc::clone(&state));
+    let session = bearer("1", "user");
+    let session = session.strip_prefix("Bearer ").unwrap();
+
+    let cases = [
+        (json!({ "name": " " }), "invalid_name"),
+        (json!({ "name": "ci", "scopes": ["admin"] }), "invalid_scope"),
+        (json!({ "name": "ci", "scopes": [] }), "invalid_scope"),
+        (json!({ "name": "ci", "expires_at": "2000-01-01T00:00:00Z" }), "invalid_expiry"),
+    ];
+    for (body, code) in cases {
+        let (status, error) = admin_request(&app, "POST", "/api-keys", session, Some(body)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["code"], code);
+    }
+
+    let expires_at = chrono::Utc::now() + chrono::Duration::hours(1);
+    let body = json!({ "name": "ci", "expires_at": expires_at });
+    let (status, created) = admin_request(&app, "POST", "/api-keys", session, Some(body)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let key = created["key"].as_str().unwrap();
+    let (status, _) = admin_request(&app, "GET", "/conversations", key, None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    sqlx::query("UPDATE api_keys SET expires_at = $1")
+        .bind(chrono::Utc::now().timestamp() - 1)
+        .execute(&state.pool)
+        .await
+        .unwrap();
+    let (status, _) = admin_request(&app, "GET", "/conversations", key, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_conversation_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = conversation_state(dir.path(), "http://127.0.0.1:9".to_string()).await;
+    let token = bearer("1", "user");
+
+    let response =
+        conversation_request(&state, "GET", "/conversations/42", Some(token.clone())).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024)
+        .await
+        .unwrap();
+    let error_response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error_response["error"], "Conversation not found");
+    assert_eq!(error_response["code"], "not_found");
+
+    // A malformed timestamp no longer breaks the conversation list
+    sqlx::query("UPDATE conversation SET updatetime = 'yesterday' WHERE id = 1")
+        .execute(&state.pool)
+        .await
+        .unwrap();
+    let response = conversation_request(&state, "GET", "/conversations", Some(token)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024)
+        .await
+        .unwrap();
+    let conversations: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(conversations.len(), 1);
+}
+
+#[tokio::test]
+async fn test_import_legacy_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = conversation_state(dir.path(), "http://127.0.0.1:9".to_string()).await;
+
+    sqlx::query(
+        "INSERT INTO conversation (title, updatetime, filepath, userid, import_pending) VALUES ('broken', '2025-01-01 00:00:00', 'broken.json', 1, TRUE)",
+    )
+    .execute(&state.pool)
+    .await
+    .unwrap();
+    std::fs::write(dir.path().join("broken.json"), "not json").unwrap();
+
+    // Conversation 1 was imported already and the broken file is skipped
+    assert_eq!(
+        conversation::import_legacy_files(&state.pool, dir.path())
+            .await
+            .unwrap(),
+        0
+    );
+    assert_eq!(read_history(&state).await.len(), 1);
+
+    // The broken conversation reports its file instead of looking empty, and can't be added to
+    let response =
+        conversation_request(&state, "GET", "/conversations/2", Some(bearer("1", "user"))).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024)
+        .await
+        .unwrap();
+    let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error["code"], "corrupt_conversation");
+    let response = conversation_request(
+        &state,
+        "POST",
+        "/conversations/2/messages",
+        Some(bearer("1", "user")),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // Once the file is fixed it is picked up by the next run
+    std::fs::write(
+        dir.path().join("broken.json"),
+        r#"[{"content": "hi", "role": "user"}, {"content": "hello!", "role": "assistant"}]"#,
+    )
+    .unwrap();
+    assert_eq!(
+        conversation::import_legacy_files(&state.pool, dir.path())
+            .await
+            .unwrap(),
+        1
+    );
+
+    let response =
+        conversation_request(&state, "GET", "/conversations/2", Some(bearer("1", "user"))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024)
+        .await
+        .unwrap();
+    let messages: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        messages,
+        json!([
+            {"content": "hi", "role": "user"},
+            {"content": "hello!", "role": "assistant"},
+        ])
+    );
+
+    // Deleting a conversation that never got imported keeps the only copy of its messages
+    std::fs::write(dir.path().join("broken.json"), "not json").unwrap();
+    sqlx::query("UPDATE conversation SET import_pending = TRUE WHERE id = 2")
+        .execute(&state.pool)
+        .await
+        .unwrap();
+    let response =
+        conversation_request(&state, "DELETE", "/conversations/2", Some(bearer("1", "user"))).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert!(dir.path().join("broken.json").exists());
+}
+
+const TEST_SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+// Load and validate `toml` as the config file, with `env` as the only environment variables
+fn load_config(toml: &str, env: &[(&str, &str)], args: ConfigArgs) -> Result<Config, config::types::ConfigError> {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    std::fs::write(&path, toml).unwrap();
+    let env: std::collections::HashMap<String, String> =
+        env.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+    let config = Config::load_from(&ConfigArgs { config: Some(path), ..args }, |name| env.get(name).cloned())?;
+    config.validate()?;
+    Ok(config)
+}
+
+#[test]
+fn test_config_layering() {
+    let toml = format!(
+        r#"
+        [server]
+        bind = "127.0.0.1:9000"
+        log_level = "info"
+
+        [database]
+        url = "sqlite:file.db"
+        max_connections = 4
+
+        [jwt]
+        secret = "{}"
+
+        [llm]
+        model = "file-model"
+        base_url = "http://file.example.com"
+        "#,
+        TEST_SECRET
+    );
+    let env = [
+        ("DATABASE_URL", "sqlite:env.db"),
+        ("LLM_MODEL", "env-model"),
+        ("JWT_ACCESS_EXPIRY", "60"),
+        ("REGISTRATION_MODE", "open"),
+        ("LLM_API_KEY", ""),
+    ];
+    let args = ConfigArgs {
+        llm_model: Some("flag-model".to_string()),
+        ..Default::default()
+    };
+    let config = load_config(&toml, &env, args).unwrap();
+
+    // Flags beat the environment, which beats the file, which beats the defaults
+    assert_eq!(config.llm.model, "flag-model");
+    assert_eq!(config.database.url, "sqlite:env.db");
+    assert_eq!(config.llm.base_url, "http://file.example.com");
+    assert_eq!(config.server.bind, "127.0.0.1:9000".parse().unwrap());
+    assert_eq!(config.database.max_connections, 4);
+    assert_eq!(config.jwt.access_expiry, 60);
+    assert_eq!(config.jwt.refresh_expiry, 7 * 24 * 3600);
+    assert_eq!(config.auth_config().registration, RegistrationMode::Open);
+    assert_eq!(config.llm.api_key, None);
+    assert_eq!(config.server.cors_origins, ["*"]);
+    assert!(config.oidc_config().is_none());
+    assert_eq!(config.jwt_config().unwrap().access_expiry, 60);
+
+    // Everything but the secret has a default
+    let config = load_config("", &[("JWT_SECRET", TEST_SECRET)], ConfigArgs::default()).unwrap();
+    assert_eq!(config.server.bind, "0.0.0.0:8000".parse().unwrap());
+    assert_eq!(config.llm.model, "deepseek-chat");
+}
+
+#[test]
+fn test_config_validation() {
+    let secret = format!("JWT_SECRET={}", TEST_SECRET);
+    let cases = [
+        ("", "", "jwt.secret (JWT_SECRET) must be set"),
+        ("", "JWT_SECRET=short", "jwt.secret (JWT_SECRET): must be at least 32 bytes, got 5"),
+        ("", &secret, ""),
+        ("[jwt]\nalgorithm = \"RS256\"", &secret, "jwt.private_key_file (JWT_PRIVATE_KEY_FILE) must be set"),
+        ("[jwt]\nalgorithm = \"HS512\"", &secret, "jwt.algorithm (JWT_ALGORITHM): HS512 is not supported"),
+        ("[cookies]\nenabled = true", &secret, "cookie sessions need the allowed origins listed"),
+        ("[server]\ncors_origins = [\"*\", \"http://a.example.com\"]", &secret, "can't be combined"),
+        ("[cookies]\npath_prefix = \"/api/\"", &secret, "cookies.path_prefix (COOKIE_PATH_PREFIX)"),
+        ("[auth]\nbcrypt_cost = 3", &secret, "auth.bcrypt_cost (BCRYPT_COST): must be between 4 and 31"),
+        ("[oidc]\ndiscovery_url = \"http://idp\"", &secret, "oidc.client_id (OIDC_CLIENT_ID) must be set"),
+        ("[mail]\ntransport = \"smtp\"", &secret, "mail.smtp_host (SMTP_HOST) must be set"),
+        ("[database]\nmax_connections = 0", &secret, "database.max_connections: must be at least 1"),
+        ("[server]\nlog_level = \"info,=\"", &secret, "server.log_level (LOG_LEVEL)"),
+        ("[server]\nport = 8000", &secret, "unknown field `port`"),
+        ("[admin]\nemail = \"admin@example.com\"", &secret, "admin.password (ADMIN_PASSWORD) must be set"),
+        ("[jwt]\naccess_expiry = \"1h\"", &secret, "invalid type"),
+    ];
+    for (toml, env, expected) in cases {
+        let env: Vec<(&str, &str)> = env.split_once('=').into_iter().collect();
+        match load_config(toml, &env, ConfigArgs::default()) {
+            Ok(_) => assert_eq!(expected, "", "{:?} should be rejected", toml),
+            Err(e) => assert!(
+                !expected.is_empty() && e.to_string().contains(expected),
+                "{:?}: expected {:?}, got {:?}",
+                toml,
+                expected,
+                e.to_string()
+            ),
+        }
+    }
+
+    // Environment variables are checked too, and named in the error
+    let env = [("JWT_SECRET", TEST_SECRET), ("JWT_ACCESS_EXPIRY", "soon")];
+    let error = load_config("", &env, ConfigArgs::default()).unwrap_err();
+    assert_eq!(error.to_string(), "JWT_ACCESS_EXPIRY: invalid digit found in string");
+    let env = [("JWT_SECRET", TEST_SECRET), ("SESSION_COOKIES", "yes")];
+    let error = load_config("", &env, ConfigArgs::default()).unwrap_err();
+    assert_eq!(error.to_string(), "SESSION_COOKIES: expected true or false, got \"yes\"");
+
+    let args = ConfigArgs {
+        config: Some("/nonexistent/config.toml".into()),
+        ..Default::default()
+    };
+    let error = Config::load_from(&args, |_| None).unwrap_err();
+    assert!(error.to_string().starts_with("failed to read /nonexistent/config.toml"));
+}
+
+#[test]
+fn test_cli_parsing() {
+    use clap::Parser;
+    use cli::types::{Cli, Command, ConversationCommand, UserCommand};
+
+    let cli = Cli::try_parse_from(["restchat", "--bind", "127.0.0.1:9000"]).unwrap();
+    assert!(cli.command.is_none());
+    assert_eq!(cli.config.bind, Some("127.0.0.1:9000".parse().unwrap()));
+
+    // Config flags work after the subcommand too
+    let cli = Cli::try_parse_from(["restchat", "user", "create", "a@example.com", "--role", "admin", "--database-url", "sqlite::memory:"]).unwrap();
+    assert!(matches!(
+        cli.command,
+        Some(Command::User(UserCommand::Create { ref email, ref role })) if email == "a@example.com" && role == "admin"
+    ));
+    assert_eq!(cli.config.database_url.as_deref(), Some("sqlite::memory:"));
+
+    let cli = Cli::try_parse_from(["restchat", "conversation", "export", "-o", "out.json"]).unwrap();
+    assert!(matches!(
+        cli.command,
+        Some(Command::Conversation(ConversationCommand::Export { user: None, output: Some(_) }))
+    ));
+    assert!(Cli::try_parse_from(["restchat", "--migrate-only"]).unwrap().migrate_only);
+    assert!(Cli::try_parse_from(["restchat", "user", "set-role", "a@example.com"]).is_err());
+}
+
+async fn run_cli(pool: &AnyPool, args: &[&str], input: &str) -> Result<String, cli::types::CliError> {
+    use clap::Parser;
+
+    let cli = cli::types::Cli::try_parse_from(std::iter::once("restchat").chain(args.iter().copied())).unwrap();
+    let mut config = Config::default();
+    config.auth.bcrypt_cost = 4;
+    let mut output = Vec::new();
+    cli::execute(cli.command.unwrap(), &config, pool, &mut input.as_bytes(), &mut output).await?;
+    Ok(String::from_utf8(output).unwrap())
+}
+
+#[tokio::test]
+async fn test_cli_user_commands() {
+    let pool = test_pool().await;
+
+    let output = run_cli(&pool, &["user", "create", "admin@example.com", "--role", "admin"], "first-password\n").await.unwrap();
+    assert_eq!(output, "Created user 1 (admin@example.com) with role admin\n");
+    let hash: String = sqlx::query_scalar("SELECT password_hash FROM auth WHERE userid = 1").fetch_one(&pool).await.unwrap();
+    assert!(bcrypt::verify("first-password", &hash).unwrap());
+
+    let error = run_cli(&pool, &["user", "create", "admin@example.com"], "other-password\n").await.unwrap_err();
+    assert_eq!(error.to_string(), "email already registered");
+    let error = run_cli(&pool, &["user", "create", "b@example.com", "--role", "root"], "password\n").await.unwrap_err();
+    assert_eq!(error.to_string(), "unknown role \"root\", expected user or admin");
+
+    run_cli(&pool, &["user", "create", "b@example.com"], "second-password\n").await.unwrap();
+    let output = run_cli(&pool, &["user", "set-role", "B@example.com", "admin"], "").await.unwrap();
+    assert_eq!(output, "User B@example.com is now admin\n");
+    let error = run_cli(&pool, &["user", "set-role", "c@example.com", "user"], "").await.unwrap_err();
+    assert_eq!(error.to_string(), "no user with email c@example.com");
+    let error = run_cli(&pool, &["serve"], "").await.unwrap_err();
+    assert_eq!(error.to_string(), "not a database command");
+
+    let output = run_cli(&pool, &["user", "list"], "").await.unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[1].contains("admin@example.com") && lines[1].contains("admin") && lines[1].ends_with("active"));
+    assert!(lines[2].contains("b@example.com") && lines[2].contains("admin"));
+
+    // Postgres spends an id on the rejected duplicate, so b's id depends on the database
+    let id: i64 = sqlx::query_scalar("SELECT id FROM users WHERE email = 'b@example.com'").fetch_one(&pool).await.unwrap();
+    sqlx::query("INSERT INTO refresh_tokens (jti, userid, family, expires_at) VALUES ('jti', $1, 'family', 32503680000)")
+        .bind(id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    run_cli(&pool, &["user", "reset-password", "b@example.com"], "third-password\r\n").await.unwrap();
+    let hash: String = sqlx::query_scalar("SELECT password_hash FROM auth WHERE userid = $1").bind(id).fetch_one(&pool).await.unwrap();
+    assert!(bcrypt::verify("third-password", &hash).unwrap());
+    let active: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM refresh_tokens WHERE userid = $1 AND revoked = FALSE")
+        .bind(id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(active, 0);
+
+    let events: Vec<(String, Option<i64>, Option<String>)> =
+        sqlx::query_as("SELECT event, actor_id, detail FROM audit_log ORDER BY id").fetch_all(&pool).await.unwrap();
+    let events: Vec<(&str, Option<i64>, Option<&str>)> =
+        events.iter().map(|(event, actor, detail)| (event.as_str(), *actor, detail.as_deref())).collect();
+    assert_eq!(
+        events,
+        [
+            ("user.registered", None, Some("role=admin via=cli")),
+            ("user.registered", None, Some("role=user via=cli")),
+            ("user.role_changed", None, Some("role=admin via=cli")),
+            ("user.password_reset", None, Some("via=cli")),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_cli_conversation_export_import() {
+    let pool = test_pool().await;
+    run_cli(&pool, &["user", "create", "a@example.com"], "password\n").await.unwrap();
+    run_cli(&pool, &["user", "create", "b@example.com"], "password\n").await.unwrap();
+    let id: i64 = sqlx::query_scalar(
+        "INSERT INTO conversation (title, updatetime, filepath, userid) VALUES ('Rust', '2025-01-02 03:04:05', '', 1) RETURNING id",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    sqlx::query("INSERT INTO messages (conversation_id, seq, role, content) VALUES ($1, 1, 'user', 'hi'), ($1, 2, 'assistant', 'hello')")
+        .bind(id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("export.json");
+    let file = file.to_str().unwrap();
+    let output = run_cli(&pool, &["conversation", "export", "--user", "a@example.com", "-o", file], "").await.unwrap();
+    assert_eq!(output, format!("Exported 1 conversations to {}\n", file));
+    let exported: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(file).unwrap()).unwrap();
+    assert_eq!(exported[0]["owner"], "a@example.com");
+    assert_eq!(exported[0]["messages"][1]["content"], "hello");
+
+    // Imported conversations keep their owner unless --user says otherwise
+    run_cli(&pool, &["conversation", "import", file], "").await.unwrap();
+    let output = run_cli(&pool, &["conversation", "import", file, "--user", "b@example.com"], "").await.unwrap();
+    assert_eq!(output, "Imported 1 conversations\n");
+    let owners: Vec<i64> = sqlx::query_scalar("SELECT userid FROM conversation ORDER BY id").fetch_all(&pool).await.unwrap();
+    assert_eq!(owners, [1, 1, 2]);
+
+    let output = run_cli(&pool, &["conversation", "export"], "").await.unwrap();
+    let exported: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(exported.as_array().unwrap().len(), 3);
+    assert_eq!(exported[2]["owner"], "b@example.com");
+    assert_eq!(exported[2]["messages"], exported[0]["messages"]);
+
+    std::fs::write(file, r#"[{"title": "No owner", "updatetime": "2025-01-01 00:00:00", "messages": []}]"#).unwrap();
+    let error = run_cli(&pool, &["conversation", "import", file], "").await.unwrap_err();
+    assert_eq!(error.to_string(), "conversation 0 has no owner; pass --user");
+    std::fs::write(file, r#"[{"title": "Bad", "updatetime": "yesterday", "messages": []}]"#).unwrap();
+    let error = run_cli(&pool, &["conversation", "import", file, "--user", "a@example.com"], "").await.unwrap_err();
+    assert!(error.to_string().starts_with("conversation 0 needs a title"));
+}
+
+#[tokio::test]
+async fn test_database_bootstrap() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data/chat.db");
+    let args = ConfigArgs {
+        database_url: Some(format!("sqlite:{}", path.display())),
+        admin_email: Some("root@example.com".to_string()),
+        ..Default::default()
+    };
+    let mut config = load_config("", &[("JWT_SECRET", TEST_SECRET), ("ADMIN_PASSWORD", "bootstrap-password")], args).unwrap();
+    config.auth.bcrypt_cost = 4;
+
+    // Without create_if_missing a missing database is an error
+    config.database.create_if_missing = false;
+    assert!(db::connect(&config.database).await.is_err());
+    assert!(!path.exists());
+
+    // Otherwise the file and its directory are created
+    config.database.create_if_missing = true;
+    let pool = db::connect(&config.database).await.unwrap();
+    assert!(path.exists());
+    db::migrate(&pool).await.unwrap();
+
+    let id = cli::seed_admin(&config, &pool).await.unwrap().unwrap();
+    let (email, role): (String, String) =
+        sqlx::query_as("SELECT email, role FROM users WHERE id = $1").bind(id).fetch_one(&pool).await.unwrap();
+    assert_eq!((email.as_str(), role.as_str()), ("root@example.com", "admin"));
+    let hash: String = sqlx::query_scalar("SELECT password_hash FROM auth WHERE userid = $1").bind(id).fetch_one(&pool).await.unwrap();
+    assert!(bcrypt::verify("bootstrap-password", &hash).unwrap());
+
+    // Once an administrator exists the seed is left alone, even under another email
+    config.admin.email = Some("other@example.com".to_string());
+    assert_eq!(cli::seed_admin(&config, &pool).await.unwrap(), None);
+    let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users").fetch_one(&pool).await.unwrap();
+    assert_eq!(users, 1);
+    pool.close().await;
+
+    // Reopening keeps the data
+    let pool = db::connect(&config.database).await.unwrap();
+    db::migrate(&pool).await.unwrap();
+    let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users").fetch_one(&pool).await.unwrap();
+    assert_eq!(users, 1);
+}
+
+#[tokio::test]
+async fn test_database_tuning() {
+    let dir = tempfile::tempdir().unwrap();
+    let url = format!("sqlite:{}", dir.path().join("chat.db").display());
+    let args = || ConfigArgs {
+        database_url: Some(url.clone()),
+        ..Default::default()
+    };
+    // journal_mode is reported by name, the other settings as numbers
+    let pragma = |pool: AnyPool, name: &'static str| async move {
+        let row = sqlx::query(&format!("PRAGMA {}", name)).fetch_one(&pool).await.unwrap();
+        row.try_get::<String, _>(0).or_else(|_| row.try_get::<i64, _>(0).map(|n| n.to_string())).unwrap()
+    };
+
+    let config = load_config("", &[("JWT_SECRET", TEST_SECRET)], args()).unwrap();
+    let pool = db::connect(&config.database).await.unwrap();
+    db::migrate(&pool).await.unwrap();
+    assert_eq!(pragma(pool.clone(), "journal_mode").await, "wal");
+    assert_eq!(pragma(pool.clone(), "synchronous").await, "1");
+    assert_eq!(pragma(pool.clone(), "busy_timeout").await, "5000");
+    assert_eq!(pragma(pool.clone(), "foreign_keys").await, "1");
+
+    // Foreign keys are enforced
+    let orphan = sqlx::query("INSERT INTO auth (userid, email, password_hash) VALUES (42, 'ghost@example.com', 'x')")
+        .execute(&pool)
+        .await
+        .unwrap_err();
+    assert!(orphan.to_string().contains("FOREIGN KEY"));
+
+    sqlx::query("INSERT INTO users (email, role) VALUES ('a@example.com', 'user')").execute(&pool).await.unwrap();
+    db::maintain(&pool).await.unwrap();
+    let wal = std::fs::metadata(dir.path().join("chat.db-wal")).unwrap();
+    assert_eq!(wal.len(), 0);
+    pool.close().await;
+
+    let env = [
+        ("JWT_SECRET", TEST_SECRET),
+        ("DATABASE_JOURNAL_MODE", "delete"),
+        ("DATABASE_SYNCHRONOUS", "full"),
+        ("DATABASE_BUSY_TIMEOUT", "250"),
+    ];
+    let config = load_config("", &env, args()).unwrap();
+    let pool = db::connect(&config.database).await.unwrap();
+    assert_eq!(pragma(pool.clone(), "journal_mode").await, "delete");
+    assert_eq!(pragma(pool.clone(), "synchronous").await, "2");
+    assert_eq!(pragma(pool.clone(), "busy_timeout").await, "250");
+    db::maintain(&pool).await.unwrap();
+
+    let error = load_config("", &[("JWT_SECRET", TEST_SECRET), ("DATABASE_SYNCHRONOUS", "sometimes")], args()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "DATABASE_SYNCHRONOUS: unknown synchronous level \"sometimes\", expected off, normal, full or extra"
+    );
+    let error = load_config("[database]\njournal_mode = \"fast\"", &[("JWT_SECRET", TEST_SECRET)], args()).unwrap_err();
+    assert!(error.to_string().contains("unknown variant `fast`"));
+
+    let env = [("JWT_SECRET", TEST_SECRET), ("DATABASE_STATEMENT_CACHE_SIZE", "20")];
+    assert_eq!(load_config("", &env, args()).unwrap().database.statement_cache_size, 20);
+    let mut database = config.database.clone();
+    database.statement_cache_size = 20;
+    db::connect(&database).await.unwrap().close().await;
+}
+
+// The whole API over `repository`, with a pool whose database can't be opened: anything the
+// handlers run outside the repositories fails
+fn memory_app(repository: Arc<MemoryRepository>) -> Router {
+    let pool = sqlx::any::AnyPoolOptions::new()
+        .acquire_timeout(std::time::Duration::from_secs(1))
+        .connect_lazy("sqlite:/nonexistent/restchat.db?mode=ro")
+        .unwrap();
+    let state = test_state(pool, test_jwt_config());
+    build_router(Arc::new(AppState {
+        repositories: Repositories::from(repository),
+        ..Arc::unwrap_or_clone(state)
+    }))
+}
+
+async fn memory_get(app: &Router, uri: &str, authorization: String) -> (StatusCode, serde_json::Value) {
+    let response = app
+        .clone()
+        .oneshot(Request::builder().uri(uri).header("Authorization", authorization).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), 1024 * 1024).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+}
+
+#[tokio::test]
+async fn test_memory_repositories() {
+    let repository = Arc::new(MemoryRepository::new());
+    let user = repository.add_user("user@example.com", "user", None);
+    let admin = repository.add_user("admin@example.com", "admin", None);
+    let hello = ChatMessage {
+        content: "hello".to_string(),
+        role: "user".to_string(),
+        truncated: false,
+    };
+    let mine = repository.add_conversation(user, "mine", "2025-01-01 00:00:00", vec![hello.clone()]);
+    let theirs = repository.add_conversation(admin, "theirs", "2025-01-02 00:00:00", Vec::new());
+    let app = memory_app(repository.clone());
+    let user_token = bearer(&user.to_string(), "user");
+    let admin_token = bearer(&admin.to_string(), "admin");
+
+    let (status, me) = memory_get(&app, "/auth/me", user_token.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(me, json!({"id": user, "email": "user@example.com", "role": "user"}));
+
+    let (status, conversations) = memory_get(&app, "/conversations", user_token.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(conversations.as_array().unwrap().len(), 1);
+    assert_eq!(conversations[0]["title"], "mine");
+
+    let (status, messages) = memory_get(&app, &format!("/conversations/{}", mine), user_token.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(messages, json!([{"content": "hello", "role": "user"}]));
+    let (status, _) = memory_get(&app, &format!("/conversations/{}", theirs), user_token.clone()).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Admins see everyone's conversations when they ask, newest first
+    let (_, conversations) = memory_get(&app, "/conversations?all=true", admin_token.clone()).await;
+    let titles: Vec<&str> = conversations.as_array().unwrap().iter().map(|c| c["title"].as_str().unwrap()).collect();
+    assert_eq!(titles, ["theirs", "mine"]);
+    let (status, _) = memory_get(&app, &format!("/conversations/{}", mine), admin_token).await;
+    assert_eq!(status, StatusCode::OK);
+
+    repository.set_disabled(user, true);
+    let (status, _) = memory_get(&app, "/auth/me", user_token).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = memory_get(&app, "/auth/me", bearer("42", "user")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_login_through_repositories() {
+    // Users and passwords come from the repositories; lockout and sessions still use the database
+    let repository = Arc::new(MemoryRepository::new());
+    let hash = bcrypt::hash("password", 4).unwrap();
+    let id = repository.add_user("user@example.com", "user", Some(&hash));
+    repository.add_user("sso@example.com", "user", None);
+    let state = test_state(test_pool().await, test_jwt_config());
+    // Refresh tokens reference the user, so the database needs the same user 1; its role is
+    // never read
+    sqlx::query("INSERT INTO users (email, role) VALUES ('user@example.com', 'admin')")
+        .execute(&state.pool)
+        .await
+        .unwrap();
+    let app = auth_router(AppState {
+        repositories: Repositories::from(repository.clone()),
+        ..Arc::unwrap_or_clone(state)
+    });
+
+    let login = |email: &str, password: &str| json!({"email": email, "password": password}).to_string();
+    let (status, body) = auth_request(&app, "/auth/login", None, login("user@example.com", "password")).await;
+    assert_eq!(status, StatusCode::OK);
+    let tokens: AuthResponse = serde_json::from_slice(&body).unwrap();
+    let (status, me) = memory_get(&app, "/auth/me", format!("Bearer {}", tokens.access_token)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(me["id"], id);
+
+    let (status, _) = auth_request(&app, "/auth/login", None, login("user@example.com", "wrong-password")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = auth_request(&app, "/auth/login", None, login("USER@example.com", "password")).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = auth_request(&app, "/auth/login", None, login("sso@example.com", "password")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Refreshed tokens carry the role from the repository
+    let body = json!({ "refresh_token": tokens.refresh_token }).to_string();
+    let (status, body) = auth_request(&app, "/auth/refresh", None, body).await;
+    assert_eq!(status, StatusCode::OK);
+    let tokens: AuthResponse = serde_json::from_slice(&body).unwrap();
+    let (status, _) = memory_get(&app, "/admin/users", format!("Bearer {}", tokens.access_token)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // The current password is checked against the repository's hash
+    let body = json!({ "current_password": "password", "new_password": "hunter22" }).to_string();
+    let (status, _) = auth_request(&app, "/auth/change-password", Some(&tokens.access_token), body).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    repository.set_disabled(id, true);
+    let (status, _) = auth_request(&app, "/auth/login", None, login("user@example.com", "password")).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
 }